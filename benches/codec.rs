@@ -0,0 +1,114 @@
+// Compares packet encoding and decoding with the copying implementation
+// GamePacket had before payloads became `Bytes`.
+//
+//     cargo bench --bench codec
+
+use std::hint::black_box;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use game_udp::{GamePacket, MessageType, PlayerUpdate, Position};
+
+// The old codec: every encode and decode copies into a fresh Vec.
+mod copying {
+    use super::*;
+
+    pub struct Packet {
+        pub msg_type: MessageType,
+        pub version: u8,
+        pub seq_num: u32,
+        pub payload: Vec<u8>,
+    }
+
+    pub fn serialize(packet: &Packet) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(1 + 1 + 4 + packet.payload.len());
+        buf.put_u8(packet.msg_type as u8);
+        buf.put_u8(packet.version);
+        buf.put_u32(packet.seq_num);
+        buf.put_slice(&packet.payload);
+        buf.to_vec()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Option<Packet> {
+        if data.len() < 6 {
+            return None;
+        }
+        Some(Packet {
+            msg_type: MessageType::from_byte(data[0])?,
+            version: data[1],
+            seq_num: u32::from_be_bytes([data[2], data[3], data[4], data[5]]),
+            payload: data[6..].to_vec(),
+        })
+    }
+}
+
+fn update(n: i32) -> PlayerUpdate {
+    PlayerUpdate {
+        player: "192.168.100.200:54321".to_string(),
+        position: Position::new(n, -n, 0),
+    }
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for payload_len in [64, 1024, 16 * 1024] {
+        let datagram =
+            GamePacket::new(MessageType::ChatMessage, 7, vec![b'x'; payload_len]).serialize();
+        group.bench_with_input(
+            BenchmarkId::new("copying", payload_len),
+            &datagram,
+            |b, datagram| b.iter(|| copying::deserialize(black_box(datagram))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("bytes", payload_len),
+            &datagram,
+            |b, datagram| b.iter(|| GamePacket::decode(black_box(datagram.clone()))),
+        );
+    }
+    group.finish();
+}
+
+// One position update fanned out to every connected player, each with its
+// own sequence number.
+fn bench_broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast");
+    for recipients in [8u32, 64, 256] {
+        group.bench_with_input(
+            BenchmarkId::new("copying", recipients),
+            &recipients,
+            |b, &recipients| {
+                b.iter(|| {
+                    (0..recipients)
+                        .map(|seq| {
+                            copying::serialize(&copying::Packet {
+                                msg_type: MessageType::PositionUpdate,
+                                version: 1,
+                                seq_num: seq,
+                                payload: update(black_box(3)).serialize(),
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("bytes", recipients),
+            &recipients,
+            |b, &recipients| {
+                b.iter(|| {
+                    let payload = Bytes::from(update(black_box(3)).serialize());
+                    (0..recipients)
+                        .map(|seq| {
+                            GamePacket::new(MessageType::PositionUpdate, seq, payload.clone())
+                                .serialize()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_broadcast);
+criterion_main!(benches);
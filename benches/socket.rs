@@ -0,0 +1,134 @@
+// Server socket throughput under load from local generators. Run it once per
+// I/O path and compare:
+//
+//     cargo bench --bench socket
+//     cargo bench --bench socket --features mmsg
+
+use std::{
+    net::UdpSocket as StdUdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use game_udp::{
+    metrics::Metrics,
+    socket::{MeteredSocket, RecvPool},
+    GamePacket, MessageType, PlayerUpdate, Position,
+};
+use tokio::{net::UdpSocket, runtime::Runtime};
+
+const PATH: &str = if cfg!(all(target_os = "linux", feature = "mmsg")) {
+    "mmsg"
+} else {
+    "portable"
+};
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn bind(rt: &Runtime) -> MeteredSocket {
+    rt.block_on(async {
+        MeteredSocket::new(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            Arc::new(Metrics::new()),
+        )
+    })
+}
+
+fn position_update(seq: u32) -> GamePacket {
+    let update = PlayerUpdate {
+        player: "127.0.0.1:40000".to_string(),
+        position: Position::new(seq as i32, 0, 0),
+    };
+    GamePacket::new(MessageType::PositionUpdate, seq, update.serialize())
+}
+
+// Generator threads send position updates as fast as they can while the
+// server receives; whatever the socket can't keep up with is dropped.
+fn bench_recv(c: &mut Criterion) {
+    let rt = runtime();
+    let socket = bind(&rt);
+    let target = socket.local_addr().unwrap();
+    let running = Arc::new(AtomicBool::new(true));
+    let generators: Vec<_> = (0..4)
+        .map(|_| {
+            let running = Arc::clone(&running);
+            thread::spawn(move || {
+                let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+                let mut seq = 0;
+                while running.load(Ordering::Relaxed) {
+                    seq += 1;
+                    let _ = client.send_to(&position_update(seq).serialize(), target);
+                }
+            })
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("recv");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function(PATH, |b| {
+        let mut pool = RecvPool::new();
+        b.iter_custom(|datagrams| {
+            rt.block_on(async {
+                let started = Instant::now();
+                let mut received = 0;
+                while received < datagrams {
+                    received += socket.recv_batch(&mut pool).await.unwrap().len() as u64;
+                }
+                started.elapsed()
+            })
+        })
+    });
+    group.finish();
+
+    running.store(false, Ordering::Relaxed);
+    for generator in generators {
+        generator.join().unwrap();
+    }
+}
+
+// One update queued for each of `recipients` players, then a flush.
+fn bench_flush(c: &mut Criterion) {
+    let rt = runtime();
+    let socket = bind(&rt);
+    let mut group = c.benchmark_group("flush");
+    for recipients in [16usize, 256] {
+        // Receivers that never read; the kernel drops what doesn't fit
+        let clients: Vec<StdUdpSocket> = (0..recipients)
+            .map(|_| StdUdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<String> = clients
+            .iter()
+            .map(|c| c.local_addr().unwrap().to_string())
+            .collect();
+        group.throughput(Throughput::Elements(recipients as u64));
+        group.bench_with_input(BenchmarkId::new(PATH, recipients), &addrs, |b, addrs| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for seq in 0..iters {
+                    for addr in addrs {
+                        socket.queue_packet(position_update(seq as u32), addr);
+                    }
+                    let started = Instant::now();
+                    rt.block_on(socket.flush()).unwrap();
+                    elapsed += started.elapsed();
+                }
+                elapsed
+            })
+        });
+        drop(clients);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_recv, bench_flush);
+criterion_main!(benches);
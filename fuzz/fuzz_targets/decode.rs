@@ -0,0 +1,39 @@
+// Arbitrary bytes through the datagram decoder, fragment reassembly and every
+// payload decoder.
+//
+//     cargo +nightly fuzz run decode
+
+#![no_main]
+
+use std::time::Instant;
+
+use bytes::Bytes;
+use game_udp::{
+    fragment::Reassembler, quality::Heartbeat, Chat, GamePacket, MessageType, MoveInput,
+    PlayerProfile,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reassembler = Reassembler::new();
+    for packet in GamePacket::messages(Bytes::copy_from_slice(data)).flatten() {
+        // Whatever decodes encodes back to the same packet
+        assert_eq!(
+            GamePacket::decode(packet.serialize()).as_ref(),
+            Some(&packet)
+        );
+
+        let packet = match packet.msg_type {
+            MessageType::Fragment => match reassembler.insert((), &packet, Instant::now()) {
+                Ok(Some(packet)) => packet,
+                _ => continue,
+            },
+            _ => packet,
+        };
+        let payload = &packet.payload;
+        let _ = MoveInput::deserialize(payload);
+        let _ = PlayerProfile::deserialize(payload);
+        let _ = Heartbeat::deserialize(payload);
+        let _ = serde_json::from_slice::<Chat>(payload);
+    }
+});
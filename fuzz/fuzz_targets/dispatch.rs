@@ -0,0 +1,73 @@
+// Arbitrary datagrams through the full server dispatch path, from two peers
+// that have already joined. The input is a run of
+//
+//     peer: u8, length: u16 (big-endian), datagram bytes
+//
+//     cargo +nightly fuzz run dispatch
+
+#![no_main]
+
+use std::{net::SocketAddr, sync::Arc, sync::OnceLock};
+
+use bytes::Bytes;
+use game_udp::{
+    access::AccessFile,
+    fragment::Reassembler,
+    metrics::Metrics,
+    server::{Config, Server},
+    GamePacket, MessageType, PlayerColor, PlayerProfile,
+};
+use libfuzzer_sys::fuzz_target;
+use tokio::{net::UdpSocket, runtime::Runtime};
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    })
+}
+
+fn peers() -> [SocketAddr; 2] {
+    [
+        "127.0.0.1:9".parse().unwrap(),
+        "127.0.0.2:9".parse().unwrap(),
+    ]
+}
+
+fuzz_target!(|data: &[u8]| {
+    runtime().block_on(async {
+        // A fresh server per input, so every run starts from the same state
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let access_path = std::env::temp_dir().join("game_udp-fuzz-access.json");
+        let (server, _handoffs) = Server::new(
+            vec![socket],
+            AccessFile::open(access_path).unwrap(),
+            Arc::new(Metrics::new()),
+            Config::new((80, 24)),
+        );
+        let mut reassembler = Reassembler::new();
+        for (i, peer) in peers().into_iter().enumerate() {
+            let profile = PlayerProfile::new(&format!("peer{}", i), PlayerColor::Green);
+            let init = GamePacket::new(MessageType::ConnectionInit, 1, profile.serialize());
+            server
+                .dispatch(&mut reassembler, init.serialize(), peer)
+                .await
+                .unwrap();
+        }
+
+        let mut rest = data;
+        while let [peer, high, low, tail @ ..] = rest {
+            let len = (u16::from_be_bytes([*high, *low]) as usize).min(tail.len());
+            let (datagram, tail) = tail.split_at(len);
+            let peer = peers()[*peer as usize % 2];
+            // Send errors are fine; panics are what we're looking for
+            let _ = server
+                .dispatch(&mut reassembler, Bytes::copy_from_slice(datagram), peer)
+                .await;
+            rest = tail;
+        }
+    });
+});
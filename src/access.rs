@@ -0,0 +1,259 @@
+// Ban lists and the optional allowlist checked when a client connects.
+// The lists are stored as JSON so they can be edited by hand while the
+// server is running.
+
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+// A single address or a CIDR block, e.g. "10.0.0.7" or "10.0.0.0/8".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    // An IPv4 client on a dual-stack socket shows up as an IPv4-mapped IPv6
+    // address (::ffff:a.b.c.d), and is matched as the IPv4 address it is.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), ip) => {
+                let ip = match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid IP address '{}'", addr))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.trim().parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("invalid prefix length '{}'", p)),
+            },
+            None => max,
+        };
+        Ok(IpRange { network, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> String {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max = if self.network.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix)
+        }
+    }
+}
+
+// Either an address range or a player name, as typed in an admin command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessEntry {
+    Ip(IpRange),
+    Name(String),
+}
+
+impl AccessEntry {
+    pub fn parse(text: &str) -> AccessEntry {
+        match text.parse::<IpRange>() {
+            Ok(range) => AccessEntry::Ip(range),
+            Err(_) => AccessEntry::Name(text.trim().to_string()),
+        }
+    }
+}
+
+impl fmt::Display for AccessEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessEntry::Ip(range) => write!(f, "{}", range),
+            AccessEntry::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntryList {
+    #[serde(default)]
+    pub ips: Vec<IpRange>,
+    #[serde(default)]
+    pub names: Vec<String>,
+}
+
+impl EntryList {
+    pub fn matches(&self, ip: IpAddr, name: &str) -> bool {
+        self.ips.iter().any(|range| range.contains(ip))
+            || self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    // Returns false if the entry was already present.
+    pub fn add(&mut self, entry: AccessEntry) -> bool {
+        match entry {
+            AccessEntry::Ip(range) if !self.ips.contains(&range) => self.ips.push(range),
+            AccessEntry::Name(name)
+                if !self.names.iter().any(|n| n.eq_ignore_ascii_case(&name)) =>
+            {
+                self.names.push(name)
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Returns false if the entry wasn't present.
+    pub fn remove(&mut self, entry: &AccessEntry) -> bool {
+        let before = self.ips.len() + self.names.len();
+        match entry {
+            AccessEntry::Ip(range) => self.ips.retain(|r| r != range),
+            AccessEntry::Name(name) => self.names.retain(|n| !n.eq_ignore_ascii_case(name)),
+        }
+        before != self.ips.len() + self.names.len()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessList {
+    #[serde(default)]
+    pub banned: EntryList,
+    // When enabled, only clients matching an allowlist entry may connect.
+    #[serde(default)]
+    pub allowlist_enabled: bool,
+    #[serde(default)]
+    pub allowlist: EntryList,
+}
+
+impl AccessList {
+    // Returns the reason to give the client if it may not connect.
+    pub fn check(&self, ip: IpAddr, name: &str) -> Result<(), String> {
+        if self.banned.ips.iter().any(|range| range.contains(ip)) {
+            return Err("Your address is banned from this server".to_string());
+        }
+        if self
+            .banned
+            .names
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("The name '{}' is banned from this server", name));
+        }
+        if self.allowlist_enabled && !self.allowlist.matches(ip, name) {
+            return Err("This server only accepts allowlisted players".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> io::Result<AccessList> {
+        let data = fs::read(path)?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        fs::write(path, data)
+    }
+}
+
+// Keeps the access list in sync with its file: changes made by the server are
+// written out, and edits made to the file are picked up by `reload_if_changed`.
+#[derive(Debug, Clone)]
+pub struct AccessFile {
+    pub path: PathBuf,
+    pub list: AccessList,
+    modified: Option<SystemTime>,
+}
+
+impl AccessFile {
+    // Loads the file, starting with empty lists if it doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<AccessFile> {
+        let path = path.into();
+        let list = match AccessList::load(&path) {
+            Ok(list) => list,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => AccessList::default(),
+            Err(e) => return Err(e),
+        };
+        let modified = modified_time(&path);
+        Ok(AccessFile {
+            path,
+            list,
+            modified,
+        })
+    }
+
+    // Empty lists kept only in memory, for simulations. Saving does nothing.
+    pub fn in_memory() -> AccessFile {
+        AccessFile {
+            path: PathBuf::new(),
+            list: AccessList::default(),
+            modified: None,
+        }
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.list.save(&self.path)?;
+        self.modified = modified_time(&self.path);
+        Ok(())
+    }
+
+    // Returns Ok(true) if the file changed on disk and was loaded again.
+    pub fn reload_if_changed(&mut self) -> io::Result<bool> {
+        let modified = modified_time(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return Ok(false);
+        }
+        // Remember the new time first so a broken file is only reported once
+        self.modified = modified;
+        self.list = AccessList::load(&self.path)?;
+        Ok(true)
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
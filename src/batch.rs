@@ -0,0 +1,152 @@
+// Packing several packets into one datagram.
+//
+// A batch is a packet of type Batch (sequence number 0) whose payload is a
+// run of frames:
+//
+//     length: u16 (big-endian), packet bytes
+//
+// Every packet inside keeps its own header, so sequence numbers and receive
+// windows work exactly as they do for packets sent on their own. Packets too
+// big to share a datagram go out separately, fragmented if need be.
+
+use std::collections::HashMap;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tracing::warn;
+
+use crate::{fragment, GamePacket, MessageType};
+
+pub const FRAME_HEADER_LEN: usize = 2;
+
+// Packets waiting to be sent, per destination, until the next flush.
+#[derive(Debug)]
+pub struct Outbox {
+    max_datagram: usize,
+    queued: HashMap<String, Vec<GamePacket>>,
+}
+
+impl Outbox {
+    pub fn new(max_datagram: usize) -> Self {
+        Outbox {
+            max_datagram,
+            queued: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, target: &str, packet: GamePacket) {
+        self.queued
+            .entry(target.to_string())
+            .or_default()
+            .push(packet);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    // Empties the outbox, returning the datagrams to send to each destination.
+    pub fn drain(&mut self) -> Vec<(String, Vec<Bytes>)> {
+        let max_datagram = self.max_datagram;
+        self.queued
+            .drain()
+            .map(|(target, packets)| (target, pack(packets, max_datagram)))
+            .collect()
+    }
+}
+
+// Packs packets, in order, into as few datagrams of at most `max_datagram`
+// bytes as possible.
+pub fn pack(packets: Vec<GamePacket>, max_datagram: usize) -> Vec<Bytes> {
+    let mut datagrams = Vec::new();
+    let mut pending: Vec<GamePacket> = Vec::new();
+    let mut size = GamePacket::HEADER_LEN;
+    for packet in packets {
+        let framed = FRAME_HEADER_LEN + packet.encoded_len();
+        if GamePacket::HEADER_LEN + framed > max_datagram {
+            finish(&mut datagrams, &mut pending);
+            size = GamePacket::HEADER_LEN;
+            match fragment::split(&packet, max_datagram) {
+                Ok(fragments) => datagrams.extend(fragments),
+                Err(e) => warn!(msg_type = ?packet.msg_type, error = %e, "dropping packet"),
+            }
+            continue;
+        }
+        if size + framed > max_datagram {
+            finish(&mut datagrams, &mut pending);
+            size = GamePacket::HEADER_LEN;
+        }
+        size += framed;
+        pending.push(packet);
+    }
+    finish(&mut datagrams, &mut pending);
+    datagrams
+}
+
+fn finish(datagrams: &mut Vec<Bytes>, pending: &mut Vec<GamePacket>) {
+    match pending.len() {
+        0 => {}
+        // Nothing to share the datagram with, so skip the batch header
+        1 => datagrams.push(pending.pop().unwrap().serialize()),
+        _ => {
+            let len = pending
+                .iter()
+                .map(|p| FRAME_HEADER_LEN + p.encoded_len())
+                .sum::<usize>();
+            let mut buf = BytesMut::with_capacity(GamePacket::HEADER_LEN + len);
+            GamePacket::new(MessageType::Batch, 0, Bytes::new()).encode_into(&mut buf);
+            for packet in pending.drain(..) {
+                buf.put_u16(packet.encoded_len() as u16);
+                packet.encode_into(&mut buf);
+            }
+            datagrams.push(buf.freeze());
+        }
+    }
+}
+
+// Iterator returned by GamePacket::messages.
+#[derive(Debug)]
+pub struct Messages {
+    // Frames still to read if this is a batch, otherwise the whole datagram
+    rest: Bytes,
+    batched: bool,
+}
+
+impl Messages {
+    pub fn new(mut data: Bytes) -> Self {
+        let batched = data.len() >= GamePacket::HEADER_LEN
+            && matches!(MessageType::from_byte(data[0]), Some(MessageType::Batch));
+        if batched {
+            data.advance(GamePacket::HEADER_LEN);
+        }
+        Messages {
+            rest: data,
+            batched,
+        }
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Option<GamePacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        if !self.batched {
+            return Some(GamePacket::decode(std::mem::take(&mut self.rest)));
+        }
+        if self.rest.len() < FRAME_HEADER_LEN {
+            self.rest.clear();
+            return Some(None);
+        }
+        let len = self.rest.get_u16() as usize;
+        if self.rest.len() < len {
+            // Truncated: nothing after this can be trusted
+            self.rest.clear();
+            return Some(None);
+        }
+        let frame = self.rest.split_to(len);
+        // Batches don't nest
+        Some(GamePacket::decode(frame).filter(|p| !matches!(p.msg_type, MessageType::Batch)))
+    }
+}
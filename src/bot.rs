@@ -0,0 +1,465 @@
+// Headless load-testing client. Runs many simulated players from one process,
+// each doing a random walk, chatting and answering heartbeats, then reports
+// how the server treated them.
+//
+// Usage: bot [--clients N] [--duration SECS] [--server ADDR]
+//            [--move-rate HZ] [--chat-rate PER_MINUTE] [--seed N]
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use game_udp::{
+    fragment::{self, Reassembler},
+    quality::{ConnectionQuality, Heartbeat},
+    sequence::{ReceiveWindow, SeqCheck, SequenceCounter},
+    Chat, GamePacket, MessageType, MoveAck, MoveInput, PlayerColor, PlayerProfile, Position,
+    ServerStateSend,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    net::UdpSocket,
+    task::JoinSet,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+
+const LOG_FILE: &str = "bot.log";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Moves per second a bot may send. Anything faster floods the server rather
+// than load-testing it.
+const MOVE_RATES: RangeInclusive<f64> = 0.01..=1000.0;
+// Chats per minute; 0 turns chat off.
+const CHAT_RATES: RangeInclusive<f64> = 0.0..=6000.0;
+const COLORS: [PlayerColor; 7] = [
+    PlayerColor::Red,
+    PlayerColor::Green,
+    PlayerColor::Yellow,
+    PlayerColor::Blue,
+    PlayerColor::Magenta,
+    PlayerColor::Cyan,
+    PlayerColor::White,
+];
+
+#[derive(Debug, Clone)]
+struct Options {
+    clients: usize,
+    duration: Duration,
+    server: SocketAddr,
+    move_rate: f64,
+    chat_rate: f64,
+    seed: u64,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            clients: 10,
+            duration: Duration::from_secs(30),
+            server: "127.0.0.1:4000".parse().unwrap(),
+            move_rate: 5.0,
+            chat_rate: 2.0,
+            seed: 0,
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--clients" => options.clients = parse_value(&flag, &value)?,
+                "--duration" => {
+                    options.duration = Duration::try_from_secs_f64(parse_value(&flag, &value)?)
+                        .ok()
+                        .filter(|duration| !duration.is_zero())
+                        .ok_or("--duration must be a positive number of seconds")?
+                }
+                "--server" => options.server = parse_value(&flag, &value)?,
+                "--move-rate" => options.move_rate = parse_value(&flag, &value)?,
+                "--chat-rate" => options.chat_rate = parse_value(&flag, &value)?,
+                "--seed" => options.seed = parse_value(&flag, &value)?,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+        if !MOVE_RATES.contains(&options.move_rate) {
+            return Err(format!(
+                "--move-rate must be from {} to {}",
+                MOVE_RATES.start(),
+                MOVE_RATES.end()
+            ));
+        }
+        if !CHAT_RATES.contains(&options.chat_rate) {
+            return Err(format!(
+                "--chat-rate must be from {} to {}",
+                CHAT_RATES.start(),
+                CHAT_RATES.end()
+            ));
+        }
+        Ok(options)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// What one bot saw during the run.
+#[derive(Debug, Default)]
+struct Report {
+    connected: bool,
+    moves_sent: u64,
+    moves_confirmed: u64,
+    // Confirmed, but at a different position than we asked for
+    moves_corrected: u64,
+    latencies: Vec<Duration>,
+    chats_sent: u64,
+    chats_received: u64,
+    packets_received: u64,
+    // Span of server sequence numbers seen, to estimate loss from
+    packets_expected: u64,
+    rtt_ms: Option<f64>,
+    // The server's view of how many of our heartbeats it lost
+    upload_loss_percent: Option<f64>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.moves_sent += other.moves_sent;
+        self.moves_confirmed += other.moves_confirmed;
+        self.moves_corrected += other.moves_corrected;
+        self.latencies.extend(other.latencies);
+        self.chats_sent += other.chats_sent;
+        self.chats_received += other.chats_received;
+        self.packets_received += other.packets_received;
+        self.packets_expected += other.packets_expected;
+    }
+}
+
+struct Bot {
+    id: usize,
+    socket: UdpSocket,
+    rng: StdRng,
+    seq: SequenceCounter,
+    reassembler: Reassembler<()>,
+    quality: ConnectionQuality,
+    recv_window: ReceiveWindow,
+    first_seq: Option<u32>,
+    board_size: (u32, u32),
+    position: Position,
+    input_seq: u32,
+    // When each unconfirmed move was sent
+    pending_moves: HashMap<u32, (Instant, Position)>,
+    report: Report,
+}
+
+impl Bot {
+    async fn connect(id: usize, server: SocketAddr, seed: u64) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(server).await?;
+        Ok(Bot {
+            id,
+            socket,
+            rng: StdRng::seed_from_u64(seed),
+            seq: SequenceCounter::default(),
+            reassembler: Reassembler::new(),
+            quality: ConnectionQuality::new(Instant::now()),
+            recv_window: ReceiveWindow::new(),
+            first_seq: None,
+            board_size: (0, 0),
+            position: Position::new(0, 0, 0),
+            input_seq: 0,
+            pending_moves: HashMap::new(),
+            report: Report::default(),
+        })
+    }
+
+    async fn send(&self, msg_type: MessageType, payload: Vec<u8>) -> std::io::Result<()> {
+        let packet = GamePacket::new(msg_type, self.seq.next(), payload);
+        self.socket.send(&packet.serialize()).await?;
+        Ok(())
+    }
+
+    async fn run(mut self, options: Options) -> std::io::Result<Report> {
+        let color = COLORS[self.rng.random_range(0..COLORS.len())];
+        let profile = PlayerProfile::new(&format!("bot{}", self.id), color);
+        self.send(MessageType::ConnectionInit, profile.serialize())
+            .await?;
+
+        let mut pool = BytesMut::with_capacity(fragment::RECV_BUFFER_SIZE);
+        let connect_deadline = time::sleep(CONNECT_TIMEOUT);
+        tokio::pin!(connect_deadline);
+        while !self.report.connected {
+            tokio::select! {
+                _ = &mut connect_deadline => {
+                    warn!(bot = self.id, "no ConnectionInit reply");
+                    return Ok(self.report);
+                }
+                received = self.recv(&mut pool) => received?,
+            }
+        }
+
+        let mut moves = time::interval(Duration::from_secs_f64(1.0 / options.move_rate));
+        moves.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let next_chat = time::sleep(self.chat_delay(options.chat_rate));
+        tokio::pin!(next_chat);
+        let finished = time::sleep(options.duration);
+        tokio::pin!(finished);
+        loop {
+            tokio::select! {
+                _ = &mut finished => break,
+                _ = moves.tick() => self.step().await?,
+                _ = &mut next_chat => {
+                    self.report.chats_sent += 1;
+                    let chat = Chat {
+                        text: format!("bot{} says hello #{}", self.id, self.report.chats_sent),
+                    };
+                    self.send(MessageType::ChatMessage, serde_json::to_vec(&chat).unwrap())
+                        .await?;
+                    next_chat
+                        .as_mut()
+                        .reset(time::Instant::now() + self.chat_delay(options.chat_rate));
+                }
+                received = self.recv(&mut pool) => received?,
+            }
+        }
+
+        let stats = self.quality.stats();
+        self.report.rtt_ms = stats.rtt_ms;
+        if let (Some(first), Some(highest)) = (self.first_seq, self.recv_window.highest()) {
+            self.report.packets_expected = highest.wrapping_sub(first) as u64 + 1;
+        }
+        Ok(self.report)
+    }
+
+    // Chats arrive as a Poisson process at `per_minute` on average.
+    fn chat_delay(&mut self, per_minute: f64) -> Duration {
+        if per_minute <= 0.0 {
+            // Effectively never
+            return Duration::from_secs(365 * 24 * 3600);
+        }
+        let u: f64 = self.rng.random_range(f64::EPSILON..1.0);
+        Duration::from_secs_f64(-u.ln() * 60.0 / per_minute)
+    }
+
+    // Takes one random step, staying inside the board.
+    async fn step(&mut self) -> std::io::Result<()> {
+        let (width, height) = (self.board_size.0 as i32, self.board_size.1 as i32);
+        let (dx, dy) = [(0, 1), (0, -1), (1, 0), (-1, 0)][self.rng.random_range(0..4)];
+        let mut position = self.position.clone();
+        position.x = (position.x + dx).clamp(-width / 2, width / 2 - 1);
+        position.y = (position.y + dy).clamp(-height / 2 + 2, height / 2 - 1);
+
+        self.input_seq = self.input_seq.wrapping_add(1);
+        let input = MoveInput {
+            input_seq: self.input_seq,
+            position: position.clone(),
+        };
+        self.pending_moves
+            .insert(self.input_seq, (Instant::now(), position.clone()));
+        self.position = position;
+        self.report.moves_sent += 1;
+        self.send(MessageType::PositionUpdate, input.serialize())
+            .await
+    }
+
+    async fn recv(&mut self, pool: &mut BytesMut) -> std::io::Result<()> {
+        pool.clear();
+        pool.reserve(fragment::RECV_BUFFER_SIZE);
+        self.socket.recv_buf(pool).await?;
+        for message in GamePacket::messages(pool.split().freeze()) {
+            let Some(mut packet) = message else {
+                continue;
+            };
+            if let MessageType::Fragment = packet.msg_type {
+                match self.reassembler.insert((), &packet, Instant::now()) {
+                    Ok(Some(complete)) => packet = complete,
+                    _ => continue,
+                }
+            }
+            if !matches!(self.recv_window.check(packet.seq_num), SeqCheck::New { .. }) {
+                continue;
+            }
+            self.first_seq.get_or_insert(packet.seq_num);
+            self.report.packets_received += 1;
+            self.handle(packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, packet: GamePacket) -> std::io::Result<()> {
+        match packet.msg_type {
+            MessageType::ConnectionInit => {
+                if let Ok(state) = ServerStateSend::deserialize(&packet.payload) {
+                    self.board_size = state.board_size;
+                }
+                self.report.connected = true;
+                debug!(bot = self.id, "connected");
+            }
+            MessageType::ConfirmPlayerMovement => {
+                let Some(ack) = MoveAck::deserialize(&packet.payload) else {
+                    return Ok(());
+                };
+                if let Some((sent_at, requested)) = self.pending_moves.remove(&ack.input_seq) {
+                    self.report.moves_confirmed += 1;
+                    self.report.latencies.push(sent_at.elapsed());
+                    if (requested.x, requested.y) != (ack.position.x, ack.position.y) {
+                        self.report.moves_corrected += 1;
+                        if ack.input_seq == self.input_seq {
+                            self.position = ack.position;
+                        }
+                    }
+                }
+            }
+            MessageType::ChatMessage => self.report.chats_received += 1,
+            MessageType::Heartbeat => {
+                let now = Instant::now();
+                if let Some(heartbeat) = Heartbeat::deserialize(&packet.payload) {
+                    self.quality.on_heartbeat(&heartbeat, now);
+                    if let Some(stats) = heartbeat.stats {
+                        self.report.upload_loss_percent = Some(stats.loss_percent);
+                    }
+                }
+                let reply = self.quality.heartbeat(now).serialize();
+                self.send(MessageType::Heartbeat, reply).await?;
+            }
+            MessageType::ConnectionRejected => {
+                warn!(bot = self.id, "connection rejected");
+                self.report.connected = false;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[index]
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            eprintln!(
+                "Usage: bot [--clients N] [--duration SECS] [--server ADDR] [--move-rate HZ] [--chat-rate PER_MINUTE] [--seed N]"
+            );
+            std::process::exit(2);
+        }
+    };
+    game_udp::logging::init(LOG_FILE)?;
+    info!(?options, "starting bots");
+    println!(
+        "Running {} bots against {} for {:?}",
+        options.clients, options.server, options.duration
+    );
+
+    let mut bots = JoinSet::new();
+    for id in 0..options.clients {
+        let bot = Bot::connect(id, options.server, options.seed.wrapping_add(id as u64)).await?;
+        let options = options.clone();
+        bots.spawn(async move { (id, bot.run(options).await) });
+        // Spread the joins out a little rather than landing them all at once
+        time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let mut total = Report::default();
+    let mut connected = 0;
+    let mut rtts = Vec::new();
+    let mut upload_losses = Vec::new();
+    // One bot failing shouldn't lose what the others saw
+    let mut failures = Vec::new();
+    while let Some(result) = bots.join_next().await {
+        let report = match result {
+            Ok((_, Ok(report))) => report,
+            Ok((id, Err(e))) => {
+                error!(bot = id, error = %e, "bot failed");
+                failures.push(format!("bot{}: {}", id, e));
+                continue;
+            }
+            Err(e) => {
+                error!(error = %e, "bot task failed");
+                failures.push(e.to_string());
+                continue;
+            }
+        };
+        if report.connected {
+            connected += 1;
+        }
+        rtts.extend(report.rtt_ms);
+        upload_losses.extend(report.upload_loss_percent);
+        total.merge(report);
+    }
+    total.latencies.sort_unstable();
+    let mean = |values: &[f64]| {
+        if values.is_empty() {
+            0.0
+        } else {
+            values.iter().sum::<f64>() / values.len() as f64
+        }
+    };
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+    println!("Bots connected: {}/{}", connected, options.clients);
+    if !failures.is_empty() {
+        println!("Bots failed: {}/{}", failures.len(), options.clients);
+        for failure in &failures {
+            println!("  {}", failure);
+        }
+    }
+    println!(
+        "Moves: {} sent, {} confirmed ({:.1}%), {} corrected by the server",
+        total.moves_sent,
+        total.moves_confirmed,
+        ratio(total.moves_confirmed, total.moves_sent),
+        total.moves_corrected
+    );
+    let latencies = &total.latencies;
+    println!(
+        "Confirmation latency: p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+        ms(percentile(latencies, 0.5)),
+        ms(percentile(latencies, 0.9)),
+        ms(percentile(latencies, 0.99)),
+        ms(latencies.last().copied().unwrap_or_default())
+    );
+    println!(
+        "Chat: {} sent, {} received",
+        total.chats_sent, total.chats_received
+    );
+    let lost = total
+        .packets_expected
+        .saturating_sub(total.packets_received);
+    println!(
+        "Server packets: {} received, {:.2}% lost",
+        total.packets_received,
+        ratio(lost, total.packets_expected)
+    );
+    println!(
+        "Heartbeats: mean rtt {:.2}ms, mean upload loss {:.2}%",
+        mean(&rtts),
+        mean(&upload_losses)
+    );
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
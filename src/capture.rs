@@ -0,0 +1,359 @@
+// Packet captures: every packet a server or client handles, with when it was
+// handled and who it came from or went to, for looking into desyncs after the
+// fact. The replay binary reads them back.
+//
+// GAME_UDP_CAPTURE names the file to record to. Packets are recorded after
+// fragments are reassembled and before they are batched, so a capture holds
+// whole packets rather than datagrams. The file is
+//
+//     magic "GUDPCAP", version: u8, role: u8
+//
+// followed by records, each starting with a kind byte:
+//
+//     peer:     0, id: u32, length: u8, address
+//     inbound:  1, micros: u64, peer: u32, length: u32, packet
+//     outbound: 2, micros: u64, peer: u32, length: u32, packet
+//
+// Integers are big-endian and times count from when recording started. A
+// peer record comes before the first packet to or from that peer.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::{clock::Clock, GamePacket};
+
+pub const CAPTURE_VAR: &str = "GAME_UDP_CAPTURE";
+
+const MAGIC: &[u8; 7] = b"GUDPCAP";
+const VERSION: u8 = 1;
+
+const PEER: u8 = 0;
+const INBOUND: u8 = 1;
+const OUTBOUND: u8 = 2;
+
+// Which end of the connection made a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server = 0,
+    Client = 1,
+}
+
+// Whether a packet was received or sent by whoever recorded it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// What the recorder hands its writer thread.
+#[derive(Debug)]
+enum Entry {
+    Packet {
+        direction: Direction,
+        at: Duration,
+        peer: String,
+        // The packet, already serialized
+        bytes: Bytes,
+    },
+    // Write out everything so far, then say so
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug)]
+struct Writer {
+    out: BufWriter<File>,
+    // IDs handed out so far, by peer address
+    peers: HashMap<String, u32>,
+}
+
+impl Writer {
+    // Writes entries until the recorder hangs up. Errors are logged rather
+    // than returned, since losing the capture shouldn't stop the game, and
+    // only the first is, so a full disk is only reported once.
+    fn run(mut self, entries: Receiver<Entry>) {
+        let mut failed = false;
+        let mut check = |result: io::Result<()>| {
+            if let Err(e) = result {
+                if !failed {
+                    warn!(error = %e, "failed to write packet capture");
+                }
+                failed = true;
+            }
+        };
+        for entry in entries {
+            match entry {
+                Entry::Packet {
+                    direction,
+                    at,
+                    peer,
+                    bytes,
+                } => check(self.packet(direction, at, &peer, &bytes)),
+                Entry::Flush(done) => {
+                    check(self.out.flush());
+                    let _ = done.send(());
+                }
+            }
+        }
+        check(self.out.flush());
+    }
+
+    fn peer_id(&mut self, addr: &str) -> io::Result<u32> {
+        if let Some(&id) = self.peers.get(addr) {
+            return Ok(id);
+        }
+        let id = self.peers.len() as u32;
+        let addr_bytes = &addr.as_bytes()[..addr.len().min(u8::MAX as usize)];
+        self.out.write_all(&[PEER])?;
+        self.out.write_all(&id.to_be_bytes())?;
+        self.out.write_all(&[addr_bytes.len() as u8])?;
+        self.out.write_all(addr_bytes)?;
+        self.peers.insert(addr.to_string(), id);
+        Ok(id)
+    }
+
+    fn packet(
+        &mut self,
+        direction: Direction,
+        at: Duration,
+        addr: &str,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let peer = self.peer_id(addr)?;
+        let kind = match direction {
+            Direction::Inbound => INBOUND,
+            Direction::Outbound => OUTBOUND,
+        };
+        self.out.write_all(&[kind])?;
+        self.out.write_all(&(at.as_micros() as u64).to_be_bytes())?;
+        self.out.write_all(&peer.to_be_bytes())?;
+        self.out.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.out.write_all(bytes)
+    }
+}
+
+// Writes a capture file. Recording happens while the server holds its state
+// lock, so the file itself is written on a thread of its own and recording
+// only queues the packet for it.
+#[derive(Debug)]
+pub struct Recorder {
+    // Taken on drop, which ends the writer thread once it's caught up
+    entries: Option<Sender<Entry>>,
+    writer: Option<JoinHandle<()>>,
+    clock: Clock,
+    started: Instant,
+}
+
+impl Recorder {
+    // Starts a recording at `path`, timed by `clock`.
+    pub fn create(path: impl AsRef<Path>, role: Role, clock: Clock) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION, role as u8])?;
+        let writer = Writer {
+            out,
+            peers: HashMap::new(),
+        };
+        let (entries, received) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || writer.run(received))?;
+        let started = clock.now();
+        Ok(Recorder {
+            entries: Some(entries),
+            writer: Some(writer),
+            clock,
+            started,
+        })
+    }
+
+    // Starts a recording at the path in GAME_UDP_CAPTURE, if it's set.
+    pub fn from_env(role: Role, clock: Clock) -> io::Result<Option<Arc<Self>>> {
+        match std::env::var(CAPTURE_VAR) {
+            Ok(path) if !path.is_empty() => Ok(Some(Arc::new(Self::create(path, role, clock)?))),
+            _ => Ok(None),
+        }
+    }
+
+    // Records a packet received from or sent to `peer`.
+    pub fn record(&self, direction: Direction, peer: &str, packet: &GamePacket) {
+        let at = self.clock.now().saturating_duration_since(self.started);
+        self.send(Entry::Packet {
+            direction,
+            at,
+            peer: peer.to_string(),
+            bytes: packet.serialize(),
+        });
+    }
+
+    // Writes out everything recorded so far, returning once it's written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        self.send(Entry::Flush(done));
+        let _ = written.await;
+    }
+
+    fn send(&self, entry: Entry) {
+        if let Some(entries) = &self.entries {
+            // Only fails if the writer thread panicked
+            let _ = entries.send(entry);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        drop(self.entries.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// One packet from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    // Time since recording started
+    pub at: Duration,
+    pub direction: Direction,
+    pub peer: u32,
+    // The peer's address, as the recorder saw it
+    pub addr: String,
+    pub packet: GamePacket,
+}
+
+// Reads the records of a capture file in order.
+#[derive(Debug)]
+pub struct Reader<R> {
+    input: R,
+    role: Role,
+    peers: HashMap<u32, String>,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 9];
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(invalid("not a packet capture"))
+            }
+            Err(e) => return Err(e),
+        }
+        if &header[..7] != MAGIC {
+            return Err(invalid("not a packet capture"));
+        }
+        if header[7] != VERSION {
+            return Err(invalid(format!(
+                "unsupported capture version {}",
+                header[7]
+            )));
+        }
+        let role = match header[8] {
+            0 => Role::Server,
+            1 => Role::Client,
+            other => return Err(invalid(format!("unknown capture role {}", other))),
+        };
+        Ok(Reader {
+            input,
+            role,
+            peers: HashMap::new(),
+        })
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    // Reads the next packet record, or None at the end of the file.
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let mut kind = [0];
+            match self.input.read_exact(&mut kind) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let direction = match kind[0] {
+                PEER => {
+                    let id = u32::from_be_bytes(self.read_array()?);
+                    let [len] = self.read_array()?;
+                    let addr = self.read_vec(len as usize)?;
+                    let addr =
+                        String::from_utf8(addr).map_err(|_| invalid("peer address isn't UTF-8"))?;
+                    self.peers.insert(id, addr);
+                    continue;
+                }
+                INBOUND => Direction::Inbound,
+                OUTBOUND => Direction::Outbound,
+                other => return Err(invalid(format!("unknown record kind {}", other))),
+            };
+            let at = Duration::from_micros(u64::from_be_bytes(self.read_array()?));
+            let peer = u32::from_be_bytes(self.read_array()?);
+            let len = u32::from_be_bytes(self.read_array()?);
+            let bytes = self.read_vec(len as usize)?;
+            let addr = self
+                .peers
+                .get(&peer)
+                .cloned()
+                .ok_or_else(|| invalid(format!("packet for undeclared peer {}", peer)))?;
+            let packet = GamePacket::decode(Bytes::from(bytes))
+                .ok_or_else(|| invalid("undecodable packet"))?;
+            return Ok(Some(Record {
+                at,
+                direction,
+                peer,
+                addr,
+                packet,
+            }));
+        }
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.input.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_vec(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        // Take rather than allocating `len` up front, so a corrupt length
+        // can't ask for gigabytes
+        (&mut self.input).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
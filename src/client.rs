@@ -1,4 +1,6 @@
 use std::{
+    collections::HashMap,
+    io,
     net::SocketAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -6,38 +8,84 @@ use std::{
     },
 };
 
+use bytes::BytesMut;
 use crossterm::{
     event::{self, Event, KeyCode},
     terminal::{disable_raw_mode, enable_raw_mode},
 };
 use game_udp::{
-    Chat, GamePacket, MessageType, PlayerStateSend, PlayerUpdate, Position, ServerStateSend,
+    capture::{Direction, Recorder, Role},
+    clock::Clock,
+    fragment::{self, Reassembler},
+    quality::{ConnectionQuality, Heartbeat},
+    reconnect::{Link, LinkState, SERVER_TIMEOUT},
+    render_hud,
+    sequence::{seq_greater, ReceiveWindow, SeqCheck, SequenceCounter},
+    Chat, ConnectionRejected, ConnectionRequest, GamePacket, MessageType, MoveAck, MoveInput,
+    PlayerColor, PlayerJoin, PlayerProfile, PlayerStateSend, PlayerUpdate, Position,
+    ServerShutdown, ServerStateSend,
 };
 use tokio::{
     net::UdpSocket,
     sync::Mutex,
-    time::{Duration, Instant},
+    time::{self, Duration, Instant},
 };
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+const LOG_FILE: &str = "client.log";
+
+// Records packets to and from the server when GAME_UDP_CAPTURE is set.
+#[derive(Clone)]
+struct Capture {
+    recorder: Option<Arc<Recorder>>,
+    server: String,
+}
+
+impl Capture {
+    fn record(&self, direction: Direction, packet: &GamePacket) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(direction, &self.server, packet);
+        }
+    }
+
+    async fn flush(&self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.flush().await;
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server_addr: SocketAddr = "127.0.0.1:4000".parse()?;
     let client_addr = "0.0.0.0:0"; // OS chooses a free port
 
+    // Usage: client [name] [color]
+    let mut args = std::env::args().skip(1);
+    let name = args.next().unwrap_or_else(|| "Player".to_string());
+    let color = args
+        .next()
+        .and_then(|c| PlayerColor::from_name(&c))
+        .unwrap_or_default();
+    let profile = PlayerProfile::new(&name, color);
+
+    game_udp::logging::init(LOG_FILE)?;
+
     let socket = UdpSocket::bind(client_addr).await?;
     socket.connect(&server_addr).await?;
+    let session_span = info_span!("session", local = %socket.local_addr()?, server = %server_addr);
+    info!(parent: &session_span, name = %profile.name, "connecting");
     let socket = Arc::new(socket);
 
-    let sequence_num = Arc::new(Mutex::new(1u32));
+    // Packet sequence numbers for everything we send to the server
+    let sequence_num = Arc::new(SequenceCounter::default());
     let shutdown_signal = Arc::new(AtomicBool::new(false));
+    let max_datagram = fragment::max_datagram_from_env();
+    let capture = Capture {
+        recorder: Recorder::from_env(Role::Client, Clock::Runtime)?,
+        server: server_addr.to_string(),
+    };
 
-    // Initialize connection
-    {
-        let mut seq = sequence_num.lock().await;
-        let init_packet = GamePacket::new(MessageType::ConnectionInit, *seq, vec![]);
-        *seq += 1;
-        socket.send(&init_packet.serialize()).await?;
-    }
     let server_state = Arc::new(Mutex::new(ServerStateSend::new()));
     // Shared position state
     let position = Arc::new(Mutex::new(Position { x: 0, y: 0, z: 0 }));
@@ -48,65 +96,256 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let sequence_num = Arc::clone(&sequence_num);
         let shutdown_signal = Arc::clone(&shutdown_signal);
         let position = Arc::clone(&position);
-        tokio::spawn(async move {
-            let mut buf = vec![0u8; 1500];
+        let capture = capture.clone();
+        let span = session_span.clone();
+        tokio::spawn(
+            async move {
+            let mut pool = BytesMut::with_capacity(fragment::RECV_BUFFER_SIZE);
+            let mut reassembler = Reassembler::<()>::new();
+            let mut quality = ConnectionQuality::new(std::time::Instant::now());
+            let mut recv_window = ReceiveWindow::new();
+            // Packet sequence of the newest position seen for each player
+            let mut latest_updates: HashMap<String, u32> = HashMap::new();
+            let mut last_ack: Option<u32> = None;
+            let mut link = Link::new(SERVER_TIMEOUT, std::time::Instant::now());
+            // Given by the server when we join, to get the same session back
+            let mut session_token: Option<String> = None;
             while !shutdown_signal.load(Ordering::Relaxed) {
-                if let Ok(len) = socket.recv(&mut buf).await {
-                    if let Some(reply) = GamePacket::deserialize(&buf[..len]) {
-                        match reply.msg_type {
-                            MessageType::Heartbeat => {
-                                let mut seq = sequence_num.lock().await;
-                                let hb_packet =
-                                    GamePacket::new(MessageType::Heartbeat, *seq, vec![]);
-                                *seq += 1;
-                                if let Err(e) = socket.send(&hb_packet.serialize()).await {
-                                    eprintln!("Failed to send heartbeat response: {}", e);
-                                }
+                if link.poll(std::time::Instant::now()) {
+                    let status = match link.state() {
+                        LinkState::Reconnecting => Some("Lost the server, reconnecting"),
+                        _ if link.attempts() > 1 => Some("Waiting for the server"),
+                        _ => None,
+                    };
+                    if let Some(status) = status {
+                        let _ = render_hud(&format!("{} (attempt {})...", status, link.attempts()));
+                        info!(state = ?link.state(), attempt = link.attempts(), "connecting");
+                    }
+                    // A restarted server numbers its packets from scratch, so
+                    // whatever we knew about the old session has to go
+                    reassembler = Reassembler::new();
+                    quality = ConnectionQuality::new(std::time::Instant::now());
+                    recv_window = ReceiveWindow::new();
+                    latest_updates.clear();
+                    last_ack = None;
+                    let request = ConnectionRequest {
+                        profile: profile.clone(),
+                        session: session_token.clone(),
+                    };
+                    let init_packet = GamePacket::new(
+                        MessageType::ConnectionInit,
+                        sequence_num.next(),
+                        request.serialize(),
+                    );
+                    capture.record(Direction::Outbound, &init_packet);
+                    // A send reports an earlier datagram being refused, and
+                    // doesn't go out itself, so try again in that case
+                    let datagram = init_packet.serialize();
+                    let sent = match socket.send(&datagram).await {
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            socket.send(&datagram).await
+                        }
+                        sent => sent,
+                    };
+                    if let Err(e) = sent {
+                        warn!(error = %e, "failed to send ConnectionInit");
+                    }
+                }
+
+                pool.clear();
+                pool.reserve(fragment::RECV_BUFFER_SIZE);
+                let deadline = Instant::from_std(link.deadline());
+                let received = match time::timeout_at(deadline, socket.recv_buf(&mut pool)).await {
+                    Ok(received) => received,
+                    // Time for the next attempt, or to give up on the server
+                    Err(_) => continue,
+                };
+                let len = match received {
+                    Ok(len) => len,
+                    Err(e) => {
+                        // Most likely the server's port is closed. Don't spin
+                        // on it, the timeout deals with it in the end.
+                        debug!(error = %e, "receive failed");
+                        time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                link.heard(std::time::Instant::now());
+                for message in GamePacket::messages(pool.split().freeze()) {
+                    let Some(mut reply) = message else {
+                        warn!(len, "dropping undecodable message");
+                        continue;
+                    };
+                    trace!(msg_type = ?reply.msg_type, seq = reply.seq_num, len, "received packet");
+                    if let MessageType::Fragment = reply.msg_type {
+                        match reassembler.insert((), &reply, std::time::Instant::now()) {
+                            Ok(Some(complete)) => reply = complete,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                warn!(seq = reply.seq_num, error = %e, "dropping fragment");
+                                continue;
                             }
-                            MessageType::PositionUpdate => {
-                                let player_state = PlayerUpdate::deserialize(&reply.payload);
-                                if let Some(player_state) = player_state {
-                                    let mut state = server_state.lock().await;
-                                    if let Some(player) =
-                                        state.players.get_mut(&player_state.player)
-                                    {
-                                        player.position = player_state.position;
-                                    }
+                        }
+                    }
+                    capture.record(Direction::Inbound, &reply);
+                    let check = recv_window.check(reply.seq_num);
+                    if !matches!(check, SeqCheck::New { .. }) {
+                        debug!(?check, seq = reply.seq_num, "dropping repeated or stale packet");
+                        continue;
+                    }
+                    match reply.msg_type {
+                        MessageType::Heartbeat => {
+                            let now = std::time::Instant::now();
+                            let server_view = Heartbeat::deserialize(&reply.payload)
+                                .and_then(|heartbeat| {
+                                    quality.on_heartbeat(&heartbeat, now);
+                                    heartbeat.stats
+                                });
+                            let stats = quality.stats();
+                            let mut hud = stats.summary();
+                            if let Some(server_view) = server_view {
+                                hud.push_str(&format!(
+                                    "  upload loss {:.0}%",
+                                    server_view.loss_percent
+                                ));
+                            }
+                            let _ = render_hud(&hud);
+                            debug!(?stats, ?server_view, "connection quality");
 
-                                    // println!("Server PositionUpdate: {:?}", state);
+                            let hb_packet = GamePacket::new(
+                                MessageType::Heartbeat,
+                                sequence_num.next(),
+                                quality.heartbeat(now).serialize(),
+                            );
+                            capture.record(Direction::Outbound, &hb_packet);
+                            if let Err(e) = socket.send(&hb_packet.serialize()).await {
+                                warn!(error = %e, "failed to send heartbeat response");
+                            }
+                            capture.flush().await;
+                        }
+                        MessageType::PositionUpdate => {
+                            let player_state = PlayerUpdate::deserialize(&reply.payload);
+                            if let Some(player_state) = player_state {
+                                // Ignore positions older than one we've already applied
+                                let latest = latest_updates
+                                    .entry(player_state.player.clone())
+                                    .or_insert(reply.seq_num);
+                                if seq_greater(*latest, reply.seq_num) {
+                                    debug!(
+                                        player = %player_state.player,
+                                        "dropping stale position update"
+                                    );
+                                    continue;
+                                }
+                                *latest = reply.seq_num;
+                                let mut state = server_state.lock().await;
+                                if let Some(player) =
+                                    state.players.get_mut(&player_state.player)
+                                {
+                                    player.position = player_state.position;
                                 }
+
+                                debug!(player = %player_state.player, "position update");
                             }
-                            MessageType::ChatMessage => {
-                                // println!("Server ChatMessage: {:?}", reply);
+                        }
+                        MessageType::ChatMessage => {
+                            if let Ok(chat) = serde_json::from_slice::<Chat>(&reply.payload) {
+                                info!(text = %chat.text, "chat message");
                             }
-                            MessageType::ConnectionInit => {
-                                let server_state_deralized =
-                                    ServerStateSend::deserialize(&reply.payload);
-                                if let Ok(server_state_deralized) = server_state_deralized {
-                                    let mut state = server_state.lock().await;
-                                    *state = server_state_deralized;
+                        }
+                        MessageType::ConnectionInit => {
+                            let Ok(mut snapshot) = ServerStateSend::deserialize(&reply.payload)
+                            else {
+                                continue;
+                            };
+                            if let Some(session) = snapshot.session.take() {
+                                let reconnected = session_token.is_some();
+                                if session.resumed {
+                                    info!("session resumed");
+                                    let _ = render_hud("Reconnected");
+                                } else if reconnected {
+                                    info!("server lost the session, joined again");
+                                    let _ = render_hud("Reconnected as a new player");
+                                }
+                                // The server's word on where we are
+                                if let Some(me) = snapshot.players.get(&session.player) {
+                                    *position.lock().await = me.position.clone();
                                 }
+                                session_token = Some(session.token);
                             }
-                            MessageType::PlayerJoin => {
-                                let player = String::from_utf8(reply.payload).unwrap();
+                            let mut state = server_state.lock().await;
+                            *state = snapshot;
+                        }
+                        MessageType::PlayerJoin => {
+                            if let Some(join) = PlayerJoin::deserialize(&reply.payload) {
                                 let mut state = server_state.lock().await;
-                                state.players.insert(player, PlayerStateSend::new());
-                            }
-                            MessageType::ConfirmPlayerMovement => {
-                                let player_state = Position::deserialize(&reply.payload);
-                                let mut position2 = position.lock().await;
-                                *position2 = player_state.unwrap();
+                                // A player who reconnected from a new address
+                                // takes their old entry with them
+                                if let Some(previous) = &join.previous {
+                                    latest_updates.remove(previous);
+                                    if let Some(player) = state.players.remove(previous) {
+                                        state.players.insert(join.player.clone(), player);
+                                    }
+                                }
+                                // A known player re-announcing themselves changed their name
+                                state
+                                    .players
+                                    .entry(join.player)
+                                    .and_modify(|p| p.profile = join.profile.clone())
+                                    .or_insert_with(|| PlayerStateSend::new(join.profile));
                             }
-                            MessageType::PlayerLeft => {
-                                let player = String::from_utf8(reply.payload).unwrap();
-                                let mut state = server_state.lock().await;
-                                state.players.remove(&player);
+                        }
+                        MessageType::ConfirmPlayerMovement => {
+                            let Some(ack) = MoveAck::deserialize(&reply.payload) else {
+                                continue;
+                            };
+                            if last_ack.is_some_and(|last| !seq_greater(ack.input_seq, last)) {
+                                let input_seq = ack.input_seq;
+                                debug!(input_seq, "dropping stale move confirmation");
+                                continue;
                             }
+                            last_ack = Some(ack.input_seq);
+                            let mut position2 = position.lock().await;
+                            *position2 = ack.position;
+                        }
+                        MessageType::PlayerLeft => {
+                            let player = String::from_utf8(reply.payload.to_vec()).unwrap();
+                            latest_updates.remove(&player);
+                            let mut state = server_state.lock().await;
+                            state.players.remove(&player);
+                        }
+                        MessageType::ConnectionRejected => {
+                            let reason =
+                                serde_json::from_slice::<ConnectionRejected>(&reply.payload)
+                                    .map(|r| r.reason)
+                                    .unwrap_or_default();
+                            println!("Connection rejected by server: {}", reason);
+                            warn!(%reason, "connection rejected");
+                            shutdown_signal.store(true, Ordering::Relaxed);
                         }
+                        MessageType::ServerShutdown => {
+                            let notice = ServerShutdown::deserialize(&reply.payload);
+                            let restart_in = notice.as_ref().and_then(ServerShutdown::restart_in);
+                            let status = match restart_in {
+                                Some(wait) => format!(
+                                    "Server shutting down, back in about {}s",
+                                    wait.as_secs()
+                                ),
+                                None => "Server shutting down".to_string(),
+                            };
+                            let _ = render_hud(&status);
+                            let reason = notice.map(|n| n.reason);
+                            info!(?reason, ?restart_in, "server shutting down");
+                            link.server_stopped(std::time::Instant::now(), restart_in);
+                        }
+                        // Reassembled or unpacked above, never reach here
+                        MessageType::Fragment | MessageType::Batch => {}
                     }
                 }
             }
-        });
+        }
+            .instrument(span),
+        );
     }
 
     // Task for reading user input and sending position updates or chat messages
@@ -115,55 +354,77 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let sequence_num = Arc::clone(&sequence_num);
         let position = Arc::clone(&position);
         let shutdown_signal = Arc::clone(&shutdown_signal);
-        tokio::spawn(async move {
-            enable_raw_mode().expect("Failed to enable raw mode");
-            println!(
-                "Use 'w', 'a', 's', 'd' to move position. Press 'c' followed by your message to send a chat message. Press 'q' to quit."
-            );
-
-            let mut chat_mode = false;
-            let mut chat_message = String::new();
-            let mut last_position_update = Instant::now();
-            let position_update_cooldown = Duration::from_millis(100);
-
-            loop {
-                if event::poll(std::time::Duration::from_millis(100)).unwrap() {
-                    if let Event::Key(key_event) = event::read().unwrap() {
-                        match key_event.code {
-                            KeyCode::Char('q') => {
-                                println!("Exiting...");
-                                shutdown_signal.store(true, Ordering::Relaxed);
-                                break;
-                            }
-                            KeyCode::Char('c') if !chat_mode => {
-                                chat_mode = true;
-                                chat_message.clear();
-                                println!("Enter chat message: ");
-                            }
-                            KeyCode::Char(c) if chat_mode => {
-                                if c == '\n' {
-                                    chat_mode = false;
-                                    let chat = Chat {
-                                        text: chat_message.clone(),
-                                    };
-                                    let chat_bytes = serde_json::to_vec(&chat).unwrap();
+        let capture = capture.clone();
+        let span = session_span.clone();
+        tokio::spawn(
+            async move {
+                enable_raw_mode().expect("Failed to enable raw mode");
+                println!(
+                    "Use 'w', 'a', 's', 'd' to move position. Press 'c' followed by your message \
+                 to send a chat message. Press 'q' to quit."
+                );
 
-                                    let mut seq = sequence_num.lock().await;
-                                    let chat_packet =
-                                        GamePacket::new(MessageType::ChatMessage, *seq, chat_bytes);
-                                    *seq += 1;
+                let mut chat_mode = false;
+                let mut chat_message = String::new();
+                let mut last_position_update = Instant::now();
+                // Numbers our moves, separately from packet sequence numbers
+                let mut input_seq: u32 = 0;
+                let position_update_cooldown = Duration::from_millis(100);
 
-                                    if let Err(e) = socket.send(&chat_packet.serialize()).await {
-                                        eprintln!("Failed to send chat message: {}", e);
-                                    }
+                loop {
+                    if event::poll(std::time::Duration::from_millis(100)).unwrap() {
+                        if let Event::Key(key_event) = event::read().unwrap() {
+                            match key_event.code {
+                                KeyCode::Char('q') => {
+                                    println!("Exiting...");
+                                    shutdown_signal.store(true, Ordering::Relaxed);
+                                    break;
+                                }
+                                KeyCode::Char('c') if !chat_mode => {
+                                    chat_mode = true;
                                     chat_message.clear();
-                                } else {
-                                    chat_message.push(c);
+                                    println!("Enter chat message: ");
                                 }
-                            }
-                            KeyCode::Char(c) if !chat_mode => {
-                                if last_position_update.elapsed() >= position_update_cooldown {
-                                    let position_bytes = {
+                                KeyCode::Char(c) if chat_mode => {
+                                    if c == '\n' {
+                                        chat_mode = false;
+                                        let chat = Chat {
+                                            text: chat_message.clone(),
+                                        };
+                                        let chat_bytes = serde_json::to_vec(&chat).unwrap();
+
+                                        let chat_packet = GamePacket::new(
+                                            MessageType::ChatMessage,
+                                            sequence_num.next(),
+                                            chat_bytes,
+                                        );
+
+                                        capture.record(Direction::Outbound, &chat_packet);
+                                        // Long messages may not fit in one datagram
+                                        let datagrams =
+                                            match fragment::split(&chat_packet, max_datagram) {
+                                                Ok(datagrams) => datagrams,
+                                                Err(e) => {
+                                                    warn!(error = %e, "chat message too long");
+                                                    Vec::new()
+                                                }
+                                            };
+                                        for datagram in datagrams {
+                                            if let Err(e) = socket.send(&datagram).await {
+                                                warn!(error = %e, "failed to send chat message");
+                                            }
+                                        }
+                                        chat_message.clear();
+                                    } else {
+                                        chat_message.push(c);
+                                    }
+                                }
+                                KeyCode::Char(c)
+                                    if !chat_mode
+                                        && last_position_update.elapsed()
+                                            >= position_update_cooldown =>
+                                {
+                                    let move_bytes = {
                                         let mut pos = position.lock().await;
                                         match c {
                                             'w' => pos.y += 1,
@@ -176,37 +437,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                             }
                                         }
 
-                                        serde_json::to_vec(&*pos).unwrap()
+                                        input_seq = input_seq.wrapping_add(1);
+                                        MoveInput {
+                                            input_seq,
+                                            position: pos.clone(),
+                                        }
+                                        .serialize()
                                     };
 
-                                    let position_packet = {
-                                        let mut seq = sequence_num.lock().await;
-                                        let position_packet = GamePacket::new(
-                                            MessageType::PositionUpdate,
-                                            *seq,
-                                            position_bytes,
-                                        );
-                                        *seq += 1;
-                                        position_packet
-                                    };
+                                    let position_packet = GamePacket::new(
+                                        MessageType::PositionUpdate,
+                                        sequence_num.next(),
+                                        move_bytes,
+                                    );
 
+                                    capture.record(Direction::Outbound, &position_packet);
                                     if let Err(e) = socket.send(&position_packet.serialize()).await
                                     {
-                                        eprintln!("Failed to send position update: {}", e);
+                                        warn!(error = %e, "failed to send position update");
                                     }
 
                                     last_position_update = Instant::now();
-                                } else {
-                                    // println!("Position update cooldown active.");
                                 }
+                                _ => {}
                             }
-                            _ => {}
                         }
                     }
                 }
+                disable_raw_mode().expect("Failed to disable raw mode");
             }
-            disable_raw_mode().expect("Failed to disable raw mode");
-        });
+            .instrument(span),
+        );
     }
 
     // Keep the main task alive until shutdown signal is triggered.
@@ -214,6 +475,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
 
+    let _ = disable_raw_mode();
+    capture.flush().await;
+    info!(parent: &session_span, "shutting down");
     println!("Main thread shutting down.");
     Ok(())
 }
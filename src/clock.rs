@@ -0,0 +1,40 @@
+// Where the server gets the time from.
+//
+// Normally that's tokio's clock, which paused-time tests can already move. A
+// manual clock only moves when it's told to, so a simulation decides exactly
+// what time every packet is handled at.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::Instant as TokioInstant;
+
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    Runtime,
+    Manual(Arc<Mutex<Instant>>),
+}
+
+impl Clock {
+    // A manual clock starting now.
+    pub fn manual() -> Self {
+        Clock::Manual(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::Runtime => TokioInstant::now().into_std(),
+            Clock::Manual(now) => *now.lock().unwrap(),
+        }
+    }
+
+    // Moves a manual clock forward. The runtime clock is left alone.
+    pub fn advance(&self, by: Duration) {
+        if let Clock::Manual(now) = self {
+            *now.lock().unwrap() += by;
+        }
+    }
+}
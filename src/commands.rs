@@ -0,0 +1,125 @@
+// Slash commands typed into chat by players, and commands typed into the
+// server's admin console. Parsing lives here; the server decides what to do.
+
+use std::{ops::RangeInclusive, time::Duration};
+
+use crate::access::AccessEntry;
+
+// Tick rates the admin console accepts. Much slower and players time out
+// between ticks; much faster and the tick is all the server does.
+pub const TICK_RATES: RangeInclusive<f64> = 0.01..=1000.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatCommand {
+    Who,
+    Msg { to: String, text: String },
+    Nick(String),
+    Ping,
+}
+
+impl ChatCommand {
+    // Returns None if the text is ordinary chat, otherwise the parsed command
+    // or a usage message to send back to the player.
+    pub fn parse(text: &str) -> Option<Result<ChatCommand, String>> {
+        let rest = text.trim().strip_prefix('/')?;
+        let (name, args) = split_word(rest);
+        let command = match name.to_ascii_lowercase().as_str() {
+            "who" => Ok(ChatCommand::Who),
+            "ping" => Ok(ChatCommand::Ping),
+            "nick" if !args.is_empty() => Ok(ChatCommand::Nick(args.to_string())),
+            "nick" => Err("Usage: /nick <name>".to_string()),
+            "msg" => match split_word(args) {
+                (to, text) if !to.is_empty() && !text.is_empty() => Ok(ChatCommand::Msg {
+                    to: to.to_string(),
+                    text: text.to_string(),
+                }),
+                _ => Err("Usage: /msg <name> <text>".to_string()),
+            },
+            _ => Err(format!(
+                "Unknown command /{}. Try /who, /msg, /nick or /ping",
+                name
+            )),
+        };
+        Some(command)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Kick { name: String, reason: String },
+    Ban(AccessEntry),
+    Unban(AccessEntry),
+    Allow(AccessEntry),
+    Disallow(AccessEntry),
+    SetAllowlist(bool),
+    ShowAccess,
+    Broadcast(String),
+    List,
+    SetTickRate(f64),
+    // Stops the server, telling players when to expect it back if it's
+    // restarting
+    Shutdown { restart_in: Option<Duration> },
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Result<AdminCommand, String> {
+        let (name, args) = split_word(line.trim());
+        match name.to_ascii_lowercase().as_str() {
+            "kick" => match split_word(args) {
+                ("", _) => Err("Usage: kick <name> [reason]".to_string()),
+                (name, reason) => Ok(AdminCommand::Kick {
+                    name: name.to_string(),
+                    reason: reason.to_string(),
+                }),
+            },
+            "ban" | "unban" | "allow" | "disallow" if args.is_empty() => {
+                Err(format!("Usage: {} <name|ip|cidr>", name))
+            }
+            "ban" => Ok(AdminCommand::Ban(AccessEntry::parse(args))),
+            "unban" => Ok(AdminCommand::Unban(AccessEntry::parse(args))),
+            "allow" => Ok(AdminCommand::Allow(AccessEntry::parse(args))),
+            "disallow" => Ok(AdminCommand::Disallow(AccessEntry::parse(args))),
+            "allowlist" => match args {
+                "on" => Ok(AdminCommand::SetAllowlist(true)),
+                "off" => Ok(AdminCommand::SetAllowlist(false)),
+                _ => Err("Usage: allowlist on|off".to_string()),
+            },
+            "bans" => Ok(AdminCommand::ShowAccess),
+            "broadcast" if !args.is_empty() => Ok(AdminCommand::Broadcast(args.to_string())),
+            "broadcast" => Err("Usage: broadcast <text>".to_string()),
+            "list" => Ok(AdminCommand::List),
+            "shutdown" if args.is_empty() => Ok(AdminCommand::Shutdown { restart_in: None }),
+            "shutdown" => match args.parse::<u64>() {
+                Ok(secs) => Ok(AdminCommand::Shutdown {
+                    restart_in: Some(Duration::from_secs(secs)),
+                }),
+                Err(_) => Err("Usage: shutdown [seconds until restart]".to_string()),
+            },
+            "set" => match split_word(args) {
+                ("tickrate", hz) => match hz.parse::<f64>() {
+                    Ok(hz) if TICK_RATES.contains(&hz) => Ok(AdminCommand::SetTickRate(hz)),
+                    _ => Err(format!(
+                        "Usage: set tickrate <hz>, hz from {} to {}",
+                        TICK_RATES.start(),
+                        TICK_RATES.end()
+                    )),
+                },
+                _ => Err("Usage: set tickrate <hz>".to_string()),
+            },
+            _ => Err(format!(
+                "Unknown command '{}'. Try kick, ban, unban, allow, disallow, allowlist, bans, broadcast, list, set tickrate or shutdown",
+                name
+            )),
+        }
+    }
+}
+
+// Splits off the first whitespace-separated word and returns it with the
+// trimmed remainder.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
@@ -0,0 +1,246 @@
+// Simulated bad networks, for testing.
+//
+// A NetworkConditioner decides what happens to each datagram crossing a
+// link: dropped, delivered after some latency and jitter, held back so later
+// datagrams overtake it, or delivered twice. Each direction has its own
+// conditions and its own seeded RNG, so a run with the same seed and the same
+// traffic makes the same decisions.
+//
+// `proxy` puts a conditioner between clients and a server as a UDP proxy.
+// `proxy_with_tap` does the same and shows every datagram to a callback on
+// the way through.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    net::UdpSocket,
+    time::{self, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::fragment::RECV_BUFFER_SIZE;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    // Chance each datagram is dropped, 0-1
+    pub loss: f64,
+    pub latency: Duration,
+    // Delivery times vary uniformly by up to this much either way
+    pub jitter: Duration,
+    // Chance each datagram is delivered twice, 0-1
+    pub duplicate: f64,
+    // Chance each datagram is held back by `reorder_delay`, letting the ones
+    // behind it arrive first, 0-1
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+}
+
+impl LinkConditions {
+    pub fn is_perfect(&self) -> bool {
+        *self == LinkConditions::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    // Client to server
+    Upstream,
+    // Server to client
+    Downstream,
+}
+
+#[derive(Debug, Clone)]
+struct Link {
+    conditions: LinkConditions,
+    rng: StdRng,
+}
+
+impl Link {
+    fn schedule(&mut self, now: Instant) -> Vec<Instant> {
+        let c = self.conditions;
+        if self.rng.random_bool(c.loss.clamp(0.0, 1.0)) {
+            return Vec::new();
+        }
+        let copies = if self.rng.random_bool(c.duplicate.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut delay = c.latency;
+                if !c.jitter.is_zero() {
+                    let jitter = c.jitter.as_secs_f64();
+                    let offset = self.rng.random_range(-jitter..=jitter);
+                    delay = Duration::from_secs_f64((delay.as_secs_f64() + offset).max(0.0));
+                }
+                if self.rng.random_bool(c.reorder.clamp(0.0, 1.0)) {
+                    delay += c.reorder_delay;
+                }
+                now + delay
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConditioner {
+    upstream: Link,
+    downstream: Link,
+}
+
+impl NetworkConditioner {
+    pub fn new(upstream: LinkConditions, downstream: LinkConditions, seed: u64) -> Self {
+        NetworkConditioner {
+            upstream: Link {
+                conditions: upstream,
+                rng: StdRng::seed_from_u64(seed),
+            },
+            downstream: Link {
+                conditions: downstream,
+                rng: StdRng::seed_from_u64(seed.wrapping_add(1)),
+            },
+        }
+    }
+
+    // The same conditions both ways.
+    pub fn symmetric(conditions: LinkConditions, seed: u64) -> Self {
+        Self::new(conditions, conditions, seed)
+    }
+
+    pub fn conditions(&self, direction: Direction) -> LinkConditions {
+        self.link(direction).conditions
+    }
+
+    pub fn set_conditions(&mut self, direction: Direction, conditions: LinkConditions) {
+        self.link_mut(direction).conditions = conditions;
+    }
+
+    // When to deliver a datagram sent now: nothing if it's lost, two times
+    // if it's duplicated.
+    pub fn schedule(&mut self, direction: Direction, now: Instant) -> Vec<Instant> {
+        self.link_mut(direction).schedule(now)
+    }
+
+    fn link(&self, direction: Direction) -> &Link {
+        match direction {
+            Direction::Upstream => &self.upstream,
+            Direction::Downstream => &self.downstream,
+        }
+    }
+
+    fn link_mut(&mut self, direction: Direction) -> &mut Link {
+        match direction {
+            Direction::Upstream => &mut self.upstream,
+            Direction::Downstream => &mut self.downstream,
+        }
+    }
+}
+
+// Sees each datagram a proxy receives, before the conditioner gets to it,
+// along with the client it's from or for.
+pub type Tap = Arc<dyn Fn(Direction, SocketAddr, &[u8]) + Send + Sync>;
+
+// Relays datagrams between clients talking to `listen` and the server at
+// `server`, through the conditioner. Each client gets its own upstream
+// socket, so the server still sees one address per client. The conditioner
+// is shared so a test can change the conditions while the proxy runs.
+pub async fn proxy(
+    listen: UdpSocket,
+    server: SocketAddr,
+    conditioner: Arc<Mutex<NetworkConditioner>>,
+) -> io::Result<()> {
+    proxy_with_tap(listen, server, conditioner, Arc::new(|_, _, _| {})).await
+}
+
+pub async fn proxy_with_tap(
+    listen: UdpSocket,
+    server: SocketAddr,
+    conditioner: Arc<Mutex<NetworkConditioner>>,
+    tap: Tap,
+) -> io::Result<()> {
+    let listen = Arc::new(listen);
+    let mut upstreams: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    loop {
+        let (len, client) = listen.recv_from(&mut buf).await?;
+        let upstream = match upstreams.get(&client) {
+            Some(upstream) => Arc::clone(upstream),
+            None => {
+                let local = if server.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let upstream = Arc::new(UdpSocket::bind(local).await?);
+                upstream.connect(server).await?;
+                debug!(%client, local = %upstream.local_addr()?, "new proxied client");
+                tokio::spawn(relay_downstream(
+                    Arc::clone(&upstream),
+                    Arc::clone(&listen),
+                    client,
+                    Arc::clone(&conditioner),
+                    Arc::clone(&tap),
+                ));
+                upstreams.insert(client, Arc::clone(&upstream));
+                upstream
+            }
+        };
+        tap(Direction::Upstream, client, &buf[..len]);
+        let deliveries = conditioner
+            .lock()
+            .unwrap()
+            .schedule(Direction::Upstream, Instant::now());
+        for at in deliveries {
+            let upstream = Arc::clone(&upstream);
+            let datagram = buf[..len].to_vec();
+            tokio::spawn(async move {
+                time::sleep_until(at).await;
+                if let Err(e) = upstream.send(&datagram).await {
+                    warn!(error = %e, "failed to forward datagram to server");
+                }
+            });
+        }
+    }
+}
+
+async fn relay_downstream(
+    upstream: Arc<UdpSocket>,
+    listen: Arc<UdpSocket>,
+    client: SocketAddr,
+    conditioner: Arc<Mutex<NetworkConditioner>>,
+    tap: Tap,
+) {
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    loop {
+        let len = match upstream.recv(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                warn!(%client, error = %e, "proxy upstream socket failed");
+                return;
+            }
+        };
+        tap(Direction::Downstream, client, &buf[..len]);
+        let deliveries = conditioner
+            .lock()
+            .unwrap()
+            .schedule(Direction::Downstream, Instant::now());
+        for at in deliveries {
+            let listen = Arc::clone(&listen);
+            let datagram = buf[..len].to_vec();
+            tokio::spawn(async move {
+                time::sleep_until(at).await;
+                if let Err(e) = listen.send_to(&datagram, client).await {
+                    warn!(%client, error = %e, "failed to forward datagram to client");
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// A Wireshark dissector for the protocol, written in Lua and generated from
+// the same definitions the codec uses, so it can't quietly fall behind.
+//
+// The header layout and payload formats come from the protocol module. The
+// copy in wireshark/game_udp.lua is checked against `lua()` by
+// tests/dissector.rs. After changing the protocol, regenerate it with
+//
+//     cargo run --bin game_udp-inspect -- --dissector wireshark/game_udp.lua
+//
+// and install it by copying it into Wireshark's Lua plugin directory.
+
+use crate::{
+    batch, fragment,
+    protocol::{self, Payload, HEADER},
+    GamePacket, MessageType,
+};
+
+// The port the dissector claims by default. It can be changed in
+// Wireshark's protocol preferences.
+pub const DEFAULT_PORT: u16 = 4000;
+
+const TEMPLATE: &str = r#"-- Wireshark dissector for game_udp.
+--
+-- Generated by game_udp::dissector from the protocol definitions; don't edit
+-- it by hand. Regenerate it with
+--
+--     cargo run --bin game_udp-inspect -- --dissector wireshark/game_udp.lua
+--
+-- To install, copy this file into Wireshark's Lua plugin directory.
+
+local game_udp = Proto("game_udp", "game_udp Protocol")
+
+local HEADER_LEN = @HEADER_LEN@
+local FRAGMENT_HEADER_LEN = @FRAGMENT_HEADER_LEN@
+local FRAME_HEADER_LEN = @FRAME_HEADER_LEN@
+
+local message_types = {
+@MESSAGE_TYPES@
+}
+
+local payload_formats = {
+@PAYLOAD_FORMATS@
+}
+
+@HEADER_FIELDS@
+local f_payload = ProtoField.bytes("game_udp.payload", "Payload")
+local f_text = ProtoField.string("game_udp.text", "Text")
+local f_fragment_index = ProtoField.uint16("game_udp.fragment.index", "Fragment index", base.DEC)
+local f_fragment_count = ProtoField.uint16("game_udp.fragment.count", "Fragment count", base.DEC)
+local f_frame_len = ProtoField.uint16("game_udp.batch.length", "Frame length", base.DEC)
+
+game_udp.fields = {
+@FIELD_LIST@
+    f_payload,
+    f_text,
+    f_fragment_index,
+    f_fragment_count,
+    f_frame_len,
+}
+
+game_udp.prefs.port = Pref.uint("UDP port", @DEFAULT_PORT@, "Port the game server listens on")
+
+local json = Dissector.get("json")
+
+-- Adds one packet in `tvb` to `tree`. Returns a short summary for the info
+-- column.
+local function dissect_packet(tvb, pinfo, tree)
+    if tvb:len() < HEADER_LEN then
+        local item = tree:add(game_udp, tvb(), "game_udp, truncated")
+        item:add_expert_info(PI_MALFORMED, PI_ERROR, "Shorter than a packet header")
+        return "Malformed"
+    end
+    local msg_type = tvb(@TYPE_OFFSET@, @TYPE_LEN@):uint()
+    local seq = tvb(@SEQ_OFFSET@, @SEQ_LEN@):uint()
+    local name = message_types[msg_type] or string.format("Unknown (0x%02x)", msg_type)
+    local subtree = tree:add(game_udp, tvb(), "game_udp " .. name .. ", seq " .. seq)
+@HEADER_ADDS@
+
+    local payload_len = tvb:len() - HEADER_LEN
+    if payload_len == 0 then
+        return name
+    end
+    local payload = tvb(HEADER_LEN, payload_len)
+    local format = payload_formats[msg_type]
+    if format == "json" then
+        subtree:add(f_payload, payload)
+        if json then
+            json:call(payload:tvb(), pinfo, subtree)
+        end
+    elseif format == "text" then
+        subtree:add(f_text, payload)
+    elseif format == "fragment" and payload_len > FRAGMENT_HEADER_LEN then
+        subtree:add(f_fragment_index, payload(0, 2))
+        subtree:add(f_fragment_count, payload(2, 2))
+        subtree:add(f_payload, payload(FRAGMENT_HEADER_LEN))
+        name = string.format("%s %d/%d", name, payload(0, 2):uint() + 1, payload(2, 2):uint())
+    elseif format == "batch" then
+        local names = {}
+        local offset = HEADER_LEN
+        while offset + FRAME_HEADER_LEN <= tvb:len() do
+            local len = tvb(offset, FRAME_HEADER_LEN):uint()
+            subtree:add(f_frame_len, tvb(offset, FRAME_HEADER_LEN))
+            offset = offset + FRAME_HEADER_LEN
+            if len == 0 or offset + len > tvb:len() then
+                subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Truncated batch frame")
+                break
+            end
+            names[#names + 1] = dissect_packet(tvb(offset, len):tvb(), pinfo, subtree)
+            offset = offset + len
+        end
+        name = name .. " [" .. table.concat(names, ", ") .. "]"
+    else
+        subtree:add(f_payload, payload)
+    end
+    return name
+end
+
+function game_udp.dissector(tvb, pinfo, tree)
+    local summary = dissect_packet(tvb, pinfo, tree)
+    -- Nested dissectors like JSON rename the protocol column, so set it last
+    pinfo.cols.protocol = game_udp.name
+    pinfo.cols.info = summary
+    return tvb:len()
+end
+
+local udp_port = DissectorTable.get("udp.port")
+local registered_port = game_udp.prefs.port
+udp_port:add(registered_port, game_udp)
+
+function game_udp.prefs_changed()
+    if registered_port ~= game_udp.prefs.port then
+        udp_port:remove(registered_port, game_udp)
+        registered_port = game_udp.prefs.port
+        udp_port:add(registered_port, game_udp)
+    end
+end
+"#;
+
+// The dissector's Lua source.
+pub fn lua() -> String {
+    let message_types: Vec<String> = MessageType::ALL
+        .iter()
+        .map(|t| format!("    [0x{:02x}] = \"{:?}\",", *t as u8, t))
+        .collect();
+    let payload_formats: Vec<String> = MessageType::ALL
+        .iter()
+        .map(|&t| {
+            // Both sides lay out a given message type the same way
+            let payload = protocol::MESSAGES
+                .iter()
+                .find(|m| m.msg_type == t)
+                .map(|m| m.payload);
+            let format = match payload {
+                Some(Payload::Json(_)) => "json",
+                Some(Payload::Text) => "text",
+                Some(Payload::Fragment) => "fragment",
+                Some(Payload::Batch) => "batch",
+                None => "bytes",
+            };
+            format!("    [0x{:02x}] = \"{}\",", t as u8, format)
+        })
+        .collect();
+    let header_fields: Vec<String> = HEADER
+        .iter()
+        .map(|field| {
+            // Only the type gets its values named
+            let (base, names) = if field.name == "type" {
+                ("base.HEX", ", message_types")
+            } else {
+                ("base.DEC", "")
+            };
+            format!(
+                "local f_{name} = ProtoField.uint{bits}(\"game_udp.{name}\", \"{label}\", {base}{names})",
+                name = field.name,
+                bits = field.len * 8,
+                label = field.label,
+            )
+        })
+        .collect();
+    let field_list: Vec<String> = HEADER
+        .iter()
+        .map(|field| format!("    f_{},", field.name))
+        .collect();
+    let header_adds: Vec<String> = HEADER
+        .iter()
+        .map(|field| {
+            format!(
+                "    subtree:add(f_{}, tvb({}, {}))",
+                field.name, field.offset, field.len
+            )
+        })
+        .collect();
+    let field = |name| HEADER.iter().find(|f| f.name == name).unwrap();
+
+    TEMPLATE
+        .replace("@HEADER_LEN@", &GamePacket::HEADER_LEN.to_string())
+        .replace(
+            "@FRAGMENT_HEADER_LEN@",
+            &fragment::FRAGMENT_HEADER_LEN.to_string(),
+        )
+        .replace("@FRAME_HEADER_LEN@", &batch::FRAME_HEADER_LEN.to_string())
+        .replace("@MESSAGE_TYPES@", &message_types.join("\n"))
+        .replace("@PAYLOAD_FORMATS@", &payload_formats.join("\n"))
+        .replace("@HEADER_FIELDS@", &header_fields.join("\n"))
+        .replace("@FIELD_LIST@", &field_list.join("\n"))
+        .replace("@HEADER_ADDS@", &header_adds.join("\n"))
+        .replace("@TYPE_OFFSET@", &field("type").offset.to_string())
+        .replace("@TYPE_LEN@", &field("type").len.to_string())
+        .replace("@SEQ_OFFSET@", &field("seq").offset.to_string())
+        .replace("@SEQ_LEN@", &field("seq").len.to_string())
+        .replace("@DEFAULT_PORT@", &DEFAULT_PORT.to_string())
+}
@@ -0,0 +1,330 @@
+// Splitting packets that don't fit in one datagram, and putting them back
+// together on the other side.
+//
+// A packet larger than the maximum datagram size is serialized as usual and
+// the bytes are cut into chunks. Each chunk goes out as a Fragment packet
+// whose header carries the original packet's sequence number, followed by
+//
+//     index: u16 (big-endian), count: u16 (big-endian), chunk bytes
+//
+// The receiver collects chunks per (peer, sequence number) and decodes the
+// original packet once all of them have arrived. Incomplete packets are
+// dropped after a timeout, and the memory held by incomplete packets is
+// capped, per peer and in total, so a peer can't make us buffer without
+// limit or push out everyone else's packets.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    hash::Hash,
+    mem,
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{GamePacket, MessageType};
+
+pub const MAX_DATAGRAM_VAR: &str = "GAME_UDP_MAX_DATAGRAM";
+// Leaves room for IP and UDP headers, plus tunnels, under a 1500 byte MTU
+pub const DEFAULT_MAX_DATAGRAM: usize = 1200;
+// Largest datagram we'll ever need to receive
+pub const RECV_BUFFER_SIZE: usize = 65536;
+
+pub const FRAGMENT_HEADER_LEN: usize = 4;
+// Most fragments a packet may be split into. Receivers drop anything more.
+pub const MAX_FRAGMENTS: u16 = 64;
+// Below this there'd be more header than data
+const MIN_MAX_DATAGRAM: usize = 64;
+
+// Reads the maximum datagram size from GAME_UDP_MAX_DATAGRAM, falling back
+// to DEFAULT_MAX_DATAGRAM.
+pub fn max_datagram_from_env() -> usize {
+    std::env::var(MAX_DATAGRAM_VAR)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .map(|size| size.clamp(MIN_MAX_DATAGRAM, RECV_BUFFER_SIZE))
+        .unwrap_or(DEFAULT_MAX_DATAGRAM)
+}
+
+// Serializes a packet into one or more datagrams of at most `max_datagram`
+// bytes each. Fails if that takes more than MAX_FRAGMENTS fragments, since
+// the other side would never put them back together.
+pub fn split(packet: &GamePacket, max_datagram: usize) -> Result<Vec<Bytes>, FragmentError> {
+    let data = packet.serialize();
+    if data.len() <= max_datagram {
+        return Ok(vec![data]);
+    }
+    let chunk_len = max_datagram
+        .max(MIN_MAX_DATAGRAM)
+        .saturating_sub(GamePacket::HEADER_LEN + FRAGMENT_HEADER_LEN);
+    let count = data.len().div_ceil(chunk_len);
+    if count > MAX_FRAGMENTS as usize {
+        return Err(FragmentError::TooManyFragments(count));
+    }
+    let fragments = data
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let header = GamePacket::new(MessageType::Fragment, packet.seq_num, Bytes::new());
+            let mut buf =
+                BytesMut::with_capacity(GamePacket::HEADER_LEN + FRAGMENT_HEADER_LEN + chunk.len());
+            header.encode_into(&mut buf);
+            buf.put_u16(index as u16);
+            buf.put_u16(count as u16);
+            buf.put_slice(chunk);
+            buf.freeze()
+        })
+        .collect();
+    Ok(fragments)
+}
+
+// The index and count of a Fragment packet, or None if it's malformed.
+pub fn header(fragment: &GamePacket) -> Option<(u16, u16)> {
+    let payload = &fragment.payload;
+    if payload.len() <= FRAGMENT_HEADER_LEN {
+        return None;
+    }
+    let index = u16::from_be_bytes([payload[0], payload[1]]);
+    let count = u16::from_be_bytes([payload[2], payload[3]]);
+    if count == 0 || index >= count {
+        return None;
+    }
+    Some((index, count))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FragmentError {
+    Malformed,
+    TooManyFragments(usize),
+    // A fragment disagreed with earlier ones about the fragment count
+    CountMismatch,
+    // The reassembled bytes weren't a valid, unfragmented packet
+    InvalidPacket,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::Malformed => write!(f, "malformed fragment header"),
+            FragmentError::TooManyFragments(n) => {
+                write!(f, "packet split into too many fragments ({})", n)
+            }
+            FragmentError::CountMismatch => write!(f, "fragment count changed between fragments"),
+            FragmentError::InvalidPacket => write!(f, "reassembled packet could not be decoded"),
+        }
+    }
+}
+
+impl std::error::Error for FragmentError {}
+
+#[derive(Debug)]
+struct Partial {
+    chunks: Vec<Option<Bytes>>,
+    received: usize,
+    // What this partial costs, the slots included, not just the chunks
+    bytes: usize,
+    started: Instant,
+}
+
+// What one peer has waiting to be put back together.
+#[derive(Debug, Default)]
+struct PeerUsage {
+    // Sequence numbers of its partials, oldest first
+    order: VecDeque<u32>,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+pub struct Reassembler<K> {
+    pending: HashMap<(K, u32), Partial>,
+    peers: HashMap<K, PeerUsage>,
+    pending_bytes: usize,
+    // When incomplete packets are next checked for the timeout
+    next_sweep: Option<Instant>,
+    pub timeout: Duration,
+    pub max_fragments: u16,
+    pub max_pending_bytes: usize,
+    // Per peer limits, so one peer can't take the whole budget
+    pub max_partials_per_peer: usize,
+    pub max_peer_bytes: usize,
+}
+
+impl<K: Hash + Eq + Clone> Default for Reassembler<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> Reassembler<K> {
+    pub fn new() -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+            peers: HashMap::new(),
+            pending_bytes: 0,
+            next_sweep: None,
+            timeout: Duration::from_secs(2),
+            max_fragments: MAX_FRAGMENTS,
+            max_pending_bytes: 1 << 20,
+            max_partials_per_peer: 8,
+            max_peer_bytes: 1 << 17,
+        }
+    }
+
+    // Memory held by incomplete packets, as counted against the limits.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    pub fn pending_packets(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Adds a Fragment packet from `peer`. Returns the original packet once
+    // every fragment of it has arrived.
+    pub fn insert(
+        &mut self,
+        peer: K,
+        fragment: &GamePacket,
+        now: Instant,
+    ) -> Result<Option<GamePacket>, FragmentError> {
+        if self.next_sweep.is_none_or(|sweep| now >= sweep) {
+            self.expire(now);
+            self.next_sweep = Some(now + self.timeout / 2);
+        }
+        let (index, count) = header(fragment).ok_or(FragmentError::Malformed)?;
+        if count > self.max_fragments {
+            return Err(FragmentError::TooManyFragments(count as usize));
+        }
+        let chunk = &fragment.payload[FRAGMENT_HEADER_LEN..];
+
+        let key = (peer, fragment.seq_num);
+        // Between sweeps a partial can outlive the timeout. Start it over
+        // rather than finish it with chunks that may belong to another packet.
+        let stale = self
+            .pending
+            .get(&key)
+            .is_some_and(|partial| now.saturating_duration_since(partial.started) >= self.timeout);
+        if stale {
+            self.remove(&key);
+        }
+        if !self.pending.contains_key(&key) {
+            self.start(&key, count, now);
+        }
+        let partial = self.pending.get_mut(&key).unwrap();
+        if partial.chunks.len() != count as usize {
+            self.remove(&key);
+            return Err(FragmentError::CountMismatch);
+        }
+        let slot = &mut partial.chunks[index as usize];
+        let new = slot.is_none();
+        if new {
+            // Copied so we don't pin the whole receive buffer while waiting
+            *slot = Some(Bytes::copy_from_slice(chunk));
+            partial.received += 1;
+            partial.bytes += chunk.len();
+        }
+        let complete = partial.received == partial.chunks.len();
+        if new {
+            self.charge(&key.0, chunk.len());
+        }
+
+        if complete {
+            let partial = self.remove(&key).unwrap();
+            let mut data = BytesMut::with_capacity(partial.bytes);
+            for chunk in partial.chunks.into_iter().flatten() {
+                data.put_slice(&chunk);
+            }
+            return match GamePacket::decode(data.freeze()) {
+                Some(packet) if !matches!(packet.msg_type, MessageType::Fragment) => {
+                    Ok(Some(packet))
+                }
+                _ => Err(FragmentError::InvalidPacket),
+            };
+        }
+
+        self.enforce_limits(&key.0);
+        Ok(None)
+    }
+
+    // Drops incomplete packets older than the timeout. Insert does this
+    // itself every half timeout, so only call it to free memory sooner.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, partial)| now.saturating_duration_since(partial.started) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    // Makes room for a new partial from this peer, dropping its oldest if
+    // it already has as many as it may.
+    fn start(&mut self, key: &(K, u32), count: u16, now: Instant) {
+        let (peer, seq) = key;
+        let usage = self.peers.entry(peer.clone()).or_default();
+        let oldest = (usage.order.len() >= self.max_partials_per_peer)
+            .then(|| usage.order.front().copied())
+            .flatten();
+        if let Some(oldest) = oldest {
+            self.remove(&(peer.clone(), oldest));
+        }
+        let bytes = mem::size_of::<Partial>() + count as usize * mem::size_of::<Option<Bytes>>();
+        self.pending.insert(
+            key.clone(),
+            Partial {
+                chunks: vec![None; count as usize],
+                received: 0,
+                bytes,
+                started: now,
+            },
+        );
+        self.peers
+            .entry(peer.clone())
+            .or_default()
+            .order
+            .push_back(*seq);
+        self.charge(peer, bytes);
+    }
+
+    fn charge(&mut self, peer: &K, bytes: usize) {
+        self.pending_bytes += bytes;
+        if let Some(usage) = self.peers.get_mut(peer) {
+            usage.bytes += bytes;
+        }
+    }
+
+    fn remove(&mut self, key: &(K, u32)) -> Option<Partial> {
+        let partial = self.pending.remove(key)?;
+        self.pending_bytes -= partial.bytes;
+        let (peer, seq) = key;
+        if let Some(usage) = self.peers.get_mut(peer) {
+            usage.bytes -= partial.bytes;
+            usage.order.retain(|s| s != seq);
+            if usage.order.is_empty() {
+                self.peers.remove(peer);
+            }
+        }
+        Some(partial)
+    }
+
+    // Drops this peer's oldest incomplete packets until both it and the
+    // total are within their limits. Only the peer that went over pays for
+    // it: everyone was within the total before its fragment arrived.
+    fn enforce_limits(&mut self, peer: &K) {
+        loop {
+            let Some(usage) = self.peers.get(peer) else {
+                return;
+            };
+            if usage.bytes <= self.max_peer_bytes && self.pending_bytes <= self.max_pending_bytes {
+                return;
+            }
+            let oldest = (peer.clone(), usage.order[0]);
+            self.remove(&oldest);
+        }
+    }
+}
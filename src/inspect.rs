@@ -0,0 +1,319 @@
+// Prints the game's traffic one packet per line, for debugging the protocol.
+//
+// Usage: game_udp-inspect [--listen ADDR] [--server ADDR] [filters]
+//        game_udp-inspect --file CAPTURE [filters]
+//        game_udp-inspect --dissector FILE
+//        game_udp-inspect --vectors FILE
+//
+// Without --file it's a transparent UDP proxy: point clients at the listen
+// address instead of the server. With --file it reads a capture recorded
+// with GAME_UDP_CAPTURE instead. --dissector writes a Wireshark dissector
+// for the protocol to FILE, for looking at the traffic there instead, and
+// --vectors writes the protocol's golden test vectors.
+//
+// Filters, all optional:
+//   --type T[,T...]       only these message types, e.g. Heartbeat,ChatMessage
+//   --peer TEXT           only peers whose address contains TEXT
+//   --direction up|down   only client to server (up) or server to client (down)
+//
+// Each line shows the time, direction, peer, message type, sequence number
+// and the payload decoded with the library's payload types.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use game_udp::{
+    capture::{self, Reader, Role},
+    conditioner::{self, Direction, LinkConditions, NetworkConditioner},
+    dissector,
+    fragment::{self, Reassembler},
+    protocol,
+    quality::Heartbeat,
+    Chat, ConnectionRejected, ConnectionRequest, GamePacket, MessageType, MoveAck, MoveInput,
+    PlayerJoin, PlayerUpdate, ServerShutdown, ServerStateSend,
+};
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tracing::info;
+
+const LOG_FILE: &str = "inspect.log";
+#[derive(Debug, Clone, Default)]
+struct Filter {
+    types: Vec<MessageType>,
+    peer: Option<String>,
+    direction: Option<Direction>,
+}
+
+impl Filter {
+    // Datagrams that don't decode have no type, and only show up when no
+    // types were asked for.
+    fn matches(&self, direction: Direction, peer: &str, msg_type: Option<MessageType>) -> bool {
+        let type_matches = match msg_type {
+            Some(msg_type) => self.types.is_empty() || self.types.contains(&msg_type),
+            None => self.types.is_empty(),
+        };
+        type_matches
+            && self.peer.as_ref().is_none_or(|p| peer.contains(p.as_str()))
+            && self.direction.is_none_or(|d| d == direction)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    listen: SocketAddr,
+    server: SocketAddr,
+    file: Option<PathBuf>,
+    dissector: Option<PathBuf>,
+    vectors: Option<PathBuf>,
+    filter: Filter,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            listen: "127.0.0.1:4001".parse().unwrap(),
+            server: "127.0.0.1:4000".parse().unwrap(),
+            file: None,
+            dissector: None,
+            vectors: None,
+            filter: Filter::default(),
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--listen" => options.listen = parse_value(&flag, &value)?,
+                "--server" => options.server = parse_value(&flag, &value)?,
+                "--file" => options.file = Some(PathBuf::from(value)),
+                "--dissector" => options.dissector = Some(PathBuf::from(value)),
+                "--vectors" => options.vectors = Some(PathBuf::from(value)),
+                "--type" => {
+                    for name in value.split(',') {
+                        let msg_type = MessageType::ALL
+                            .into_iter()
+                            .find(|t| format!("{:?}", t).eq_ignore_ascii_case(name.trim()))
+                            .ok_or_else(|| format!("Unknown message type {}", name))?;
+                        options.filter.types.push(msg_type);
+                    }
+                }
+                "--peer" => options.filter.peer = Some(value),
+                "--direction" => {
+                    options.filter.direction = Some(match value.as_str() {
+                        "up" => Direction::Upstream,
+                        "down" => Direction::Downstream,
+                        _ => return Err(format!("Invalid value for {}: {}", flag, value)),
+                    })
+                }
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// Decodes datagrams seen by the proxy, putting fragments back together.
+struct Inspector {
+    filter: Filter,
+    started: Instant,
+    reassembler: Reassembler<(SocketAddr, Direction)>,
+}
+
+impl Inspector {
+    fn datagram(&mut self, direction: Direction, peer: SocketAddr, data: &[u8]) {
+        let now = Instant::now();
+        let at = now - self.started;
+        let peer_str = peer.to_string();
+        for message in GamePacket::messages(Bytes::copy_from_slice(data)) {
+            let Some(packet) = message else {
+                if self.filter.matches(direction, &peer_str, None) {
+                    println!(
+                        "{} undecodable, {} bytes: {}",
+                        prefix(at, direction, &peer_str),
+                        data.len(),
+                        hex(data)
+                    );
+                }
+                continue;
+            };
+            print_packet(&self.filter, at, direction, &peer_str, &packet, "");
+            if packet.msg_type == MessageType::Fragment {
+                if let Ok(Some(whole)) = self.reassembler.insert((peer, direction), &packet, now) {
+                    print_packet(
+                        &self.filter,
+                        at,
+                        direction,
+                        &peer_str,
+                        &whole,
+                        " (reassembled)",
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn print_packet(
+    filter: &Filter,
+    at: Duration,
+    direction: Direction,
+    peer: &str,
+    packet: &GamePacket,
+    note: &str,
+) {
+    if !filter.matches(direction, peer, Some(packet.msg_type)) {
+        return;
+    }
+    println!(
+        "{} {:<22} seq={:<6} {}{}",
+        prefix(at, direction, peer),
+        format!("{:?}", packet.msg_type),
+        packet.seq_num,
+        describe(direction, packet),
+        note
+    );
+}
+
+fn prefix(at: Duration, direction: Direction, peer: &str) -> String {
+    let arrow = match direction {
+        Direction::Upstream => "up  ",
+        Direction::Downstream => "down",
+    };
+    format!("{:>10.3}s {} {:<21}", at.as_secs_f64(), arrow, peer)
+}
+
+// The payload decoded as whatever this message type carries in this
+// direction, printed as JSON.
+fn describe(direction: Direction, packet: &GamePacket) -> String {
+    let payload = &packet.payload;
+    let upstream = direction == Direction::Upstream;
+    let decoded = match packet.msg_type {
+        MessageType::PositionUpdate if upstream => json(MoveInput::deserialize(payload)),
+        MessageType::PositionUpdate => json(PlayerUpdate::deserialize(payload)),
+        MessageType::ChatMessage => json(serde_json::from_slice::<Chat>(payload).ok()),
+        MessageType::Heartbeat => json(Heartbeat::deserialize(payload)),
+        MessageType::ConnectionInit if upstream => json(ConnectionRequest::deserialize(payload)),
+        MessageType::ConnectionInit => json(ServerStateSend::deserialize(payload).ok()),
+        MessageType::PlayerJoin => json(PlayerJoin::deserialize(payload)),
+        MessageType::ConfirmPlayerMovement => json(MoveAck::deserialize(payload)),
+        MessageType::PlayerLeft => std::str::from_utf8(payload)
+            .ok()
+            .map(|p| format!("{:?}", p)),
+        MessageType::ConnectionRejected => {
+            json(serde_json::from_slice::<ConnectionRejected>(payload).ok())
+        }
+        MessageType::ServerShutdown => json(ServerShutdown::deserialize(payload)),
+        MessageType::Fragment => fragment::header(packet).map(|(index, count)| {
+            format!(
+                "fragment {}/{}, {} bytes",
+                index + 1,
+                count,
+                payload.len() - fragment::FRAGMENT_HEADER_LEN
+            )
+        }),
+        // Unpacked by GamePacket::messages, so only an empty batch gets here
+        MessageType::Batch => Some("empty batch".to_string()),
+    };
+    decoded.unwrap_or_else(|| format!("malformed, {} bytes: {}", payload.len(), hex(payload)))
+}
+
+fn json<T: Serialize>(decoded: Option<T>) -> Option<String> {
+    decoded.map(|value| serde_json::to_string(&value).unwrap())
+}
+
+// The first few bytes in hex, which is usually enough to see what's wrong.
+fn hex(data: &[u8]) -> String {
+    let mut text: String = data.iter().take(32).map(|b| format!("{:02x}", b)).collect();
+    if data.len() > 32 {
+        text.push_str("...");
+    }
+    text
+}
+
+// Prints the packets in a capture file.
+fn read_capture(path: &Path, filter: &Filter) -> std::io::Result<()> {
+    let reader = Reader::open(path)?;
+    let role = reader.role();
+    for record in reader {
+        let record = record?;
+        // Captures record direction from the recorder's side
+        let direction = match (role, record.direction) {
+            (Role::Server, capture::Direction::Inbound)
+            | (Role::Client, capture::Direction::Outbound) => Direction::Upstream,
+            _ => Direction::Downstream,
+        };
+        print_packet(
+            filter,
+            record.at,
+            direction,
+            &record.addr,
+            &record.packet,
+            "",
+        );
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            eprintln!(
+                "Usage: game_udp-inspect [--listen ADDR] [--server ADDR | --file CAPTURE | --dissector FILE | --vectors FILE] [--type T[,T...]] [--peer TEXT] [--direction up|down]"
+            );
+            std::process::exit(2);
+        }
+    };
+    game_udp::logging::init(LOG_FILE)?;
+    info!(?options, "starting inspector");
+
+    if let Some(path) = &options.dissector {
+        std::fs::write(path, dissector::lua())?;
+        println!("Wrote {}", path.display());
+        return Ok(());
+    }
+    if let Some(path) = &options.vectors {
+        std::fs::write(path, protocol::vectors_json())?;
+        println!("Wrote {}", path.display());
+        return Ok(());
+    }
+    if let Some(path) = &options.file {
+        read_capture(path, &options.filter)?;
+        return Ok(());
+    }
+
+    eprintln!("Proxying {} -> {}", options.listen, options.server);
+    let inspector = Mutex::new(Inspector {
+        filter: options.filter.clone(),
+        started: Instant::now(),
+        reassembler: Reassembler::new(),
+    });
+    let tap: conditioner::Tap = Arc::new(move |direction, peer, data| {
+        inspector.lock().unwrap().datagram(direction, peer, data)
+    });
+    let listen = UdpSocket::bind(options.listen).await?;
+    // A perfect network, so the proxy only watches
+    let conditioner = NetworkConditioner::symmetric(LinkConditions::default(), 0);
+    conditioner::proxy_with_tap(
+        listen,
+        options.server,
+        Arc::new(Mutex::new(conditioner)),
+        tap,
+    )
+    .await?;
+    Ok(())
+}
@@ -1,19 +1,47 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{stdout, Write},
+    sync::Arc,
+    time::Duration,
 };
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use crossterm::{
     cursor, execute,
     style::{self, Print},
     terminal::{self, Clear, ClearType},
 };
+use quality::ConnectionQuality;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sequence::{ReceiveWindow, SequenceCounter};
 use serde::{Deserialize, Serialize};
 use std::time::Instant;
+use storage::{PlayerStats, Storage};
+
+pub mod access;
+pub mod batch;
+pub mod capture;
+pub mod clock;
+pub mod commands;
+pub mod conditioner;
+pub mod dissector;
+pub mod fragment;
+pub mod logging;
+pub mod metrics;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod mmsg;
+pub mod protocol;
+pub mod quality;
+pub mod reconnect;
+pub mod sequence;
+pub mod server;
+pub mod sim;
+pub mod socket;
+pub mod storage;
+pub mod workers;
 
 // Define an enum for message types.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     PositionUpdate = 0x01,
     ChatMessage = 0x02,
@@ -22,9 +50,32 @@ pub enum MessageType {
     PlayerJoin = 0x05,
     ConfirmPlayerMovement = 0x06,
     PlayerLeft = 0x07,
+    ConnectionRejected = 0x08,
+    // One piece of a packet too large for a single datagram, see fragment.rs
+    Fragment = 0x09,
+    // Several packets sharing one datagram, see batch.rs
+    Batch = 0x0A,
+    // The server is going away, and maybe coming back
+    ServerShutdown = 0x0B,
 }
 
 impl MessageType {
+    // Every message type, in wire order. Tools that describe the protocol
+    // are generated from this, so keep it in step with from_byte.
+    pub const ALL: [MessageType; 11] = [
+        MessageType::PositionUpdate,
+        MessageType::ChatMessage,
+        MessageType::Heartbeat,
+        MessageType::ConnectionInit,
+        MessageType::PlayerJoin,
+        MessageType::ConfirmPlayerMovement,
+        MessageType::PlayerLeft,
+        MessageType::ConnectionRejected,
+        MessageType::Fragment,
+        MessageType::Batch,
+        MessageType::ServerShutdown,
+    ];
+
     pub fn from_byte(b: u8) -> Option<MessageType> {
         match b {
             0x01 => Some(MessageType::PositionUpdate),
@@ -34,13 +85,17 @@ impl MessageType {
             0x05 => Some(MessageType::PlayerJoin),
             0x06 => Some(MessageType::ConfirmPlayerMovement),
             0x07 => Some(MessageType::PlayerLeft),
+            0x08 => Some(MessageType::ConnectionRejected),
+            0x09 => Some(MessageType::Fragment),
+            0x0A => Some(MessageType::Batch),
+            0x0B => Some(MessageType::ServerShutdown),
             _ => None,
         }
     }
 }
 
 // Example payloads:
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Position {
     pub x: i32,
     pub y: i32,
@@ -58,49 +113,219 @@ impl Position {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Chat {
     pub text: String,
 }
 
+// Payload of a ConnectionRejected reply.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionRejected {
+    pub reason: String,
+}
+
+// Payload of a ServerShutdown notice, sent to everyone just before the
+// server stops.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerShutdown {
+    pub reason: String,
+    // Roughly how long until it's back, if it's restarting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_in_secs: Option<u64>,
+}
+
+impl ServerShutdown {
+    pub fn restart_in(&self) -> Option<Duration> {
+        self.restart_in_secs.map(Duration::from_secs)
+    }
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+pub const MAX_NAME_LEN: usize = 16;
+
+// Colors a player can pick for their marker on the board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PlayerColor {
+    Red,
+    #[default]
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl PlayerColor {
+    pub fn from_name(name: &str) -> Option<PlayerColor> {
+        match name.to_ascii_lowercase().as_str() {
+            "red" => Some(PlayerColor::Red),
+            "green" => Some(PlayerColor::Green),
+            "yellow" => Some(PlayerColor::Yellow),
+            "blue" => Some(PlayerColor::Blue),
+            "magenta" => Some(PlayerColor::Magenta),
+            "cyan" => Some(PlayerColor::Cyan),
+            "white" => Some(PlayerColor::White),
+            _ => None,
+        }
+    }
+
+    pub fn to_terminal_color(self) -> style::Color {
+        match self {
+            PlayerColor::Red => style::Color::Red,
+            PlayerColor::Green => style::Color::Green,
+            PlayerColor::Yellow => style::Color::Yellow,
+            PlayerColor::Blue => style::Color::Blue,
+            PlayerColor::Magenta => style::Color::Magenta,
+            PlayerColor::Cyan => style::Color::Cyan,
+            PlayerColor::White => style::Color::White,
+        }
+    }
+}
+
+// Profile a client sends in its ConnectionInit payload. The server validates
+// and deduplicates the name before storing it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerProfile {
+    pub name: String,
+    #[serde(default)]
+    pub color: PlayerColor,
+}
+
+impl PlayerProfile {
+    pub fn new(name: &str, color: PlayerColor) -> Self {
+        PlayerProfile {
+            name: name.to_string(),
+            color,
+        }
+    }
+
+    // Profile used when a client does not send one.
+    pub fn default_for(player_number: u32) -> Self {
+        PlayerProfile::new(&format!("Player{}", player_number), PlayerColor::default())
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    // Up to two uppercase initials, used when the full name doesn't fit.
+    pub fn initials(&self) -> String {
+        let initials: String = self
+            .name
+            .split(|c: char| c.is_whitespace() || c == '_' || c == '-')
+            .filter_map(|word| word.chars().next())
+            .take(2)
+            .collect();
+        initials.to_uppercase()
+    }
+}
+
+// The whole ConnectionInit payload a client sends: its profile, plus the
+// token of the session it had if it's reconnecting. Without a token it
+// encodes exactly like the profile on its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionRequest {
+    #[serde(flatten)]
+    pub profile: PlayerProfile,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+}
+
+impl ConnectionRequest {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+// Trims a requested display name and strips anything we don't want on the
+// board. Returns None if nothing usable is left.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let cleaned: String = name
+        .trim()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '_' || *c == '-')
+        .take(MAX_NAME_LEN)
+        .collect();
+    let cleaned = cleaned.trim().to_string();
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
 // Unified packet structure
 // We'll store the payload as raw bytes. It's up to the caller
 // to serialize/deserialize according to the message type.
-#[derive(Debug)]
+// Payloads are reference counted, so a packet can be cloned for every
+// recipient of a broadcast, and a decoded payload is a view into the
+// datagram it arrived in rather than a copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GamePacket {
     pub msg_type: MessageType,
     pub version: u8,
     pub seq_num: u32,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 impl GamePacket {
-    pub fn new(msg_type: MessageType, seq_num: u32, payload: Vec<u8>) -> Self {
+    // Type, version and sequence number
+    pub const HEADER_LEN: usize = 6;
+
+    pub fn new(msg_type: MessageType, seq_num: u32, payload: impl Into<Bytes>) -> Self {
         GamePacket {
             msg_type,
-            version: 1,
+            version: protocol::VERSION,
             seq_num,
-            payload,
+            payload: payload.into(),
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(1 + 1 + 4 + self.payload.len());
+    pub fn encoded_len(&self) -> usize {
+        Self::HEADER_LEN + self.payload.len()
+    }
+
+    // Appends the encoded packet to `buf`.
+    pub fn encode_into(&self, buf: &mut BytesMut) {
+        buf.reserve(self.encoded_len());
         buf.put_u8(self.msg_type as u8);
         buf.put_u8(self.version);
         buf.put_u32(self.seq_num);
         buf.put_slice(&self.payload);
-        buf.to_vec()
+    }
+
+    pub fn serialize(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_into(&mut buf);
+        buf.freeze()
     }
 
     pub fn deserialize(data: &[u8]) -> Option<GamePacket> {
-        if data.len() < 6 {
+        Self::decode(Bytes::copy_from_slice(data))
+    }
+
+    // Like deserialize, but takes ownership of the datagram so the payload
+    // can point into it instead of being copied.
+    pub fn decode(data: Bytes) -> Option<GamePacket> {
+        if data.len() < Self::HEADER_LEN {
             return None; // Not enough for header
         }
         let msg_type = MessageType::from_byte(data[0])?;
         let version = data[1];
         let seq_num = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
-        let payload = data[6..].to_vec();
+        let payload = data.slice(Self::HEADER_LEN..);
         Some(GamePacket {
             msg_type,
             seq_num,
@@ -108,12 +333,61 @@ impl GamePacket {
             version,
         })
     }
+
+    // Iterates over the packets in a datagram: every packet in it if it's a
+    // batch, or just the one otherwise. Yields None for a packet that
+    // can't be decoded.
+    pub fn messages(data: Bytes) -> batch::Messages {
+        batch::Messages::new(data)
+    }
 }
 #[derive(Debug, Clone)]
 pub struct PlayerState {
     pub position: Position,
     pub last_heartbeat: Instant,
     pub player_number: u32,
+    pub profile: PlayerProfile,
+    pub quality: ConnectionQuality,
+    // Sequence numbers for packets we send to this player
+    pub send_seq: SequenceCounter,
+    // Packet sequence numbers received from this player
+    pub recv_window: ReceiveWindow,
+    // Latest move applied, so older moves arriving late are ignored
+    pub last_input_seq: Option<u32>,
+    // Secret the player's client sends back to resume this session
+    pub session_token: String,
+    // When the player's heartbeats stopped, while their session is kept for
+    // them to reconnect to
+    pub linkdead_since: Option<Instant>,
+    // Carried over from earlier sessions when the server keeps records
+    pub stats: PlayerStats,
+    // Only a player who got the name they asked for has a record. Fallback
+    // and numbered names belong to whoever the server hands them to next.
+    pub keeps_record: bool,
+}
+
+impl PlayerState {
+    pub fn new(
+        player_number: u32,
+        profile: PlayerProfile,
+        session_token: String,
+        now: Instant,
+    ) -> Self {
+        PlayerState {
+            position: Position::new(0, 0, 0),
+            last_heartbeat: now,
+            player_number,
+            profile,
+            quality: ConnectionQuality::new(now),
+            send_seq: SequenceCounter::default(),
+            recv_window: ReceiveWindow::new(),
+            last_input_seq: None,
+            session_token,
+            linkdead_since: None,
+            stats: PlayerStats::default(),
+            keeps_record: false,
+        }
+    }
 }
 
 // Server state structure
@@ -121,6 +395,12 @@ pub struct PlayerState {
 pub struct ServerState {
     pub players: HashMap<String, PlayerState>,
     pub board_size: (u32, u32),
+    // How often the cleanup task runs and redraws the board
+    pub tick_interval: Duration,
+    next_player_number: u32,
+    session_rng: StdRng,
+    // Where player records are kept between runs, if anywhere
+    pub storage: Option<Arc<dyn Storage>>,
 }
 
 impl ServerState {
@@ -128,11 +408,99 @@ impl ServerState {
         ServerState {
             players: HashMap::new(),
             board_size,
+            tick_interval: Duration::from_secs(5),
+            next_player_number: 0,
+            session_rng: StdRng::from_os_rng(),
+            storage: None,
         }
     }
+
+    // Makes session tokens repeatable, for deterministic runs.
+    pub fn seed_sessions(&mut self, seed: u64) {
+        self.session_rng = StdRng::seed_from_u64(seed);
+    }
+
+    // A fresh unguessable token for a new session.
+    pub fn new_session_token(&mut self) -> String {
+        format!("{:016x}", self.session_rng.random::<u64>())
+    }
+
+    // The address of the player holding a session token, if anyone does.
+    pub fn find_session(&self, token: &str) -> Option<String> {
+        self.players
+            .iter()
+            .find(|(_, p)| p.session_token == token)
+            .map(|(addr, _)| addr.clone())
+    }
+
+    // Hands out player numbers, which also serve as session IDs in logs.
+    pub fn next_player_number(&mut self) -> u32 {
+        let number = self.next_player_number;
+        self.next_player_number += 1;
+        number
+    }
+
+    // Builds a packet for one player, numbered in that player's outbound
+    // sequence space. Addresses without a session get sequence number 0.
+    pub fn packet_to(
+        &self,
+        addr: &str,
+        msg_type: MessageType,
+        payload: impl Into<Bytes>,
+    ) -> GamePacket {
+        let seq_num = self
+            .players
+            .get(addr)
+            .map(|p| p.send_seq.next())
+            .unwrap_or(0);
+        GamePacket::new(msg_type, seq_num, payload)
+    }
+
+    // Looks up a connected player's address by display name.
+    pub fn find_player(&self, name: &str) -> Option<String> {
+        self.players
+            .iter()
+            .find(|(_, p)| p.profile.name.eq_ignore_ascii_case(name))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    // Validates the requested profile and makes the name unique among the
+    // connected players by appending a number.
+    pub fn resolve_profile(
+        &self,
+        requested: Option<PlayerProfile>,
+        player_number: u32,
+    ) -> PlayerProfile {
+        let fallback = PlayerProfile::default_for(player_number);
+        let (base, color) = match requested {
+            Some(profile) => match sanitize_name(&profile.name) {
+                Some(name) => (name, profile.color),
+                None => (fallback.name, profile.color),
+            },
+            None => (fallback.name, fallback.color),
+        };
+        let taken = |name: &str| {
+            self.players
+                .values()
+                .any(|p| p.profile.name.eq_ignore_ascii_case(name))
+        };
+        let mut name = base.clone();
+        let mut suffix = 2;
+        while taken(&name) {
+            let tag = suffix.to_string();
+            let keep = MAX_NAME_LEN.saturating_sub(tag.len());
+            name = format!("{}{}", base.chars().take(keep).collect::<String>(), tag);
+            suffix += 1;
+        }
+        PlayerProfile { name, color }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
-        //convert to ServerStateSend
-        serde_json::to_vec(&ServerStateSend {
+        self.snapshot().serialize()
+    }
+
+    pub fn snapshot(&self) -> ServerStateSend {
+        ServerStateSend {
             players: self
                 .players
                 .iter()
@@ -141,46 +509,127 @@ impl ServerState {
                         k.clone(),
                         PlayerStateSend {
                             position: v.position.clone(),
+                            profile: v.profile.clone(),
                         },
                     )
                 })
                 .collect(),
             board_size: self.board_size,
-        })
-        .unwrap()
+            session: None,
+        }
     }
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
-
+// Players are kept in address order so the same state always encodes to the
+// same bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ServerStateSend {
-    pub players: HashMap<String, PlayerStateSend>,
+    pub players: BTreeMap<String, PlayerStateSend>,
     pub board_size: (u32, u32),
+    // Set only in the reply to the receiving player's own ConnectionInit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<Session>,
+}
+impl Default for ServerStateSend {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl ServerStateSend {
     pub fn new() -> Self {
         ServerStateSend {
-            players: HashMap::new(),
+            players: BTreeMap::new(),
             board_size: (254, 254),
+            session: None,
         }
     }
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
     pub fn deserialize(data: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(data)
     }
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+
+// The session a ConnectionInit reply is for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    // Sent back in a later ConnectionRequest to get this session back
+    pub token: String,
+    // The player's own key in `players`
+    pub player: String,
+    // Whether this picked up an existing session rather than starting one
+    pub resumed: bool,
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PlayerStateSend {
     pub position: Position,
+    pub profile: PlayerProfile,
 }
 
 impl PlayerStateSend {
-    pub fn new() -> Self {
+    pub fn new(profile: PlayerProfile) -> Self {
         PlayerStateSend {
             position: Position::new(0, 0, 0),
+            profile,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Payload of a PlayerJoin broadcast.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerJoin {
+    pub player: String,
+    pub profile: PlayerProfile,
+    // Set when a player reconnected from a new address: the address they
+    // were known by until now, whose entry moves over to `player`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+}
+
+impl PlayerJoin {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+// Payload of a client's PositionUpdate. `input_seq` numbers the player's moves
+// separately from packet sequence numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoveInput {
+    pub input_seq: u32,
+    pub position: Position,
+}
+
+impl MoveInput {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+// Payload of ConfirmPlayerMovement: where the server put the player after
+// handling the move numbered `input_seq`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoveAck {
+    pub input_seq: u32,
+    pub position: Position,
+}
+
+impl MoveAck {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PlayerUpdate {
     pub player: String,
     pub position: Position,
@@ -222,12 +671,20 @@ pub fn render_board(players: &HashMap<String, PlayerState>) -> Result<(), std::i
     }
 
     // Render players
-    for (_addr, player) in players {
+    for player in players.values() {
         let pos = &player.position;
 
         // Convert logical position to screen coordinates
-        let screen_x = center_x + pos.x as i32;
-        let screen_y = center_y - pos.y as i32;
+        let screen_x = center_x + pos.x;
+        let screen_y = center_y - pos.y;
+
+        // Fall back to initials when the full name would run into the border
+        let label =
+            if screen_x + (player.profile.name.chars().count() as i32) < term_width as i32 - 1 {
+                player.profile.name.clone()
+            } else {
+                player.profile.initials()
+            };
 
         // Ensure the player's position is visible
         if screen_x >= 0
@@ -238,8 +695,8 @@ pub fn render_board(players: &HashMap<String, PlayerState>) -> Result<(), std::i
             execute!(
                 stdout,
                 cursor::MoveTo(screen_x as u16, screen_y as u16),
-                style::SetForegroundColor(style::Color::Green),
-                Print(format!("P{}", player.player_number)), // Represent player with 'P'
+                style::SetForegroundColor(player.profile.color.to_terminal_color()),
+                Print(label),
                 style::ResetColor
             )?;
         }
@@ -251,3 +708,30 @@ pub fn render_board(players: &HashMap<String, PlayerState>) -> Result<(), std::i
     stdout.flush()?; // Ensure everything is drawn to the screen
     Ok(())
 }
+
+// Draws a status line across the top of the terminal without moving the cursor.
+pub fn render_hud(text: &str) -> Result<(), std::io::Error> {
+    let mut stdout = stdout();
+    execute!(
+        stdout,
+        cursor::SavePosition,
+        cursor::MoveTo(0, 0),
+        Clear(ClearType::CurrentLine),
+        Print(text),
+        cursor::RestorePosition
+    )?;
+    stdout.flush()
+}
+
+// Undoes render_board when the server stops: clears the board and shows the
+// cursor again, so the shell it returns to is usable.
+pub fn restore_terminal() -> Result<(), std::io::Error> {
+    let mut stdout = stdout();
+    execute!(
+        stdout,
+        Clear(ClearType::All),
+        cursor::MoveTo(0, 0),
+        cursor::Show
+    )?;
+    stdout.flush()
+}
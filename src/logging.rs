@@ -0,0 +1,30 @@
+// Tracing setup shared by the binaries. Logs never go to stdout, since both
+// the server and the client draw their UI there.
+//
+// GAME_UDP_LOG sets the filter using the usual RUST_LOG syntax, for example
+// "info" or "game_udp=debug,warn". GAME_UDP_LOG_FILE picks where output goes:
+// a file path, or "stderr". Without it, logs are appended to `default_file`.
+
+use std::{fs::OpenOptions, io, sync::Mutex};
+
+use tracing_subscriber::EnvFilter;
+
+pub const FILTER_VAR: &str = "GAME_UDP_LOG";
+pub const FILE_VAR: &str = "GAME_UDP_LOG_FILE";
+
+pub fn init(default_file: &str) -> io::Result<()> {
+    let filter = EnvFilter::try_from_env(FILTER_VAR).unwrap_or_else(|_| EnvFilter::new("info"));
+    let target = std::env::var(FILE_VAR).unwrap_or_else(|_| default_file.to_string());
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(false);
+
+    // Ignore the error if a subscriber is already installed, e.g. in tests
+    if target == "stderr" {
+        let _ = builder.with_writer(io::stderr).try_init();
+    } else {
+        let file = OpenOptions::new().create(true).append(true).open(&target)?;
+        let _ = builder.with_writer(Mutex::new(file)).try_init();
+    }
+    Ok(())
+}
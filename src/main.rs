@@ -1,253 +1,128 @@
 use crossterm::terminal;
-use game_udp::{Chat, GamePacket, MessageType, PlayerState, PlayerUpdate, Position, ServerState};
+use game_udp::{
+    access::AccessFile,
+    capture::{Recorder, Role},
+    clock::Clock,
+    commands::AdminCommand,
+    fragment,
+    metrics::{self, Metrics},
+    server::{Config, Server},
+    storage::FileStorage,
+    workers, ServerShutdown,
+};
 use std::{
+    io::{self, BufRead},
     sync::Arc,
-    time::{Duration, Instant},
-};
-use tokio::{
-    net::UdpSocket,
-    sync::Mutex,
-    task,
-    time::{self},
+    thread,
 };
+use tokio::{net::TcpListener, sync::mpsc, task};
+use tracing::{error, info};
+
+const ACCESS_FILE: &str = "access.json";
+const LOG_FILE: &str = "server.log";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    game_udp::logging::init(LOG_FILE)?;
     let server_addr = "0.0.0.0:4000";
-    let socket = Arc::new(UdpSocket::bind(server_addr).await?);
+    let metrics = Arc::new(Metrics::new());
+    let worker_count = workers::workers_from_env();
+    let sockets = workers::bind(server_addr.parse()?, worker_count).await?;
     println!("Server listening on {}", server_addr);
+    info!(
+        addr = server_addr,
+        workers = worker_count,
+        "server listening"
+    );
     let size = terminal::size().unwrap();
 
-    let state = Arc::new(Mutex::new(ServerState::new((size.0 as u32, size.1 as u32))));
+    let mut config = Config::new((size.0 as u32, size.1 as u32));
+    config.max_datagram = fragment::max_datagram_from_env();
+    config.render = true;
+    config.capture = Recorder::from_env(Role::Server, Clock::Runtime)?;
+    config.storage = FileStorage::from_env()?;
+    let access = AccessFile::open(ACCESS_FILE)?;
+    let (server, handoffs) = Server::new(sockets, access, Arc::clone(&metrics), config);
 
-    // Start a task for cleaning up disconnected players
-    let cleanup_state = Arc::clone(&state);
-    let cleanup_socket = Arc::clone(&socket);
-    task::spawn(async move {
-        let interval = time::interval(Duration::from_secs(5));
-        tokio::pin!(interval);
+    // Metrics are only served when an address is configured
+    if let Ok(metrics_addr) = std::env::var(metrics::ADDR_VAR) {
+        let listener = TcpListener::bind(&metrics_addr).await?;
+        info!(addr = %metrics_addr, "serving metrics");
+        let state = Arc::clone(server.state());
+        task::spawn(async move {
+            if let Err(e) = metrics::serve(listener, metrics, state).await {
+                error!(error = %e, "metrics listener stopped");
+            }
+        });
+    }
 
-        loop {
-            interval.tick().await;
-            let mut state = cleanup_state.lock().await;
-            let now = Instant::now();
-            let ids_to_remove: Vec<String> = state
-                .players
-                .iter()
-                .filter_map(|(addr, player)| {
-                    if now.duration_since(player.last_heartbeat) > Duration::from_secs(10) {
-                        // println!("Removing inactive player: {}", addr);
-                        Some(addr.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            for id in ids_to_remove {
-                let packet = GamePacket::new(MessageType::PlayerLeft, 0, id.as_bytes().to_vec());
-                let data = packet.serialize();
-                for (addr, _) in &state.players {
-                    if addr != &id {
-                        cleanup_socket.send_to(&data, addr).await.unwrap();
-                    }
-                }
+    // Admin console: commands typed on stdin. Reading stdin blocks and can't
+    // be cancelled, so it gets a thread of its own rather than one of the
+    // runtime's, which would keep the process alive after shutdown.
+    let (line_tx, mut lines) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if line_tx.send(line).is_err() {
+                break;
             }
-            state.players.retain(|_addr, player| {
-                if now.duration_since(player.last_heartbeat) > Duration::from_secs(10) {
-                    // println!("Removing inactive player: {}", addr);
-                    false
-                } else {
-                    true
-                }
-            });
-            game_udp::render_board(&state.players).unwrap();
         }
     });
-    // Start a Task to ping all players
-    // Start a task for sending heartbeats
-    let ping_socket = Arc::clone(&socket);
-    let ping_state = Arc::clone(&state);
+    let admin = server.clone();
     task::spawn(async move {
-        let interval = time::interval(Duration::from_secs(3));
-        tokio::pin!(interval);
-
-        loop {
-            interval.tick().await;
-            let state = ping_state.lock().await;
-            for (addr, _) in &state.players {
-                let reply = GamePacket::new(MessageType::Heartbeat, 0, vec![]);
-                let data = reply.serialize();
-                if let Ok(addr) = addr.parse::<std::net::SocketAddr>() {
-                    if let Err(e) = ping_socket.send_to(&data, addr).await {
-                        eprintln!("Failed to send heartbeat to {}: {}", addr, e);
+        while let Some(line) = lines.recv().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply = match AdminCommand::parse(&line) {
+                Ok(command) => match admin.admin(command).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        error!(error = %e, "admin command failed");
+                        format!("Failed: {}", e)
                     }
-                }
+                },
+                Err(usage) => usage,
+            };
+            if !reply.is_empty() {
+                println!("{}", reply);
             }
         }
     });
-    let mut buf = vec![0u8; 1500];
-    let mut player_number = 0;
-    loop {
-        let (len, client_addr) = socket.recv_from(&mut buf).await?;
-        let client_addr_str = client_addr.to_string();
-
-        if let Some(packet) = GamePacket::deserialize(&buf[..len]) {
-            // println!("Received {:?} from {}", packet, client_addr);
-
-            match packet.msg_type {
-                MessageType::PositionUpdate => {
-                    let position = Position::deserialize(&packet.payload).unwrap();
-                    let mut state = state.lock().await;
-                    let current_player_position = state
-                        .players
-                        .get(&client_addr_str)
-                        .unwrap() // FIXME: Handle error
-                        .position
-                        .clone();
-                    if position.x < -(state.board_size.0 as i32) / 2
-                        || position.x >= (state.board_size.0 as i32) / 2
-                    {
-                        // Invalid move, reset player position
-                        let player_packet = GamePacket::new(
-                            MessageType::ConfirmPlayerMovement,
-                            packet.seq_num,
-                            current_player_position.serialize(),
-                        );
-                        let data = player_packet.serialize();
-                        socket.send_to(&data, &client_addr).await?;
-                        continue;
-                    }
-                    if position.y - 2 < -(state.board_size.1 as i32) / 2
-                        || position.y >= (state.board_size.1 as i32) / 2
-                    {
-                        // Invalid move, reset player position
-                        let player_packet = GamePacket::new(
-                            MessageType::ConfirmPlayerMovement,
-                            packet.seq_num,
-                            current_player_position.serialize(),
-                        );
-                        let data = player_packet.serialize();
-                        socket.send_to(&data, &client_addr).await?;
-                        continue;
-                    }
-                    // Update player position
-                    if let Some(player) = state.players.get_mut(&client_addr_str) {
-                        player.position = position.clone();
-                        player.last_heartbeat = Instant::now();
-                    } else {
-                        // New player connecting
-                        state.players.insert(
-                            client_addr_str.clone(),
-                            PlayerState {
-                                position: position.clone(),
-                                last_heartbeat: Instant::now(),
-                                player_number,
-                            },
-                        );
-                        player_number += 1;
-                    }
 
-                    // Notify all players about the move
-                    let update_packet = GamePacket::new(
-                        MessageType::PositionUpdate,
-                        packet.seq_num,
-                        PlayerUpdate {
-                            player: client_addr_str.clone(),
-                            position: position.clone(),
-                        }
-                        .serialize(),
-                    );
-                    let data = update_packet.serialize();
-                    for (addr, _) in &state.players {
-                        if addr != &client_addr_str {
-                            socket.send_to(&data, addr).await?;
-                        } else {
-                            let player_packet = GamePacket::new(
-                                MessageType::ConfirmPlayerMovement,
-                                packet.seq_num,
-                                position.serialize(),
-                            );
-                            let data = player_packet.serialize();
-                            socket.send_to(&data, addr).await?;
-                        }
-                    }
-                    game_udp::render_board(&state.players).unwrap();
-                }
-                MessageType::ChatMessage => {
-                    if let Ok(chat) = serde_json::from_slice::<Chat>(&packet.payload) {
-                        // println!("Player says: {}", chat.text);
-
-                        // Broadcast chat to all players
-                        let chat_packet = GamePacket::new(
-                            MessageType::ChatMessage,
-                            packet.seq_num,
-                            serde_json::to_vec(&chat).unwrap(),
-                        );
-                        let data = chat_packet.serialize();
-                        let state = state.lock().await;
-                        for (addr, _) in &state.players {
-                            socket.send_to(&data, addr).await?;
-                        }
-                    }
-                }
-                MessageType::Heartbeat => {
-                    // Update heartbeat
-                    let mut state = state.lock().await;
-                    if let Some(player) = state.players.get_mut(&client_addr_str) {
-                        player.last_heartbeat = Instant::now();
-                    }
-                }
-                MessageType::ConnectionInit => {
-                    // Send current state to new player
-                    let mut state = state.lock().await;
-
-                    state.players.insert(
-                        client_addr_str.clone(),
-                        PlayerState {
-                            position: Position { x: 0, y: 0, z: 0 },
-                            last_heartbeat: Instant::now(),
-                            player_number,
-                        },
-                    );
-                    player_number += 1;
-                    let current_state = state.clone();
-                    drop(state);
-                    let reply = GamePacket::new(
-                        MessageType::ConnectionInit,
-                        packet.seq_num,
-                        current_state.serialize(),
-                    );
-                    let data = reply.serialize();
-                    socket.send_to(&data, &client_addr).await?;
-
-                    // Notify all players about the new player
-                    let new_player = GamePacket::new(
-                        MessageType::PlayerJoin,
-                        packet.seq_num,
-                        client_addr_str.clone().as_bytes().to_vec(),
-                    );
-                    let data = new_player.serialize();
-                    for (addr, _) in &current_state.players {
-                        if addr != &client_addr_str {
-                            socket.send_to(&data, addr).await?;
-                        }
-                    }
-
-                    // Send welcome message
-
-                    let welcome = Chat {
-                        text: "Welcome to the server!".to_string(),
-                    };
-                    let reply = GamePacket::new(
-                        MessageType::ChatMessage,
-                        packet.seq_num,
-                        serde_json::to_vec(&welcome).unwrap(),
-                    );
-                    let data = reply.serialize();
-                    socket.send_to(&data, &client_addr).await?;
-                }
-                _ => {}
+    // Ctrl+C or SIGTERM stops the server the way the shutdown command does
+    let stopper = server.clone();
+    task::spawn(async move {
+        match stop_signal().await {
+            Ok(signal) => info!(signal, "stopping"),
+            Err(e) => {
+                error!(error = %e, "can't listen for signals");
+                return;
             }
         }
+        stopper.stop(ServerShutdown {
+            reason: "Server shutting down".to_string(),
+            restart_in_secs: None,
+        });
+    });
+
+    server.run(handoffs).await?;
+    info!("shut down");
+    Ok(())
+}
+
+// Waits for a signal asking the server to stop, and names it.
+#[cfg(unix)]
+async fn stop_signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
     }
 }
+
+#[cfg(not(unix))]
+async fn stop_signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await.map(|_| "ctrl-c")
+}
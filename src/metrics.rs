@@ -0,0 +1,349 @@
+// Server health counters, exposed in the Prometheus text format by a small
+// HTTP listener. Everything here is lock-free so recording a packet never
+// waits on the game state.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::Mutex,
+};
+use tracing::{debug, info, warn};
+
+use crate::{quality::ConnectionStats, MessageType, PlayerState, ServerState};
+
+pub const ADDR_VAR: &str = "GAME_UDP_METRICS_ADDR";
+
+// Reads one per-client gauge from that client's connection stats.
+type ClientGauge = fn(&ConnectionStats) -> Option<f64>;
+
+// One slot per possible type byte.
+const TYPE_SLOTS: usize = 256;
+
+#[derive(Debug)]
+struct TypeCounters([AtomicU64; TYPE_SLOTS]);
+
+impl TypeCounters {
+    fn new() -> Self {
+        TypeCounters(std::array::from_fn(|_| AtomicU64::new(0)))
+    }
+
+    fn add(&self, type_byte: u8, n: u64) {
+        self.0[type_byte as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self, type_byte: u8) -> u64 {
+        self.0[type_byte as usize].load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    // Packets by type, counted outside any batch or fragments they travel
+    // in, so batching doesn't hide what's being sent
+    packets_in: TypeCounters,
+    packets_out: TypeCounters,
+    // What actually crossed the wire
+    datagrams_in: AtomicU64,
+    bytes_in: AtomicU64,
+    datagrams_out: AtomicU64,
+    bytes_out: AtomicU64,
+    decode_failures: AtomicU64,
+    heartbeat_timeouts: AtomicU64,
+    tick_nanos: AtomicU64,
+    lock_wait_nanos: AtomicU64,
+    lock_acquisitions: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            packets_in: TypeCounters::new(),
+            packets_out: TypeCounters::new(),
+            datagrams_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            datagrams_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            decode_failures: AtomicU64::new(0),
+            heartbeat_timeouts: AtomicU64::new(0),
+            tick_nanos: AtomicU64::new(0),
+            lock_wait_nanos: AtomicU64::new(0),
+            lock_acquisitions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_datagram_received(&self, datagram: &[u8]) {
+        self.datagrams_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_datagram_sent(&self, datagram: &[u8]) {
+        self.datagrams_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+    }
+
+    // A packet taken out of a datagram: one of a batch, a reassembled one,
+    // or one that came on its own.
+    pub fn record_packet_received(&self, msg_type: MessageType) {
+        self.packets_in.add(msg_type as u8, 1);
+    }
+
+    // A packet handed to the socket, before it's batched or fragmented.
+    pub fn record_packet_sent(&self, msg_type: MessageType) {
+        self.packets_out.add(msg_type as u8, 1);
+    }
+
+    pub fn record_decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_heartbeat_timeouts(&self, n: u64) {
+        self.heartbeat_timeouts.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn record_tick(&self, duration: Duration) {
+        self.tick_nanos
+            .store(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_lock_wait(&self, waited: Duration) {
+        self.lock_wait_nanos
+            .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+        self.lock_acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Logs the totals, so the last of them aren't lost with the process when
+    // nothing scrapes the listener in time.
+    pub fn log_totals(&self) {
+        let total = |counters: &TypeCounters| (0..=u8::MAX).map(|t| counters.get(t)).sum::<u64>();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        info!(
+            packets_received = total(&self.packets_in),
+            datagrams_received = load(&self.datagrams_in),
+            bytes_received = load(&self.bytes_in),
+            packets_sent = total(&self.packets_out),
+            datagrams_sent = load(&self.datagrams_out),
+            bytes_sent = load(&self.bytes_out),
+            decode_failures = self.decode_failures.load(Ordering::Relaxed),
+            heartbeat_timeouts = self.heartbeat_timeouts.load(Ordering::Relaxed),
+            "final metrics"
+        );
+    }
+
+    // Renders all metrics. Player gauges are read from the current state.
+    pub fn render(&self, players: &HashMap<String, PlayerState>) -> String {
+        let mut out = String::new();
+        let per_type = [
+            (
+                "game_udp_packets_received_total",
+                "Packets received, counted inside batches and after reassembly",
+                &self.packets_in,
+            ),
+            (
+                "game_udp_packets_sent_total",
+                "Packets sent, counted before batching and fragmenting",
+                &self.packets_out,
+            ),
+        ];
+        for (name, help, counters) in per_type {
+            write_header(&mut out, name, help, "counter");
+            for type_byte in 0..=u8::MAX {
+                let value = counters.get(type_byte);
+                let known = MessageType::from_byte(type_byte);
+                if value == 0 && known.is_none() {
+                    continue;
+                }
+                let label = match known {
+                    Some(msg_type) => format!("{:?}", msg_type),
+                    None => format!("unknown_{:#04x}", type_byte),
+                };
+                let _ = writeln!(out, "{}{{type=\"{}\"}} {}", name, label, value);
+            }
+        }
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let linkdead = players
+            .values()
+            .filter(|player| player.linkdead_since.is_some())
+            .count();
+        let seconds = |nanos: u64| nanos as f64 / 1e9;
+        let totals = [
+            (
+                "game_udp_datagrams_received_total",
+                "Datagrams received",
+                &self.datagrams_in,
+            ),
+            (
+                "game_udp_bytes_received_total",
+                "Bytes received",
+                &self.bytes_in,
+            ),
+            (
+                "game_udp_datagrams_sent_total",
+                "Datagrams sent",
+                &self.datagrams_out,
+            ),
+            ("game_udp_bytes_sent_total", "Bytes sent", &self.bytes_out),
+        ];
+        for (name, help, counter) in totals {
+            write_metric(&mut out, name, help, "counter", load(counter) as f64);
+        }
+        write_metric(
+            &mut out,
+            "game_udp_decode_failures_total",
+            "Datagrams that could not be decoded",
+            "counter",
+            load(&self.decode_failures) as f64,
+        );
+        write_metric(
+            &mut out,
+            "game_udp_heartbeat_timeouts_total",
+            "Players marked linkdead after missing heartbeats",
+            "counter",
+            load(&self.heartbeat_timeouts) as f64,
+        );
+        write_metric(
+            &mut out,
+            "game_udp_active_players",
+            "Players currently connected and answering heartbeats",
+            "gauge",
+            (players.len() - linkdead) as f64,
+        );
+        write_metric(
+            &mut out,
+            "game_udp_linkdead_players",
+            "Players whose session is kept for them after they stopped answering",
+            "gauge",
+            linkdead as f64,
+        );
+        write_metric(
+            &mut out,
+            "game_udp_tick_duration_seconds",
+            "Duration of the most recent server tick",
+            "gauge",
+            seconds(load(&self.tick_nanos)),
+        );
+        write_metric(
+            &mut out,
+            "game_udp_state_lock_wait_seconds_total",
+            "Time spent waiting for the server state lock",
+            "counter",
+            seconds(load(&self.lock_wait_nanos)),
+        );
+        write_metric(
+            &mut out,
+            "game_udp_state_lock_acquisitions_total",
+            "Number of times the server state lock was taken",
+            "counter",
+            load(&self.lock_acquisitions) as f64,
+        );
+
+        let per_client: [(&str, &str, ClientGauge); 3] = [
+            (
+                "game_udp_client_rtt_seconds",
+                "Smoothed round-trip time measured from heartbeats",
+                |stats| stats.rtt_ms.map(|ms| ms / 1000.0),
+            ),
+            (
+                "game_udp_client_jitter_seconds",
+                "Round-trip time variation measured from heartbeats",
+                |stats| Some(stats.jitter_ms / 1000.0),
+            ),
+            (
+                "game_udp_client_heartbeat_loss_ratio",
+                "Share of the client's heartbeats that never arrived",
+                |stats| Some(stats.loss_percent / 100.0),
+            ),
+        ];
+        for (name, help, value) in per_client {
+            write_header(&mut out, name, help, "gauge");
+            for (addr, player) in players {
+                if let Some(value) = value(&player.quality.stats()) {
+                    let _ = writeln!(
+                        out,
+                        "{}{{peer=\"{}\",name=\"{}\"}} {}",
+                        name,
+                        addr,
+                        escape_label(&player.profile.name),
+                        value
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: f64) {
+    write_header(out, name, help, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Serves the metrics on /metrics. This is deliberately minimal: it reads the
+// request head, ignores everything but the path, and closes the connection
+// after one response.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    state: Arc<Mutex<ServerState>>,
+) -> io::Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let len = match stream.read(&mut buf).await {
+                Ok(len) => len,
+                Err(e) => {
+                    warn!(%peer, error = %e, "failed to read metrics request");
+                    return;
+                }
+            };
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let path = request.split_whitespace().nth(1).unwrap_or("/");
+            debug!(%peer, path, "metrics request");
+            let response = if path == "/metrics" || path == "/" {
+                let body = metrics.render(&state.lock().await.players);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                warn!(%peer, error = %e, "failed to write metrics response");
+            }
+        });
+    }
+}
@@ -0,0 +1,154 @@
+// Batched datagram I/O for Linux, enabled with the `mmsg` feature.
+//
+// recvmmsg and sendmmsg move up to BATCH datagrams per system call instead of
+// one. Both are called non-blocking: MeteredSocket runs them inside tokio's
+// readiness handling, so WouldBlock here just means "wait and try again".
+
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::RawFd,
+    ptr,
+};
+
+use bytes::Bytes;
+
+pub const BATCH: usize = 16;
+
+// Receives up to BATCH datagrams, the i-th into the i-th `slot`-byte chunk of
+// `buf`, and appends each one's length and sender to `out`.
+pub fn recv(
+    fd: RawFd,
+    buf: &mut [u8],
+    slot: usize,
+    out: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<()> {
+    let slots = (buf.len() / slot).min(BATCH);
+    // SAFETY: all-zero is a valid value for these plain C structs
+    let mut iovecs: [libc::iovec; BATCH] = unsafe { mem::zeroed() };
+    let mut addrs: [libc::sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+    for (i, chunk) in buf.chunks_exact_mut(slot).take(slots).enumerate() {
+        iovecs[i] = libc::iovec {
+            iov_base: chunk.as_mut_ptr().cast(),
+            iov_len: slot,
+        };
+        let hdr = &mut msgs[i].msg_hdr;
+        hdr.msg_name = ptr::addr_of_mut!(addrs[i]).cast();
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+        hdr.msg_iovlen = 1;
+    }
+    // SAFETY: every header points at a live iovec, address and buffer chunk
+    // that outlive the call
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            slots as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for (msg, addr) in msgs.iter().zip(&addrs).take(received as usize) {
+        if let Some(addr) = to_socket_addr(addr) {
+            out.push((msg.msg_len as usize, addr));
+        }
+    }
+    Ok(())
+}
+
+// Sends up to BATCH of `datagrams` and returns how many went out.
+pub fn send(fd: RawFd, datagrams: &[(SocketAddr, Bytes)]) -> io::Result<usize> {
+    let count = datagrams.len().min(BATCH);
+    // SAFETY: all-zero is a valid value for these plain C structs
+    let mut iovecs: [libc::iovec; BATCH] = unsafe { mem::zeroed() };
+    let mut addrs: [libc::sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+    for (i, (addr, data)) in datagrams.iter().take(count).enumerate() {
+        iovecs[i] = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let hdr = &mut msgs[i].msg_hdr;
+        hdr.msg_namelen = from_socket_addr(addr, &mut addrs[i]);
+        hdr.msg_name = ptr::addr_of_mut!(addrs[i]).cast();
+        hdr.msg_iov = ptr::addr_of_mut!(iovecs[i]);
+        hdr.msg_iovlen = 1;
+    }
+    // SAFETY: every header points at a live iovec, address and datagram
+    // that outlive the call; the kernel only reads the datagrams
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says this is a sockaddr_in, which fits in
+            // sockaddr_storage
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: as above, for sockaddr_in6
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn from_socket_addr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: sockaddr_storage is large and aligned enough for any
+            // socket address
+            unsafe { ptr::write(storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: as above
+            unsafe { ptr::write(storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// UDP proxy that makes the network worse on purpose. Point clients at the
+// listen address instead of the server.
+//
+// Usage: netsim [--listen ADDR] [--server ADDR] [--seed N]
+//               [--loss PERCENT] [--latency MS] [--jitter MS]
+//               [--duplicate PERCENT] [--reorder PERCENT] [--reorder-delay MS]
+//
+// Conditions apply in both directions. Prefix one with `up-` (client to
+// server) or `down-` (server to client) to set just that direction, for
+// example `--down-loss 20`.
+
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use game_udp::conditioner::{self, LinkConditions, NetworkConditioner};
+use tokio::net::UdpSocket;
+use tracing::info;
+
+const LOG_FILE: &str = "netsim.log";
+
+#[derive(Debug, Clone)]
+struct Options {
+    listen: SocketAddr,
+    server: SocketAddr,
+    seed: u64,
+    upstream: LinkConditions,
+    downstream: LinkConditions,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            listen: "127.0.0.1:4001".parse().unwrap(),
+            server: "127.0.0.1:4000".parse().unwrap(),
+            seed: 0,
+            upstream: LinkConditions::default(),
+            downstream: LinkConditions::default(),
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--listen" => options.listen = parse_value(&flag, &value)?,
+                "--server" => options.server = parse_value(&flag, &value)?,
+                "--seed" => options.seed = parse_value(&flag, &value)?,
+                _ => {
+                    let name = flag
+                        .strip_prefix("--")
+                        .ok_or_else(|| format!("Unknown option {}", flag))?;
+                    if let Some(name) = name.strip_prefix("up-") {
+                        set_condition(&mut options.upstream, name, &flag, &value)?;
+                    } else if let Some(name) = name.strip_prefix("down-") {
+                        set_condition(&mut options.downstream, name, &flag, &value)?;
+                    } else {
+                        set_condition(&mut options.upstream, name, &flag, &value)?;
+                        set_condition(&mut options.downstream, name, &flag, &value)?;
+                    }
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn set_condition(
+    conditions: &mut LinkConditions,
+    name: &str,
+    flag: &str,
+    value: &str,
+) -> Result<(), String> {
+    match name {
+        "loss" => conditions.loss = parse_percent(flag, value)?,
+        "latency" => conditions.latency = parse_millis(flag, value)?,
+        "jitter" => conditions.jitter = parse_millis(flag, value)?,
+        "duplicate" => conditions.duplicate = parse_percent(flag, value)?,
+        "reorder" => conditions.reorder = parse_percent(flag, value)?,
+        "reorder-delay" => conditions.reorder_delay = parse_millis(flag, value)?,
+        _ => return Err(format!("Unknown option {}", flag)),
+    }
+    Ok(())
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+fn parse_percent(flag: &str, value: &str) -> Result<f64, String> {
+    let percent: f64 = parse_value(flag, value)?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("{} must be between 0 and 100", flag));
+    }
+    Ok(percent / 100.0)
+}
+
+fn parse_millis(flag: &str, value: &str) -> Result<Duration, String> {
+    let millis: f64 = parse_value(flag, value)?;
+    if !millis.is_finite() || millis < 0.0 {
+        return Err(format!(
+            "{} must be a positive number of milliseconds",
+            flag
+        ));
+    }
+    Ok(Duration::from_secs_f64(millis / 1000.0))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            eprintln!(
+                "Usage: netsim [--listen ADDR] [--server ADDR] [--seed N] [--[up-|down-]loss PERCENT] [--[up-|down-]latency MS] [--[up-|down-]jitter MS] [--[up-|down-]duplicate PERCENT] [--[up-|down-]reorder PERCENT] [--[up-|down-]reorder-delay MS]"
+            );
+            std::process::exit(2);
+        }
+    };
+    game_udp::logging::init(LOG_FILE)?;
+    info!(?options, "starting network simulator");
+    println!(
+        "Proxying {} -> {} (seed {})",
+        options.listen, options.server, options.seed
+    );
+    println!("  up:   {:?}", options.upstream);
+    println!("  down: {:?}", options.downstream);
+
+    let listen = UdpSocket::bind(options.listen).await?;
+    let conditioner = NetworkConditioner::new(options.upstream, options.downstream, options.seed);
+    conditioner::proxy(listen, options.server, Arc::new(Mutex::new(conditioner))).await?;
+    Ok(())
+}
@@ -0,0 +1,746 @@
+// The wire format, written down in one place.
+//
+// Every datagram holds one packet, a batch of packets (see batch.rs) or one
+// fragment of a packet too big for a datagram (see fragment.rs). A packet is
+// a fixed header followed by a payload, laid out as in HEADER. Payloads are
+// JSON objects whose field names come from the serde definitions of the
+// payload types, except PlayerLeft's, which is the departing player's
+// address as bare UTF-8.
+//
+// MESSAGES describes every payload field by field, for each side that sends
+// it. `vectors` encodes a sample of every message with the library's own
+// types, and protocol/vectors.json holds the bytes those samples encoded to
+// the last time the format was changed on purpose. tests/protocol.rs checks
+// the two still agree and that every sample matches its schema, so clients
+// written in other languages can be checked against the same file. After a
+// deliberate change, regenerate it with
+//
+//     cargo run --bin game_udp-inspect -- --vectors protocol/vectors.json
+
+use std::time::Instant;
+
+use bytes::Bytes;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    batch,
+    fragment::{self, Reassembler},
+    quality::{ConnectionStats, Heartbeat, HeartbeatEcho},
+    Chat, ConnectionRejected, ConnectionRequest, GamePacket, MessageType, MoveAck, MoveInput,
+    PlayerColor, PlayerJoin, PlayerProfile, PlayerStateSend, PlayerUpdate, Position,
+    ServerShutdown, ServerStateSend, Session,
+};
+
+// The version byte every packet carries.
+pub const VERSION: u8 = 1;
+
+// One field of the packet header. Integers are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderField {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub offset: usize,
+    pub len: usize,
+}
+
+// The header as GamePacket::encode_into writes it.
+pub const HEADER: [HeaderField; 3] = [
+    HeaderField {
+        name: "type",
+        label: "Message type",
+        offset: 0,
+        len: 1,
+    },
+    HeaderField {
+        name: "version",
+        label: "Version",
+        offset: 1,
+        len: 1,
+    },
+    HeaderField {
+        name: "seq",
+        label: "Sequence number",
+        offset: 2,
+        len: 4,
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sender {
+    Client,
+    Server,
+}
+
+// The type of a JSON value in a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    U32,
+    U64,
+    I32,
+    F64,
+    Bool,
+    String,
+    // One of these strings
+    Enum(&'static [&'static str]),
+    // May be null or left out
+    Optional(&'static Type),
+    Object(&'static [Field]),
+    // An object keyed by arbitrary strings
+    Map(&'static Type),
+    // An array of exactly these
+    Tuple(&'static [Type]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub ty: Type,
+}
+
+const fn field(name: &'static str, ty: Type) -> Field {
+    Field { name, ty }
+}
+
+// How a message's payload is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    // A JSON object with exactly these fields
+    Json(&'static [Field]),
+    // Bare UTF-8
+    Text,
+    // Index: u16, count: u16, then a chunk of the original packet
+    Fragment,
+    // Frames of length: u16, then a whole packet
+    Batch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    pub msg_type: MessageType,
+    pub sender: Sender,
+    pub payload: Payload,
+    pub summary: &'static str,
+}
+
+const POSITION: Type = Type::Object(&[
+    field("x", Type::I32),
+    field("y", Type::I32),
+    field("z", Type::I32),
+]);
+const COLOR: Type = Type::Enum(&["Red", "Green", "Yellow", "Blue", "Magenta", "Cyan", "White"]);
+const PROFILE: Type = Type::Object(&[field("name", Type::String), field("color", COLOR)]);
+const HEARTBEAT: &[Field] = &[
+    field("id", Type::U32),
+    field("sent_at_us", Type::U64),
+    field(
+        "echo",
+        Type::Optional(&Type::Object(&[
+            field("id", Type::U32),
+            field("sent_at_us", Type::U64),
+            field("held_us", Type::U64),
+        ])),
+    ),
+    field(
+        "stats",
+        Type::Optional(&Type::Object(&[
+            field("rtt_ms", Type::Optional(&Type::F64)),
+            field("jitter_ms", Type::F64),
+            field("loss_percent", Type::F64),
+        ])),
+    ),
+];
+const CHAT: &[Field] = &[field("text", Type::String)];
+
+// Every message, once for each side that sends it.
+pub const MESSAGES: &[Message] = &[
+    Message {
+        msg_type: MessageType::ConnectionInit,
+        sender: Sender::Client,
+        // The color may be left out of a requested profile, but the server
+        // always sends one
+        payload: Payload::Json(&[
+            field("name", Type::String),
+            field("color", Type::Optional(&COLOR)),
+            field("session", Type::Optional(&Type::String)),
+        ]),
+        summary: "Asks to join with a profile. With the token of an earlier session, asks \
+                  for that session back, and starts a new one if the server no longer has it.",
+    },
+    Message {
+        msg_type: MessageType::ConnectionInit,
+        sender: Sender::Server,
+        payload: Payload::Json(&[
+            field(
+                "players",
+                Type::Map(&Type::Object(&[
+                    field("position", POSITION),
+                    field("profile", PROFILE),
+                ])),
+            ),
+            field("board_size", Type::Tuple(&[Type::U32, Type::U32])),
+            field(
+                "session",
+                Type::Optional(&Type::Object(&[
+                    field("token", Type::String),
+                    field("player", Type::String),
+                    field("resumed", Type::Bool),
+                ])),
+            ),
+        ]),
+        summary: "Accepts a join with everyone on the board, keyed by address, and the \
+                  session it started or resumed.",
+    },
+    Message {
+        msg_type: MessageType::PositionUpdate,
+        sender: Sender::Client,
+        payload: Payload::Json(&[field("input_seq", Type::U32), field("position", POSITION)]),
+        summary: "Asks to move. input_seq numbers the player's moves.",
+    },
+    Message {
+        msg_type: MessageType::PositionUpdate,
+        sender: Sender::Server,
+        payload: Payload::Json(&[field("player", Type::String), field("position", POSITION)]),
+        summary: "Another player moved.",
+    },
+    Message {
+        msg_type: MessageType::ConfirmPlayerMovement,
+        sender: Sender::Server,
+        payload: Payload::Json(&[field("input_seq", Type::U32), field("position", POSITION)]),
+        summary: "Where a move left the player, which is where they were if it was refused.",
+    },
+    Message {
+        msg_type: MessageType::ChatMessage,
+        sender: Sender::Client,
+        payload: Payload::Json(CHAT),
+        summary: "Says something, or runs a command if it starts with a slash.",
+    },
+    Message {
+        msg_type: MessageType::ChatMessage,
+        sender: Sender::Server,
+        payload: Payload::Json(CHAT),
+        summary: "Something said, or a reply to a command.",
+    },
+    Message {
+        msg_type: MessageType::Heartbeat,
+        sender: Sender::Client,
+        payload: Payload::Json(HEARTBEAT),
+        summary: "Keeps the session alive and measures the connection, see quality.rs.",
+    },
+    Message {
+        msg_type: MessageType::Heartbeat,
+        sender: Sender::Server,
+        payload: Payload::Json(HEARTBEAT),
+        summary: "Asks for a heartbeat back and measures the connection.",
+    },
+    Message {
+        msg_type: MessageType::PlayerJoin,
+        sender: Sender::Server,
+        payload: Payload::Json(&[
+            field("player", Type::String),
+            field("profile", PROFILE),
+            field("previous", Type::Optional(&Type::String)),
+        ]),
+        summary: "A player joined, or changed their name if already known. With previous, \
+                  a player reconnected from a new address and keeps their old entry.",
+    },
+    Message {
+        msg_type: MessageType::PlayerLeft,
+        sender: Sender::Server,
+        payload: Payload::Text,
+        summary: "The address of a player who left.",
+    },
+    Message {
+        msg_type: MessageType::ConnectionRejected,
+        sender: Sender::Server,
+        payload: Payload::Json(&[field("reason", Type::String)]),
+        summary: "A join was refused, for example by a ban.",
+    },
+    Message {
+        msg_type: MessageType::Fragment,
+        sender: Sender::Client,
+        payload: Payload::Fragment,
+        summary: "One piece of a packet too big for a datagram. Carries its sequence number.",
+    },
+    Message {
+        msg_type: MessageType::Fragment,
+        sender: Sender::Server,
+        payload: Payload::Fragment,
+        summary: "One piece of a packet too big for a datagram. Carries its sequence number.",
+    },
+    Message {
+        msg_type: MessageType::Batch,
+        sender: Sender::Server,
+        payload: Payload::Batch,
+        summary: "Several packets sharing a datagram. Always sequence number 0.",
+    },
+    Message {
+        msg_type: MessageType::ServerShutdown,
+        sender: Sender::Server,
+        payload: Payload::Json(&[
+            field("reason", Type::String),
+            field("restart_in_secs", Type::Optional(&Type::U64)),
+        ]),
+        summary: "The server is stopping. With restart_in_secs, it expects to be back \
+                  in about that long, and clients wait that long before reconnecting.",
+    },
+];
+
+// The schema for a message sent by `sender`, if it sends that message.
+pub fn message(msg_type: MessageType, sender: Sender) -> Option<&'static Message> {
+    MESSAGES
+        .iter()
+        .find(|m| m.msg_type == msg_type && m.sender == sender)
+}
+
+// Checks a packet's payload against its schema. Fields are checked strictly,
+// so a renamed or extra field is an error.
+pub fn validate(packet: &GamePacket, sender: Sender) -> Result<(), String> {
+    let message = message(packet.msg_type, sender)
+        .ok_or_else(|| format!("the {:?} doesn't send {:?}", sender, packet.msg_type))?;
+    match message.payload {
+        Payload::Json(fields) => {
+            let value: Value = serde_json::from_slice(&packet.payload)
+                .map_err(|e| format!("payload isn't JSON: {}", e))?;
+            check(&Type::Object(fields), &value, "payload")
+        }
+        Payload::Text => std::str::from_utf8(&packet.payload)
+            .map(|_| ())
+            .map_err(|_| "payload isn't UTF-8".to_string()),
+        Payload::Fragment => fragment::header(packet)
+            .map(|_| ())
+            .ok_or_else(|| "malformed fragment header".to_string()),
+        Payload::Batch => {
+            let datagram = packet.serialize();
+            for (i, inner) in GamePacket::messages(datagram).enumerate() {
+                let inner = inner.ok_or_else(|| format!("frame {} is malformed", i))?;
+                validate(&inner, sender).map_err(|e| format!("frame {}: {}", i, e))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn check(ty: &Type, value: &Value, path: &str) -> Result<(), String> {
+    let fits = match ty {
+        Type::U32 => value.as_u64().is_some_and(|n| n <= u32::MAX as u64),
+        Type::U64 => value.is_u64(),
+        Type::I32 => value.as_i64().is_some_and(|n| i32::try_from(n).is_ok()),
+        Type::F64 => value.is_number(),
+        Type::Bool => value.is_boolean(),
+        Type::String => value.is_string(),
+        Type::Enum(names) => value.as_str().is_some_and(|s| names.contains(&s)),
+        Type::Optional(inner) => {
+            return if value.is_null() {
+                Ok(())
+            } else {
+                check(inner, value, path)
+            }
+        }
+        Type::Object(fields) => {
+            let object = value
+                .as_object()
+                .ok_or_else(|| format!("{} isn't an object", path))?;
+            for key in object.keys() {
+                if !fields.iter().any(|f| f.name == key) {
+                    return Err(format!("{} has an unexpected field {:?}", path, key));
+                }
+            }
+            for field in fields.iter() {
+                let path = format!("{}.{}", path, field.name);
+                match object.get(field.name) {
+                    Some(value) => check(&field.ty, value, &path)?,
+                    None if matches!(field.ty, Type::Optional(_)) => {}
+                    None => return Err(format!("{} is missing", path)),
+                }
+            }
+            return Ok(());
+        }
+        Type::Map(inner) => {
+            let object = value
+                .as_object()
+                .ok_or_else(|| format!("{} isn't an object", path))?;
+            for (key, value) in object {
+                check(inner, value, &format!("{}[{:?}]", path, key))?;
+            }
+            return Ok(());
+        }
+        Type::Tuple(types) => {
+            let array = value
+                .as_array()
+                .ok_or_else(|| format!("{} isn't an array", path))?;
+            if array.len() != types.len() {
+                return Err(format!("{} should have {} items", path, types.len()));
+            }
+            for (i, (ty, value)) in types.iter().zip(array).enumerate() {
+                check(ty, value, &format!("{}[{}]", path, i))?;
+            }
+            return Ok(());
+        }
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(format!("{} should be {:?}, not {}", path, ty, value))
+    }
+}
+
+// A sample exchange, as the datagrams that carry it.
+#[derive(Debug, Clone)]
+pub struct Vector {
+    pub name: &'static str,
+    pub sender: Sender,
+    pub description: &'static str,
+    pub datagrams: Vec<Bytes>,
+}
+
+impl Vector {
+    fn packet(
+        name: &'static str,
+        sender: Sender,
+        description: &'static str,
+        msg_type: MessageType,
+        seq_num: u32,
+        payload: Vec<u8>,
+    ) -> Self {
+        let packet = GamePacket::new(msg_type, seq_num, payload);
+        Vector {
+            name,
+            sender,
+            description,
+            datagrams: vec![packet.serialize()],
+        }
+    }
+
+    // The whole packets the datagrams carry, unbatched and reassembled.
+    pub fn packets(&self) -> Vec<GamePacket> {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let mut packets = Vec::new();
+        for datagram in &self.datagrams {
+            for packet in GamePacket::messages(datagram.clone()).flatten() {
+                match packet.msg_type {
+                    MessageType::Fragment => {
+                        if let Ok(Some(whole)) = reassembler.insert((), &packet, now) {
+                            packets.push(whole);
+                        }
+                    }
+                    _ => packets.push(packet),
+                }
+            }
+        }
+        packets
+    }
+}
+
+// One sample of every message, encoded by the library.
+pub fn vectors() -> Vec<Vector> {
+    let alice = PlayerProfile::new("alice", PlayerColor::Red);
+    let bob = PlayerProfile::new("bob", PlayerColor::Cyan);
+    let alice_addr = "192.0.2.1:50000".to_string();
+    let bob_addr = "192.0.2.2:50001".to_string();
+    let mut snapshot = ServerStateSend::new();
+    snapshot.board_size = (80, 24);
+    snapshot.players.insert(
+        alice_addr.clone(),
+        PlayerStateSend {
+            position: Position::new(0, 0, 0),
+            profile: alice.clone(),
+        },
+    );
+    snapshot.players.insert(
+        bob_addr.clone(),
+        PlayerStateSend {
+            position: Position::new(-3, 5, 0),
+            profile: bob.clone(),
+        },
+    );
+    let token = "5eb63bbbe01eeed0".to_string();
+    snapshot.session = Some(Session {
+        token: token.clone(),
+        player: alice_addr.clone(),
+        resumed: false,
+    });
+    let mut resumed = snapshot.clone();
+    resumed.players.get_mut(&alice_addr).unwrap().position = Position::new(4, -2, 0);
+    resumed.session.as_mut().unwrap().resumed = true;
+    let chat = |text: &str| {
+        serde_json::to_vec(&Chat {
+            text: text.to_string(),
+        })
+        .unwrap()
+    };
+    let first_heartbeat = Heartbeat {
+        id: 0,
+        sent_at_us: 0,
+        echo: None,
+        stats: Some(ConnectionStats {
+            rtt_ms: None,
+            jitter_ms: 0.0,
+            loss_percent: 0.0,
+        }),
+    };
+    let heartbeat = Heartbeat {
+        id: 7,
+        sent_at_us: 21_000_250,
+        echo: Some(HeartbeatEcho {
+            id: 6,
+            sent_at_us: 18_003_000,
+            held_us: 2_994_500,
+        }),
+        stats: Some(ConnectionStats {
+            rtt_ms: Some(12.5),
+            jitter_ms: 1.25,
+            loss_percent: 3.125,
+        }),
+    };
+    let moved = Position::new(4, -2, 0);
+
+    let batched = [
+        GamePacket::new(
+            MessageType::PlayerJoin,
+            3,
+            PlayerJoin {
+                player: bob_addr.clone(),
+                profile: bob.clone(),
+                previous: None,
+            }
+            .serialize(),
+        ),
+        GamePacket::new(MessageType::ChatMessage, 4, chat("bob joined")),
+    ];
+    let split_snapshot = GamePacket::new(
+        MessageType::ConnectionInit,
+        1,
+        serde_json::to_vec(&snapshot).unwrap(),
+    );
+    let long_chat = GamePacket::new(
+        MessageType::ChatMessage,
+        9,
+        chat("a message long enough that it has to be split across several small datagrams"),
+    );
+
+    vec![
+        Vector::packet(
+            "connection_init_request",
+            Sender::Client,
+            "A client asking to join as alice.",
+            MessageType::ConnectionInit,
+            1,
+            alice.serialize(),
+        ),
+        Vector::packet(
+            "connection_init_snapshot",
+            Sender::Server,
+            "The server accepting alice, with bob already on the board.",
+            MessageType::ConnectionInit,
+            1,
+            serde_json::to_vec(&snapshot).unwrap(),
+        ),
+        Vector::packet(
+            "connection_init_resume",
+            Sender::Client,
+            "Alice's client reconnecting with the session it was given.",
+            MessageType::ConnectionInit,
+            12,
+            ConnectionRequest {
+                profile: alice.clone(),
+                session: Some(token),
+            }
+            .serialize(),
+        ),
+        Vector::packet(
+            "connection_init_resumed",
+            Sender::Server,
+            "The server handing alice their session back, where they left off.",
+            MessageType::ConnectionInit,
+            9,
+            resumed.serialize(),
+        ),
+        Vector::packet(
+            "move_input",
+            Sender::Client,
+            "Alice asking to move.",
+            MessageType::PositionUpdate,
+            2,
+            MoveInput {
+                input_seq: 1,
+                position: moved.clone(),
+            }
+            .serialize(),
+        ),
+        Vector::packet(
+            "move_ack",
+            Sender::Server,
+            "The server confirming alice's move.",
+            MessageType::ConfirmPlayerMovement,
+            5,
+            MoveAck {
+                input_seq: 1,
+                position: moved.clone(),
+            }
+            .serialize(),
+        ),
+        Vector::packet(
+            "player_update",
+            Sender::Server,
+            "The server telling bob that alice moved.",
+            MessageType::PositionUpdate,
+            6,
+            PlayerUpdate {
+                player: alice_addr.clone(),
+                position: moved,
+            }
+            .serialize(),
+        ),
+        Vector::packet(
+            "chat_from_client",
+            Sender::Client,
+            "Alice saying hello, with non-ASCII text.",
+            MessageType::ChatMessage,
+            3,
+            chat("héllo \"world\" 👋"),
+        ),
+        Vector::packet(
+            "chat_from_server",
+            Sender::Server,
+            "The server's welcome message.",
+            MessageType::ChatMessage,
+            2,
+            chat("Welcome to the server, alice!"),
+        ),
+        Vector::packet(
+            "heartbeat_first",
+            Sender::Server,
+            "The first heartbeat of a session, with nothing to echo yet.",
+            MessageType::Heartbeat,
+            7,
+            first_heartbeat.serialize(),
+        ),
+        Vector::packet(
+            "heartbeat_reply",
+            Sender::Client,
+            "A heartbeat echoing the server's last one.",
+            MessageType::Heartbeat,
+            4,
+            heartbeat.serialize(),
+        ),
+        Vector::packet(
+            "player_join",
+            Sender::Server,
+            "The server telling alice that bob joined.",
+            MessageType::PlayerJoin,
+            8,
+            PlayerJoin {
+                player: bob_addr.clone(),
+                profile: bob.clone(),
+                previous: None,
+            }
+            .serialize(),
+        ),
+        Vector::packet(
+            "player_reconnected",
+            Sender::Server,
+            "The server telling alice that bob came back from a new address.",
+            MessageType::PlayerJoin,
+            15,
+            PlayerJoin {
+                player: "192.0.2.2:50002".to_string(),
+                profile: bob,
+                previous: Some(bob_addr.clone()),
+            }
+            .serialize(),
+        ),
+        Vector::packet(
+            "player_left",
+            Sender::Server,
+            "The server telling alice that bob left.",
+            MessageType::PlayerLeft,
+            9,
+            bob_addr.into_bytes(),
+        ),
+        Vector::packet(
+            "connection_rejected",
+            Sender::Server,
+            "The server refusing a banned client.",
+            MessageType::ConnectionRejected,
+            1,
+            serde_json::to_vec(&ConnectionRejected {
+                reason: "You are banned from this server".to_string(),
+            })
+            .unwrap(),
+        ),
+        Vector::packet(
+            "server_shutdown",
+            Sender::Server,
+            "The server telling alice it's restarting and will be back in a minute.",
+            MessageType::ServerShutdown,
+            10,
+            ServerShutdown {
+                reason: "Restarting for an update".to_string(),
+                restart_in_secs: Some(60),
+            }
+            .serialize(),
+        ),
+        Vector {
+            name: "batch",
+            sender: Sender::Server,
+            description: "Two packets for alice sharing one datagram.",
+            datagrams: batch::pack(batched.to_vec(), fragment::DEFAULT_MAX_DATAGRAM),
+        },
+        Vector {
+            name: "fragmented_snapshot",
+            sender: Sender::Server,
+            description: "The snapshot above split for a 128 byte datagram limit.",
+            datagrams: fragment::split(&split_snapshot, 128).unwrap(),
+        },
+        Vector {
+            name: "fragments",
+            sender: Sender::Client,
+            description: "A chat message split for a 64 byte datagram limit.",
+            datagrams: fragment::split(&long_chat, 64).unwrap(),
+        },
+    ]
+}
+
+// The vectors as the JSON in protocol/vectors.json. Each one lists its
+// datagrams in hex, and the packets they carry with decoded payloads.
+pub fn vectors_json() -> String {
+    let vectors: Vec<Value> = vectors()
+        .iter()
+        .map(|vector| {
+            let packets: Vec<Value> = vector
+                .packets()
+                .iter()
+                .map(|packet| {
+                    let payload = match message(packet.msg_type, vector.sender).map(|m| m.payload) {
+                        Some(Payload::Json(_)) => serde_json::from_slice(&packet.payload).unwrap(),
+                        _ => Value::String(String::from_utf8_lossy(&packet.payload).into_owned()),
+                    };
+                    json!({
+                        "type": format!("{:?}", packet.msg_type),
+                        "type_byte": packet.msg_type as u8,
+                        "version": packet.version,
+                        "seq": packet.seq_num,
+                        "payload": payload,
+                    })
+                })
+                .collect();
+            json!({
+                "name": vector.name,
+                "sender": vector.sender,
+                "description": vector.description,
+                "datagrams": vector.datagrams.iter().map(|d| hex(d)).collect::<Vec<_>>(),
+                "packets": packets,
+            })
+        })
+        .collect();
+    let mut text = serde_json::to_string_pretty(&vectors).unwrap();
+    text.push('\n');
+    text
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,194 @@
+// Connection quality measured from heartbeats.
+//
+// Every heartbeat carries the sender's heartbeat id and a timestamp from the
+// sender's own clock. It also echoes the last heartbeat the sender received,
+// along with how long it held on to it before replying. That lets either side
+// take an RTT sample from any heartbeat the other side sends, without the two
+// clocks having to agree:
+//
+//     rtt = now - echo.sent_at - echo.held
+//
+// Gaps in the heartbeat ids show how many of the peer's heartbeats were lost.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sequence::seq_greater;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HeartbeatEcho {
+    pub id: u32,
+    pub sent_at_us: u64,
+    pub held_us: u64,
+}
+
+// Payload of a Heartbeat packet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub id: u32,
+    pub sent_at_us: u64,
+    pub echo: Option<HeartbeatEcho>,
+    // The sender's view of the connection, so both ends can display it
+    pub stats: Option<ConnectionStats>,
+}
+
+impl Heartbeat {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    // Smoothed round-trip time, None until the first sample
+    pub rtt_ms: Option<f64>,
+    pub jitter_ms: f64,
+    // Share of the peer's heartbeats that never arrived, 0-100
+    pub loss_percent: f64,
+}
+
+impl ConnectionStats {
+    // Short status line for the client HUD.
+    pub fn summary(&self) -> String {
+        match self.rtt_ms {
+            Some(rtt) => format!(
+                "ping {:.0}ms  jitter {:.1}ms  loss {:.0}%",
+                rtt, self.jitter_ms, self.loss_percent
+            ),
+            None => format!("ping --  loss {:.0}%", self.loss_percent),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Received {
+    id: u32,
+    sent_at_us: u64,
+    at: Instant,
+}
+
+// Tracks one side of a connection. The server keeps one per player; the
+// client keeps one for its server.
+#[derive(Debug, Clone)]
+pub struct ConnectionQuality {
+    epoch: Instant,
+    next_id: u32,
+    last_received: Option<Received>,
+    first_id: Option<u32>,
+    highest_id: u32,
+    received: u64,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl ConnectionQuality {
+    pub fn new(now: Instant) -> Self {
+        ConnectionQuality {
+            epoch: now,
+            next_id: 0,
+            last_received: None,
+            first_id: None,
+            highest_id: 0,
+            received: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    // Builds the next heartbeat to send, echoing the last one we received.
+    pub fn heartbeat(&mut self, now: Instant) -> Heartbeat {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let echo = self.last_received.as_ref().map(|r| HeartbeatEcho {
+            id: r.id,
+            sent_at_us: r.sent_at_us,
+            held_us: now.saturating_duration_since(r.at).as_micros() as u64,
+        });
+        Heartbeat {
+            id,
+            sent_at_us: self.micros(now),
+            echo,
+            stats: Some(self.stats()),
+        }
+    }
+
+    // Records a heartbeat from the peer and returns the RTT sample it
+    // produced, if it echoed one of ours.
+    pub fn on_heartbeat(&mut self, heartbeat: &Heartbeat, now: Instant) -> Option<Duration> {
+        // Ids are compared as serial numbers, so they can wrap, and one
+        // overtaken by the first we saw widens the range rather than
+        // looking like billions of heartbeats went missing
+        match self.first_id {
+            None => {
+                self.first_id = Some(heartbeat.id);
+                self.highest_id = heartbeat.id;
+            }
+            Some(first) => {
+                if seq_greater(first, heartbeat.id) {
+                    self.first_id = Some(heartbeat.id);
+                }
+                if seq_greater(heartbeat.id, self.highest_id) {
+                    self.highest_id = heartbeat.id;
+                }
+            }
+        }
+        self.received += 1;
+        self.last_received = Some(Received {
+            id: heartbeat.id,
+            sent_at_us: heartbeat.sent_at_us,
+            at: now,
+        });
+
+        let echo = heartbeat.echo?;
+        let elapsed = self.micros(now).checked_sub(echo.sent_at_us)?;
+        let sample = Duration::from_micros(elapsed.saturating_sub(echo.held_us));
+        self.add_sample(sample);
+        Some(sample)
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let expected = match self.first_id {
+            Some(first) => self.highest_id.wrapping_sub(first) as u64 + 1,
+            None => 0,
+        };
+        let loss_percent = if expected > 0 {
+            let lost = expected.saturating_sub(self.received);
+            lost as f64 * 100.0 / expected as f64
+        } else {
+            0.0
+        };
+        ConnectionStats {
+            rtt_ms: self.srtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            jitter_ms: self.rttvar.as_secs_f64() * 1000.0,
+            loss_percent,
+        }
+    }
+
+    // Same smoothing TCP uses (RFC 6298): srtt moves 1/8 of the way to each
+    // sample and the variation 1/4 of the way.
+    fn add_sample(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+
+    fn micros(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+}
@@ -0,0 +1,105 @@
+// The client's side of staying connected: noticing the server has gone quiet
+// and deciding when to try getting back in. This only keeps time. The client
+// does the sending, with the session token it was given, so a server that
+// still has the session hands it back.
+
+use std::time::{Duration, Instant};
+
+// The server sends a heartbeat every few seconds, so this long without
+// hearing anything means it, or the network in between, is gone.
+pub const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+// The wait after the first attempt, doubling after each one up to MAX_RETRY
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    // Waiting for the server to answer for the first time
+    Connecting,
+    Connected,
+    // Heard nothing for too long, and trying to get back in
+    Reconnecting,
+}
+
+#[derive(Debug, Clone)]
+pub struct Link {
+    state: LinkState,
+    timeout: Duration,
+    last_heard: Instant,
+    // When the next ConnectionInit is due, while not connected
+    next_attempt: Instant,
+    // Attempts since we were last connected
+    attempts: u32,
+}
+
+impl Link {
+    // Starts out connecting, with the first attempt due right away.
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Link {
+            state: LinkState::Connecting,
+            timeout,
+            last_heard: now,
+            next_attempt: now,
+            attempts: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // Something arrived from the server.
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = now;
+        self.state = LinkState::Connected;
+        self.attempts = 0;
+    }
+
+    // The server said it's stopping. Rather than waiting out the timeout,
+    // start reconnecting: once it says it should be back, or on the usual
+    // schedule if it didn't say.
+    pub fn server_stopped(&mut self, now: Instant, restart_in: Option<Duration>) {
+        self.state = LinkState::Reconnecting;
+        self.attempts = 0;
+        self.next_attempt = now + restart_in.unwrap_or(FIRST_RETRY);
+    }
+
+    // When `poll` next has something to do.
+    pub fn deadline(&self) -> Instant {
+        match self.state {
+            LinkState::Connected => self.last_heard + self.timeout,
+            _ => self.next_attempt,
+        }
+    }
+
+    // Whether it's time to send a ConnectionInit. Hearing nothing for
+    // longer than the timeout starts reconnecting, with the first attempt
+    // straight away.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.state == LinkState::Connected {
+            if now < self.last_heard + self.timeout {
+                return false;
+            }
+            self.state = LinkState::Reconnecting;
+            self.next_attempt = now;
+        }
+        if now < self.next_attempt {
+            return false;
+        }
+        self.next_attempt = now + retry_delay(self.attempts);
+        self.attempts += 1;
+        true
+    }
+}
+
+// How long to wait after attempt number `attempts` (counting from 0) before
+// the next one.
+pub fn retry_delay(attempts: u32) -> Duration {
+    FIRST_RETRY
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_RETRY)
+}
@@ -0,0 +1,424 @@
+// Plays back a packet capture recorded with GAME_UDP_CAPTURE.
+//
+// Usage: replay FILE [--speed X] [--headless]
+//
+// By default the board is redrawn from what the server told its players, at
+// the recorded pace sped up by --speed (0 for as fast as possible). With
+// --headless, the packets a server received are fed to a fresh in-memory
+// server on a manual clock instead, and what it sends back is compared with
+// what the recording says it sent.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, stdout},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crossterm::{cursor, execute};
+use game_udp::{
+    access::AccessFile,
+    capture::{Direction, Reader, Record, Role},
+    clock::Clock,
+    fragment::Reassembler,
+    metrics::Metrics,
+    render_board, render_hud, sanitize_name,
+    server::{Config, Server},
+    GamePacket, MessageType, MoveAck, PlayerJoin, PlayerProfile, PlayerState, PlayerUpdate,
+    Position, ServerStateSend,
+};
+use tracing::{info, warn};
+
+const LOG_FILE: &str = "replay.log";
+// Used for headless replays of recordings that never show the board size
+const DEFAULT_BOARD_SIZE: (u32, u32) = (80, 24);
+const SERVER_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 4000);
+
+#[derive(Debug, Clone)]
+struct Options {
+    file: PathBuf,
+    speed: f64,
+    headless: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut file = None;
+        let mut options = Options {
+            file: PathBuf::new(),
+            speed: 1.0,
+            headless: false,
+        };
+        while let Some(flag) = args.next() {
+            if flag == "--headless" {
+                options.headless = true;
+                continue;
+            }
+            if !flag.starts_with("--") {
+                if file.replace(PathBuf::from(&flag)).is_some() {
+                    return Err(format!("Unexpected argument {}", flag));
+                }
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--speed" => options.speed = parse_value(&flag, &value)?,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+        if !options.speed.is_finite() || options.speed < 0.0 {
+            return Err("--speed must be 0 or more".to_string());
+        }
+        options.file = file.ok_or("No capture file given")?;
+        Ok(options)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// Keeps the recording's pace, scaled by the replay speed.
+struct Pacer {
+    speed: f64,
+    started: Instant,
+}
+
+impl Pacer {
+    fn new(speed: f64) -> Self {
+        Pacer {
+            speed,
+            started: Instant::now(),
+        }
+    }
+
+    // Waits until a record made `at` into the recording is due.
+    async fn wait_for(&self, at: Duration) {
+        if self.speed > 0.0 {
+            let due = self.started + at.div_f64(self.speed);
+            tokio::time::sleep_until(due.into()).await;
+        }
+    }
+}
+
+// The board as the server described it to its players.
+#[derive(Default)]
+struct Board {
+    players: HashMap<String, PlayerState>,
+    // For client recordings, the name we asked for and the address the
+    // server knows us by, once the snapshot tells us
+    own_name: Option<String>,
+    own_addr: Option<String>,
+}
+
+impl Board {
+    // Applies a record if it's the server telling a player about the world.
+    // Returns whether the board changed.
+    fn apply(&mut self, role: Role, record: &Record) -> bool {
+        let packet = &record.packet;
+        let from_server = match role {
+            Role::Server => record.direction == Direction::Outbound,
+            Role::Client => record.direction == Direction::Inbound,
+        };
+        if !from_server {
+            if packet.msg_type == MessageType::ConnectionInit {
+                self.own_name = PlayerProfile::deserialize(&packet.payload)
+                    .and_then(|profile| sanitize_name(&profile.name));
+            }
+            return false;
+        }
+        match packet.msg_type {
+            MessageType::ConnectionInit => {
+                let Ok(snapshot) = ServerStateSend::deserialize(&packet.payload) else {
+                    return false;
+                };
+                if role == Role::Client {
+                    // Older captures don't say which player is ours
+                    self.own_addr = match &snapshot.session {
+                        Some(session) => Some(session.player.clone()),
+                        None => snapshot
+                            .players
+                            .iter()
+                            .find(|(_, p)| Some(&p.profile.name) == self.own_name.as_ref())
+                            .map(|(addr, _)| addr.clone()),
+                    };
+                }
+                for (addr, player) in snapshot.players {
+                    self.player(&addr, player.profile).position = player.position;
+                }
+            }
+            MessageType::PlayerJoin => {
+                let Some(join) = PlayerJoin::deserialize(&packet.payload) else {
+                    return false;
+                };
+                // A player who reconnected from a new address takes their
+                // old entry with them
+                if let Some(player) = join.previous.and_then(|p| self.players.remove(&p)) {
+                    self.players.insert(join.player.clone(), player);
+                }
+                // A known player re-announcing themselves changed their name
+                let player = self.player(&join.player, join.profile.clone());
+                player.profile = join.profile;
+            }
+            MessageType::PositionUpdate => {
+                let Some(update) = PlayerUpdate::deserialize(&packet.payload) else {
+                    return false;
+                };
+                let Some(player) = self.players.get_mut(&update.player) else {
+                    return false;
+                };
+                player.position = update.position;
+            }
+            MessageType::ConfirmPlayerMovement => {
+                let Some(ack) = MoveAck::deserialize(&packet.payload) else {
+                    return false;
+                };
+                // Acks go to the player who moved
+                let mover = match role {
+                    Role::Server => Some(&record.addr),
+                    Role::Client => self.own_addr.as_ref(),
+                };
+                let Some(player) = mover.and_then(|addr| self.players.get_mut(addr)) else {
+                    return false;
+                };
+                player.position = ack.position;
+            }
+            MessageType::PlayerLeft => {
+                let addr = String::from_utf8_lossy(&packet.payload);
+                return self.players.remove(addr.as_ref()).is_some();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn player(&mut self, addr: &str, profile: PlayerProfile) -> &mut PlayerState {
+        let number = self.players.len() as u32;
+        self.players
+            .entry(addr.to_string())
+            .or_insert_with(|| PlayerState::new(number, profile, String::new(), Instant::now()))
+    }
+}
+
+// Redraws the recorded board as the capture plays.
+async fn view(options: &Options) -> io::Result<()> {
+    let reader = Reader::open(&options.file)?;
+    let role = reader.role();
+    let pacer = Pacer::new(options.speed);
+    let mut board = Board::default();
+    let mut at = Duration::ZERO;
+    for record in reader {
+        let record = record?;
+        pacer.wait_for(record.at).await;
+        at = record.at;
+        // Drawing every change at full speed would take longer than the
+        // replay itself, so that only draws once at the end
+        if board.apply(role, &record) && options.speed > 0.0 {
+            draw(&board, at)?;
+        }
+    }
+    draw(&board, at)?;
+    execute!(stdout(), cursor::Show)?;
+    println!();
+    Ok(())
+}
+
+fn draw(board: &Board, at: Duration) -> io::Result<()> {
+    render_board(&board.players)?;
+    render_hud(&format!(
+        " {:.1}s  {} players ",
+        at.as_secs_f64(),
+        board.players.len()
+    ))
+}
+
+// Packet counts by message type.
+type Counts = BTreeMap<u8, u64>;
+
+// Feeds a server recording's inbound packets to a fresh server, keeping the
+// recorded timing, and reports where the two runs differ.
+async fn headless(options: &Options) -> io::Result<()> {
+    let reader = Reader::open(&options.file)?;
+    if reader.role() != Role::Server {
+        return Err(io::Error::other(
+            "headless replay needs a recording made by the server",
+        ));
+    }
+    let board_size = board_size(&options.file)?;
+    let clock = Clock::manual();
+    let mut config = Config::new(board_size);
+    config.clock = clock.clone();
+    let step = config.flush_interval;
+    let server = Server::in_memory(
+        SERVER_ADDR.into(),
+        AccessFile::in_memory(),
+        Arc::new(Metrics::new()),
+        config,
+    );
+
+    let pacer = Pacer::new(options.speed);
+    let started = clock.now();
+    let mut next_tick = started;
+    let mut next_heartbeat = started;
+    let mut inbound = Reassembler::new();
+    let mut outbound = Reassembler::new();
+    let mut board = Board::default();
+    let mut recorded = Counts::new();
+    let mut replayed = Counts::new();
+    for record in reader {
+        let record = record?;
+        pacer.wait_for(record.at).await;
+        // Run the server's timers up to when this packet was handled
+        let due = started + record.at;
+        while clock.now() + step <= due {
+            clock.advance(step);
+            let now = clock.now();
+            if now >= next_tick {
+                server.tick().await;
+                next_tick += server.state().lock().await.tick_interval;
+            }
+            if now >= next_heartbeat {
+                server.send_heartbeats().await;
+                next_heartbeat += server.config().heartbeat_interval;
+            }
+            server.flush().await?;
+            count_sent(&server, &mut outbound, now, &mut replayed);
+        }
+        clock.advance(due.saturating_duration_since(clock.now()));
+
+        match record.direction {
+            Direction::Inbound => {
+                let Ok(from) = record.addr.parse::<SocketAddr>() else {
+                    warn!(peer = %record.addr, "skipping packet from unparseable address");
+                    continue;
+                };
+                server
+                    .dispatch(&mut inbound, record.packet.serialize(), from)
+                    .await?;
+            }
+            Direction::Outbound => {
+                *recorded.entry(record.packet.msg_type as u8).or_default() += 1;
+                board.apply(Role::Server, &record);
+            }
+        }
+    }
+    server.flush().await?;
+    count_sent(&server, &mut outbound, clock.now(), &mut replayed);
+
+    println!("Replayed {:.1}s", (clock.now() - started).as_secs_f64());
+    println!("{:<24}{:>10}{:>10}", "packets sent", "recorded", "replayed");
+    let types: BTreeSet<_> = recorded.keys().chain(replayed.keys()).collect();
+    for msg_type in types {
+        let name = format!("{:?}", MessageType::from_byte(*msg_type).unwrap());
+        let recorded = recorded.get(msg_type).copied().unwrap_or(0);
+        let replayed = replayed.get(msg_type).copied().unwrap_or(0);
+        let marker = if recorded == replayed { "" } else { "  *" };
+        println!("{:<24}{:>10}{:>10}{}", name, recorded, replayed, marker);
+    }
+
+    let state = server.state().lock().await;
+    let mut addrs: Vec<_> = state.players.keys().chain(board.players.keys()).collect();
+    addrs.sort();
+    addrs.dedup();
+    println!(
+        "{:<24}{:>16}{:>16}",
+        "players at the end", "recorded", "replayed"
+    );
+    let mut diverged = 0;
+    for addr in addrs {
+        let recorded = board.players.get(addr).map(|p| &p.position);
+        let replayed = state.players.get(addr).map(|p| &p.position);
+        let marker = if recorded == replayed {
+            ""
+        } else {
+            diverged += 1;
+            "  *"
+        };
+        println!(
+            "{:<24}{:>16}{:>16}{}",
+            addr,
+            describe(recorded),
+            describe(replayed),
+            marker
+        );
+    }
+    info!(diverged, "headless replay finished");
+    if diverged > 0 {
+        println!("{} players ended up somewhere else", diverged);
+    }
+    Ok(())
+}
+
+// The board size the recorded server sent in its first snapshot.
+fn board_size(file: &Path) -> io::Result<(u32, u32)> {
+    for record in Reader::open(file)? {
+        let record = record?;
+        if record.direction == Direction::Outbound
+            && record.packet.msg_type == MessageType::ConnectionInit
+        {
+            if let Ok(snapshot) = ServerStateSend::deserialize(&record.packet.payload) {
+                return Ok(snapshot.board_size);
+            }
+        }
+    }
+    warn!(
+        ?DEFAULT_BOARD_SIZE,
+        "no snapshot in the recording, guessing the board size"
+    );
+    Ok(DEFAULT_BOARD_SIZE)
+}
+
+// Counts the whole packets in what the server sent since the last call.
+fn count_sent(
+    server: &Server,
+    reassembler: &mut Reassembler<SocketAddr>,
+    now: Instant,
+    counts: &mut Counts,
+) {
+    for (addr, datagram) in server.take_sent() {
+        for packet in GamePacket::messages(datagram).flatten() {
+            let packet = match packet.msg_type {
+                MessageType::Fragment => match reassembler.insert(addr, &packet, now) {
+                    Ok(Some(packet)) => packet,
+                    _ => continue,
+                },
+                _ => packet,
+            };
+            *counts.entry(packet.msg_type as u8).or_default() += 1;
+        }
+    }
+}
+
+fn describe(position: Option<&Position>) -> String {
+    match position {
+        Some(p) => format!("({}, {})", p.x, p.y),
+        None => "-".to_string(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            eprintln!("Usage: replay FILE [--speed X] [--headless]");
+            std::process::exit(2);
+        }
+    };
+    game_udp::logging::init(LOG_FILE)?;
+    info!(?options, "replaying capture");
+    if options.headless {
+        headless(&options).await?;
+    } else {
+        view(&options).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+// Sequence numbers for packets and inputs.
+//
+// Every session has two packet sequence spaces, one per direction: the
+// `seq_num` in a packet's header counts packets sent by that side of that
+// session only. Inputs (a player's moves) are numbered separately, in the
+// payload, so the server can confirm exactly which move it applied no matter
+// how many heartbeats or chat messages were sent in between.
+//
+// All comparisons use serial number arithmetic (RFC 1982), so counters can
+// wrap around u32::MAX without older numbers suddenly looking newer.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// True if `a` comes after `b`, allowing for wraparound.
+pub fn seq_greater(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+// Hands out sequence numbers for one direction of one session.
+#[derive(Debug)]
+pub struct SequenceCounter(AtomicU32);
+
+impl SequenceCounter {
+    pub fn new(first: u32) -> Self {
+        SequenceCounter(AtomicU32::new(first))
+    }
+
+    pub fn next(&self) -> u32 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Default for SequenceCounter {
+    fn default() -> Self {
+        SequenceCounter::new(1)
+    }
+}
+
+impl Clone for SequenceCounter {
+    fn clone(&self) -> Self {
+        SequenceCounter::new(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqCheck {
+    // First time we've seen this number; `newest` if nothing later has arrived
+    New { newest: bool },
+    Duplicate,
+    // Too far behind the newest packet to tell whether it's a duplicate
+    TooOld,
+}
+
+// Remembers which of the last 64 sequence numbers have arrived.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveWindow {
+    highest: Option<u32>,
+    // Bit n is set if `highest - n` has been received
+    seen: u64,
+}
+
+impl ReceiveWindow {
+    pub const SIZE: u32 = 64;
+
+    pub fn new() -> Self {
+        ReceiveWindow::default()
+    }
+
+    pub fn highest(&self) -> Option<u32> {
+        self.highest
+    }
+
+    pub fn check(&mut self, seq: u32) -> SeqCheck {
+        let Some(highest) = self.highest else {
+            self.highest = Some(seq);
+            self.seen = 1;
+            return SeqCheck::New { newest: true };
+        };
+        if seq_greater(seq, highest) {
+            let shift = seq.wrapping_sub(highest);
+            self.seen = if shift >= Self::SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = Some(seq);
+            return SeqCheck::New { newest: true };
+        }
+        let behind = highest.wrapping_sub(seq);
+        if behind >= Self::SIZE {
+            return SeqCheck::TooOld;
+        }
+        let bit = 1u64 << behind;
+        if self.seen & bit != 0 {
+            return SeqCheck::Duplicate;
+        }
+        self.seen |= bit;
+        SeqCheck::New { newest: false }
+    }
+}
@@ -0,0 +1,1306 @@
+// The game server: packet handlers, the background tasks that keep sessions
+// alive, and the receive loops that tie them to the sockets. The binary wires
+// this up to a terminal; tests run it on an ephemeral port.
+
+use std::{
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch, Mutex, MutexGuard},
+    task::{self, JoinSet},
+    time::{self, Instant as TokioInstant},
+};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+
+use crate::{
+    access::AccessFile,
+    capture::Recorder,
+    clock::Clock,
+    commands::{AdminCommand, ChatCommand},
+    fragment::{Reassembler, DEFAULT_MAX_DATAGRAM},
+    metrics::Metrics,
+    quality::Heartbeat,
+    render_board, restore_terminal, sanitize_name,
+    sequence::{seq_greater, ReceiveWindow, SeqCheck},
+    socket::{MeteredSocket, RecvPool},
+    storage::{self, PlayerRecord, Storage},
+    workers::{Handoff, Transport},
+    Chat, ConnectionRejected, ConnectionRequest, GamePacket, MessageType, MoveAck, MoveInput,
+    PlayerJoin, PlayerProfile, PlayerState, PlayerUpdate, Position, ServerShutdown, ServerState,
+    Session,
+};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub board_size: (u32, u32),
+    pub max_datagram: usize,
+    // Players we haven't heard from in this long are linkdead
+    pub heartbeat_timeout: Duration,
+    // How long a linkdead player's session is kept for them to reconnect
+    // to before they're dropped. Zero drops them straight away.
+    pub linkdead_grace: Duration,
+    pub heartbeat_interval: Duration,
+    // How often queued packets are batched up and sent
+    pub flush_interval: Duration,
+    // How often the access file is checked for edits made by hand
+    pub access_reload_interval: Duration,
+    // Draw the board on stdout, for running in a terminal
+    pub render: bool,
+    pub clock: Clock,
+    // Where every packet in and out is recorded, if anywhere
+    pub capture: Option<Arc<Recorder>>,
+    // Where player records are kept between runs, if anywhere
+    pub storage: Option<Arc<dyn Storage>>,
+    // How often everyone's record is saved while they play
+    pub autosave_interval: Duration,
+    // Seeds session tokens, for deterministic runs. Random when None.
+    pub session_seed: Option<u64>,
+}
+
+impl Config {
+    pub fn new(board_size: (u32, u32)) -> Self {
+        Config {
+            board_size,
+            max_datagram: DEFAULT_MAX_DATAGRAM,
+            heartbeat_timeout: Duration::from_secs(10),
+            linkdead_grace: Duration::from_secs(30),
+            heartbeat_interval: Duration::from_secs(3),
+            flush_interval: Duration::from_millis(50),
+            access_reload_interval: Duration::from_secs(2),
+            render: false,
+            clock: Clock::default(),
+            capture: None,
+            storage: None,
+            autosave_interval: Duration::from_secs(60),
+            session_seed: None,
+        }
+    }
+}
+
+// Shared handles the packet handlers work with. Each worker has its own copy.
+#[derive(Clone)]
+pub struct Server {
+    transport: Transport,
+    // Whoever needs both locks takes state first, then access
+    state: Arc<Mutex<ServerState>>,
+    access: Arc<Mutex<AccessFile>>,
+    metrics: Arc<Metrics>,
+    config: Arc<Config>,
+    // Set once something asks the server to stop
+    stopping: Arc<watch::Sender<Option<ServerShutdown>>>,
+}
+
+impl Server {
+    // Takes one socket per worker. Returns the server along with each
+    // worker's handoff receiver, to pass to `run`.
+    pub fn new(
+        sockets: Vec<UdpSocket>,
+        access: AccessFile,
+        metrics: Arc<Metrics>,
+        config: Config,
+    ) -> (Self, Vec<mpsc::UnboundedReceiver<Handoff>>) {
+        let sockets = sockets
+            .into_iter()
+            .map(|socket| MeteredSocket::new(socket, Arc::clone(&metrics)))
+            .collect();
+        Self::with_sockets(sockets, access, metrics, config)
+    }
+
+    // A server on one in-memory socket pretending to be bound to `addr`, for
+    // simulations. Nothing runs on its own: the caller dispatches datagrams
+    // and calls the step functions, and collects what was sent with
+    // take_sent.
+    pub fn in_memory(
+        addr: SocketAddr,
+        access: AccessFile,
+        metrics: Arc<Metrics>,
+        config: Config,
+    ) -> Self {
+        let socket = MeteredSocket::in_memory(addr, Arc::clone(&metrics));
+        let (server, _) = Self::with_sockets(vec![socket], access, metrics, config);
+        // With no workers to hand packets over to, everything goes straight
+        // to the one socket
+        Server {
+            transport: server.transport.for_worker(0),
+            ..server
+        }
+    }
+
+    fn with_sockets(
+        sockets: Vec<MeteredSocket>,
+        access: AccessFile,
+        metrics: Arc<Metrics>,
+        config: Config,
+    ) -> (Self, Vec<mpsc::UnboundedReceiver<Handoff>>) {
+        let sockets = sockets
+            .into_iter()
+            .map(|socket| Arc::new(socket.with_max_datagram(config.max_datagram)))
+            .collect();
+        let (transport, handoffs) = Transport::new(sockets, config.capture.clone());
+        let mut state = ServerState::new(config.board_size);
+        if let Some(seed) = config.session_seed {
+            state.seed_sessions(seed);
+        }
+        state.storage = config.storage.clone();
+        let server = Server {
+            transport,
+            state: Arc::new(Mutex::new(state)),
+            access: Arc::new(Mutex::new(access)),
+            metrics,
+            config: Arc::new(config),
+            stopping: Arc::new(watch::Sender::new(None)),
+        };
+        (server, handoffs)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.socket(0).local_addr()
+    }
+
+    pub fn state(&self) -> &Arc<Mutex<ServerState>> {
+        &self.state
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn now(&self) -> Instant {
+        self.config.clock.now()
+    }
+
+    // Datagrams an in-memory server has sent since the last call.
+    pub fn take_sent(&self) -> Vec<(SocketAddr, Bytes)> {
+        self.transport.socket(0).take_sent()
+    }
+
+    // Locks the game state, recording how long we waited for it.
+    async fn lock_state(&self) -> MutexGuard<'_, ServerState> {
+        let started = Instant::now();
+        let state = self.state.lock().await;
+        self.metrics.record_lock_wait(started.elapsed());
+        state
+    }
+
+    // Runs a command from the admin console, returning what to tell the
+    // admin. Printing it is up to the console.
+    pub async fn admin(&self, command: AdminCommand) -> io::Result<String> {
+        if let AdminCommand::Shutdown { restart_in } = command {
+            self.stop(ServerShutdown {
+                reason: match restart_in {
+                    Some(_) => "Server restarting".to_string(),
+                    None => "Server shutting down".to_string(),
+                },
+                restart_in_secs: restart_in.map(|wait| wait.as_secs()),
+            });
+            return Ok("Shutting down".to_string());
+        }
+        let handled = {
+            let mut state = self.state.lock().await;
+            let mut access = self.access.lock().await;
+            handle_admin_command(&self.transport, &mut state, &mut access, command).await
+        };
+        // Kicks and bans save the records of whoever they removed
+        if let Err(e) = self.persist().await {
+            error!(error = %e, "failed to write player records");
+        }
+        handled
+    }
+
+    // Handles a datagram as if the first worker had received it, without
+    // running the receive loops. Replies are queued on that worker's socket.
+    pub async fn dispatch(
+        &self,
+        reassembler: &mut Reassembler<SocketAddr>,
+        datagram: Bytes,
+        from: SocketAddr,
+    ) -> io::Result<()> {
+        let server = Server {
+            transport: self.transport.for_worker(0),
+            ..self.clone()
+        };
+        handle_datagram(&server, reassembler, datagram, from).await
+    }
+
+    // Marks players whose heartbeats stopped as linkdead, drops those who
+    // stayed that way past the grace period, and redraws the board.
+    pub async fn tick(&self) {
+        let tick_started = Instant::now();
+        let mut state = self.state.lock().await;
+        let now = self.now();
+        let grace = self.config.linkdead_grace;
+        let mut timed_out = Vec::new();
+        let mut expired = Vec::new();
+        for (addr, player) in &state.players {
+            match player.linkdead_since {
+                Some(since) => {
+                    if now.duration_since(since) > grace {
+                        let session = player.player_number;
+                        info!(session, peer = %addr, "linkdead session expired");
+                        expired.push((session, addr.clone()));
+                    }
+                }
+                None => {
+                    if now.duration_since(player.last_heartbeat) > self.config.heartbeat_timeout {
+                        info!(session = player.player_number, peer = %addr, "heartbeat timed out");
+                        timed_out.push((player.player_number, addr.clone()));
+                    }
+                }
+            }
+        }
+        self.metrics
+            .record_heartbeat_timeouts(timed_out.len() as u64);
+        if grace.is_zero() {
+            expired.append(&mut timed_out);
+        }
+        // The map's order changes from run to run; join order doesn't
+        timed_out.sort();
+        expired.sort();
+        let removing = !expired.is_empty();
+        for (_, addr) in timed_out {
+            if let Err(e) = mark_linkdead(&self.transport, &mut state, &addr, now).await {
+                warn!(peer = %addr, error = %e, "failed to announce linkdead player");
+            }
+        }
+        for (_, id) in expired {
+            if let Err(e) = remove_player(&self.transport, &mut state, &id).await {
+                warn!(peer = %id, error = %e, "failed to send PlayerLeft");
+            }
+        }
+        if self.config.render {
+            if let Err(e) = render_board(&state.players) {
+                warn!(error = %e, "failed to draw the board");
+            }
+        }
+        drop(state);
+        self.metrics.record_tick(tick_started.elapsed());
+        if removing {
+            if let Err(e) = self.persist().await {
+                error!(error = %e, "failed to write player records");
+            }
+        }
+    }
+
+    // Sends every connected player a heartbeat to answer.
+    pub async fn send_heartbeats(&self) {
+        let mut state = self.state.lock().await;
+        let now = self.now();
+        // Nobody's listening at a linkdead player's address
+        let live = state
+            .players
+            .iter_mut()
+            .filter(|(_, player)| player.linkdead_since.is_none());
+        for (addr, player) in live {
+            let heartbeat = player.quality.heartbeat(now);
+            let reply = GamePacket::new(
+                MessageType::Heartbeat,
+                player.send_seq.next(),
+                heartbeat.serialize(),
+            );
+            if let Err(e) = self.transport.send_packet(&reply, addr).await {
+                warn!(peer = %addr, error = %e, "failed to send heartbeat");
+            }
+        }
+    }
+
+    // Saves everyone's record, if the server keeps records.
+    pub async fn save(&self) -> io::Result<()> {
+        {
+            let state = self.state.lock().await;
+            save_players(&state, state.players.keys().map(String::as_str))?;
+        }
+        self.persist().await
+    }
+
+    // Writes the saved records out on a blocking thread, so the disk never
+    // holds up the state lock or the runtime.
+    async fn persist(&self) -> io::Result<()> {
+        let Some(storage) = self.config.storage.clone() else {
+            return Ok(());
+        };
+        task::spawn_blocking(move || storage.persist())
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    // Sends everything queued since the last flush.
+    pub async fn flush(&self) -> io::Result<()> {
+        self.transport.flush().await
+    }
+
+    // Asks `run` to stop, telling everyone why with `notice`.
+    pub fn stop(&self, notice: ServerShutdown) {
+        self.stopping.send_replace(Some(notice));
+    }
+
+    // Tells every connected player the server is going away, then saves
+    // their records and writes out whatever is still buffered. Packets
+    // queued before this go out ahead of the notice.
+    pub async fn shutdown(&self, notice: &ServerShutdown) -> io::Result<()> {
+        let state = self.state.lock().await;
+        info!(
+            reason = %notice.reason,
+            restart_in_secs = ?notice.restart_in_secs,
+            players = state.players.len(),
+            "shutting down"
+        );
+        if let Err(e) = self.transport.flush().await {
+            warn!(error = %e, "failed to flush outgoing packets");
+        }
+        let live = state
+            .players
+            .iter()
+            .filter(|(_, player)| player.linkdead_since.is_none());
+        for (addr, _) in live {
+            let packet = state.packet_to(addr, MessageType::ServerShutdown, notice.serialize());
+            if let Err(e) = self.transport.send_packet(&packet, addr).await {
+                warn!(peer = %addr, error = %e, "failed to send ServerShutdown");
+            }
+        }
+        let saved = save_players(&state, state.players.keys().map(String::as_str));
+        drop(state);
+        let saved = saved.and(self.persist().await);
+        // Picks up the capture of the notices just sent
+        if let Err(e) = self.transport.flush().await {
+            warn!(error = %e, "failed to flush outgoing packets");
+        }
+        self.metrics.log_totals();
+        if self.config.render {
+            restore_terminal()?;
+        }
+        saved
+    }
+
+    // Runs the receive loops and background tasks until one of them fails
+    // or `stop` is called. Either way it waits for the tasks to end, then
+    // shuts down. Dropping the future stops the tasks without a word to
+    // anyone.
+    pub async fn run(self, handoffs: Vec<mpsc::UnboundedReceiver<Handoff>>) -> io::Result<()> {
+        let mut stopping = self.stopping.subscribe();
+        let mut tasks = JoinSet::new();
+        tasks.spawn(reload_access(self.clone()));
+        tasks.spawn(cleanup(self.clone()));
+        tasks.spawn(ping(self.clone()));
+        tasks.spawn(flush(self.clone()));
+        if self.config.storage.is_some() {
+            tasks.spawn(autosave(self.clone()));
+        }
+        for (worker, handoff) in handoffs.into_iter().enumerate() {
+            let server = Server {
+                transport: self.transport.for_worker(worker),
+                ..self.clone()
+            };
+            tasks.spawn(
+                run_worker(server, worker, handoff).instrument(info_span!("worker", id = worker)),
+            );
+        }
+        let failed = loop {
+            tokio::select! {
+                joined = tasks.join_next() => match joined {
+                    Some(Ok(Ok(()))) => {}
+                    Some(Ok(Err(e))) => break Some(e),
+                    Some(Err(e)) => break Some(io::Error::other(e)),
+                    None => return Ok(()),
+                },
+                _ = stopping.wait_for(Option::is_some) => break None,
+            }
+        };
+        // Nothing else is received or ticked from here on
+        tasks.shutdown().await;
+        let Some(e) = failed else {
+            let notice = self.stopping.borrow().clone().unwrap();
+            return self.shutdown(&notice).await;
+        };
+        // Players still hear about it and keep their records, and the
+        // terminal is put back, before the error goes up
+        error!(error = %e, "server task failed");
+        let notice = ServerShutdown {
+            reason: "Server error".to_string(),
+            restart_in_secs: None,
+        };
+        if let Err(e) = self.shutdown(&notice).await {
+            error!(error = %e, "failed to shut down cleanly");
+        }
+        Err(e)
+    }
+}
+
+// Reloads the ban lists when the file is edited by hand.
+async fn reload_access(server: Server) -> io::Result<()> {
+    let mut interval = time::interval(server.config.access_reload_interval);
+    loop {
+        interval.tick().await;
+        let reloaded = {
+            // State before access, like everywhere else
+            let mut state = server.state.lock().await;
+            let mut access = server.access.lock().await;
+            match access.reload_if_changed() {
+                Ok(true) => {
+                    info!(path = %access.path.display(), "reloaded access list");
+                    if let Err(e) = enforce_access(&server.transport, &mut state, &access).await {
+                        error!(error = %e, "failed to apply reloaded access list");
+                    }
+                    true
+                }
+                Ok(false) => false,
+                Err(e) => {
+                    error!(path = %access.path.display(), error = %e, "failed to reload access list");
+                    false
+                }
+            }
+        };
+        // Anyone the new list shut out had their record saved
+        if reloaded {
+            if let Err(e) = server.persist().await {
+                error!(error = %e, "failed to write player records");
+            }
+        }
+    }
+}
+
+// Runs a server tick whenever the tick interval comes round.
+async fn cleanup(server: Server) -> io::Result<()> {
+    let mut tick_interval = server.state.lock().await.tick_interval;
+    let mut interval = time::interval(tick_interval);
+
+    loop {
+        interval.tick().await;
+        // Pick up tick rate changes made from the admin console
+        let current = server.state.lock().await.tick_interval;
+        if current != tick_interval {
+            tick_interval = current;
+            interval = time::interval_at(TokioInstant::now() + tick_interval, tick_interval);
+        }
+        server.tick().await;
+    }
+}
+
+// Sends heartbeats whenever the heartbeat interval comes round.
+async fn ping(server: Server) -> io::Result<()> {
+    let mut interval = time::interval(server.config.heartbeat_interval);
+    loop {
+        interval.tick().await;
+        server.send_heartbeats().await;
+    }
+}
+
+// Sends everything queued during the last tick.
+async fn flush(server: Server) -> io::Result<()> {
+    let mut interval = time::interval(server.config.flush_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = server.flush().await {
+            warn!(error = %e, "failed to flush outgoing packets");
+        }
+    }
+}
+
+// Saves everyone's record whenever the autosave interval comes round.
+async fn autosave(server: Server) -> io::Result<()> {
+    let mut interval = time::interval(server.config.autosave_interval);
+    // The first tick is immediate, and there's nothing new to save yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = server.save().await {
+            error!(error = %e, "failed to save player records");
+        }
+    }
+}
+
+// Receives on one socket and handles what arrives, along with packets other
+// workers hand over for this worker's sessions.
+async fn run_worker(
+    server: Server,
+    worker: usize,
+    mut handoff: mpsc::UnboundedReceiver<Handoff>,
+) -> io::Result<()> {
+    let socket = Arc::clone(server.transport.socket(worker));
+    let mut pool = RecvPool::new();
+    let mut reassembler = Reassembler::<SocketAddr>::new();
+    loop {
+        tokio::select! {
+            received = socket.recv_batch(&mut pool) => {
+                for (datagram, client_addr) in received? {
+                    handle_datagram(&server, &mut reassembler, datagram, client_addr).await?;
+                }
+            }
+            Some((addr, packet)) = handoff.recv() => socket.queue_packet(packet, &addr),
+        }
+    }
+}
+
+// Unpacks a datagram, reassembling fragments, and handles each packet in it.
+async fn handle_datagram(
+    server: &Server,
+    reassembler: &mut Reassembler<SocketAddr>,
+    datagram: Bytes,
+    client_addr: SocketAddr,
+) -> io::Result<()> {
+    let len = datagram.len();
+    for message in GamePacket::messages(datagram) {
+        let Some(mut packet) = message else {
+            server.metrics.record_decode_failure();
+            warn!(peer = %client_addr, len, "dropping undecodable message");
+            continue;
+        };
+        if let MessageType::Fragment = packet.msg_type {
+            match reassembler.insert(client_addr, &packet, server.now()) {
+                Ok(Some(complete)) => packet = complete,
+                Ok(None) => continue,
+                Err(e) => {
+                    server.metrics.record_decode_failure();
+                    let seq = packet.seq_num;
+                    warn!(peer = %client_addr, seq, error = %e, "dropping fragment");
+                    continue;
+                }
+            }
+        }
+        server.metrics.record_packet_received(packet.msg_type);
+        server
+            .transport
+            .record_inbound(&packet, &client_addr.to_string());
+        let session = server
+            .lock_state()
+            .await
+            .players
+            .get(&client_addr.to_string())
+            .map(|p| p.player_number);
+        let span = info_span!(
+            "session",
+            id = session,
+            peer = %client_addr,
+            msg_type = ?packet.msg_type,
+            seq = packet.seq_num
+        );
+        handle_packet(server, packet, client_addr)
+            .instrument(span)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn handle_packet(
+    server: &Server,
+    packet: GamePacket,
+    client_addr: SocketAddr,
+) -> io::Result<()> {
+    let transport = &server.transport;
+    let access = &server.access;
+    let client_addr_str = client_addr.to_string();
+    trace!(len = packet.payload.len(), "received packet");
+
+    // ConnectionInit starts a new session, so it's the one message that isn't
+    // checked against the previous session's sequence numbers. Everything
+    // else needs a session: without one the sender was never let in, or
+    // has been kicked or banned since.
+    if !matches!(packet.msg_type, MessageType::ConnectionInit) {
+        let mut state = server.lock_state().await;
+        let Some(player) = state.players.get_mut(&client_addr_str) else {
+            debug!(msg_type = ?packet.msg_type, "dropping packet without a session");
+            return Ok(());
+        };
+        match player.recv_window.check(packet.seq_num) {
+            SeqCheck::New { .. } => {}
+            check => {
+                debug!(?check, "dropping repeated or stale packet");
+                return Ok(());
+            }
+        }
+        // A linkdead player whose connection came back by itself
+        if player.linkdead_since.take().is_some() {
+            info!("linkdead player is back");
+            player.last_heartbeat = server.now();
+            let text = format!("{} reconnected", player.profile.name);
+            announce(transport, &state, &client_addr_str, &text).await?;
+        }
+    }
+
+    match packet.msg_type {
+        MessageType::PositionUpdate => {
+            let Some(input) = MoveInput::deserialize(&packet.payload) else {
+                debug!("dropping malformed move");
+                return Ok(());
+            };
+            let mut state = server.lock_state().await;
+            let board_size = state.board_size;
+            let Some(player) = state.players.get_mut(&client_addr_str) else {
+                debug!("dropping move from unknown player");
+                return Ok(());
+            };
+            if let Some(last) = player.last_input_seq {
+                if !seq_greater(input.input_seq, last) {
+                    debug!(input_seq = input.input_seq, last, "dropping stale move");
+                    return Ok(());
+                }
+            }
+            player.last_input_seq = Some(input.input_seq);
+            player.last_heartbeat = server.now();
+
+            let position = input.position;
+            if !on_board(board_size, &position) {
+                // Invalid move, reset player position
+                let ack = MoveAck {
+                    input_seq: input.input_seq,
+                    position: player.position.clone(),
+                };
+                let reply = state.packet_to(
+                    &client_addr_str,
+                    MessageType::ConfirmPlayerMovement,
+                    ack.serialize(),
+                );
+                transport.queue_packet(reply, &client_addr_str);
+                return Ok(());
+            }
+            // Update player position
+            player.position = position.clone();
+            player.stats.moves += 1;
+
+            // Notify all players about the move, encoding the update once for
+            // every recipient
+            let update = Bytes::from(
+                PlayerUpdate {
+                    player: client_addr_str.clone(),
+                    position: position.clone(),
+                }
+                .serialize(),
+            );
+            for addr in state.players.keys() {
+                let packet = if addr != &client_addr_str {
+                    state.packet_to(addr, MessageType::PositionUpdate, update.clone())
+                } else {
+                    let ack = MoveAck {
+                        input_seq: input.input_seq,
+                        position: position.clone(),
+                    };
+                    state.packet_to(addr, MessageType::ConfirmPlayerMovement, ack.serialize())
+                };
+                transport.queue_packet(packet, addr);
+            }
+            if server.config.render {
+                if let Err(e) = render_board(&state.players) {
+                    warn!(error = %e, "failed to draw the board");
+                }
+            }
+        }
+        MessageType::ChatMessage => {
+            if let Ok(chat) = serde_json::from_slice::<Chat>(&packet.payload) {
+                debug!(text = %chat.text, "chat message");
+
+                if let Some(command) = ChatCommand::parse(&chat.text) {
+                    let mut state = server.lock_state().await;
+                    match command {
+                        Ok(command) => {
+                            let access = access.lock().await;
+                            handle_chat_command(
+                                transport,
+                                &mut state,
+                                &access,
+                                &client_addr_str,
+                                command,
+                            )
+                            .await?
+                        }
+                        Err(usage) => {
+                            send_chat(transport, &state, &client_addr_str, &usage).await?
+                        }
+                    }
+                    return Ok(());
+                }
+
+                // Broadcast chat to all players
+                let payload = Bytes::from(serde_json::to_vec(&chat).unwrap());
+                let mut state = server.lock_state().await;
+                if let Some(player) = state.players.get_mut(&client_addr_str) {
+                    player.stats.chat_messages += 1;
+                }
+                for addr in state.players.keys() {
+                    let packet = state.packet_to(addr, MessageType::ChatMessage, payload.clone());
+                    transport.queue_packet(packet, addr);
+                }
+            }
+        }
+        MessageType::Heartbeat => {
+            // Update heartbeat
+            let mut state = server.lock_state().await;
+            if let Some(player) = state.players.get_mut(&client_addr_str) {
+                player.last_heartbeat = server.now();
+                if let Some(heartbeat) = Heartbeat::deserialize(&packet.payload) {
+                    if let Some(rtt) = player.quality.on_heartbeat(&heartbeat, server.now()) {
+                        trace!(rtt_us = rtt.as_micros() as u64, "rtt sample");
+                    }
+                    if let Some(stats) = heartbeat.stats {
+                        trace!(?stats, "client connection stats");
+                    }
+                }
+            }
+        }
+        MessageType::ConnectionInit => {
+            let mut state = server.lock_state().await;
+
+            let request = ConnectionRequest::deserialize(&packet.payload);
+            // A reconnecting client gets its session back if we still have
+            // it. So does one that sends ConnectionInit again without the
+            // token, say because the reply was lost: there's only ever one
+            // player per address.
+            let resumed = request
+                .as_ref()
+                .and_then(|r| r.session.as_deref())
+                .and_then(|token| state.find_session(token))
+                .or_else(|| {
+                    state
+                        .players
+                        .contains_key(&client_addr_str)
+                        .then(|| client_addr_str.clone())
+                });
+            let requested = request.map(|r| r.profile);
+            // A new player is checked under the name they asked for and the
+            // one they'd be given, which differs when theirs is taken
+            let (requested_name, joining) = match &resumed {
+                Some(addr) => (state.players[addr].profile.name.clone(), None),
+                None => {
+                    let name = requested
+                        .as_ref()
+                        .and_then(|p| sanitize_name(&p.name))
+                        .unwrap_or_default();
+                    let player_number = state.next_player_number();
+                    let profile = state.resolve_profile(requested, player_number);
+                    (name, Some((player_number, profile)))
+                }
+            };
+            let allowed = {
+                let access = access.lock().await;
+                let assigned = joining.as_ref().map(|(_, profile)| profile.name.as_str());
+                [
+                    Some(requested_name.as_str()).filter(|name| !name.is_empty()),
+                    assigned,
+                ]
+                .into_iter()
+                .flatten()
+                .try_for_each(|name| access.list.check(client_addr.ip(), name))
+            };
+            if let Err(reason) = allowed {
+                info!(name = %requested_name, %reason, "connection rejected");
+                send_rejection(transport, &state, &client_addr_str, &reason).await?;
+                return Ok(());
+            }
+            if let Some(old_addr) = resumed {
+                let displaced = resume_session(
+                    server,
+                    &mut state,
+                    &old_addr,
+                    &client_addr_str,
+                    packet.seq_num,
+                )
+                .await?;
+                drop(state);
+                if displaced {
+                    if let Err(e) = server.persist().await {
+                        error!(error = %e, "failed to write player records");
+                    }
+                }
+                return Ok(());
+            }
+
+            // Send current state to new player
+            let (player_number, profile) = joining.expect("only resumed sessions have no profile");
+            info!(session = player_number, name = %profile.name, "player joined");
+            let token = state.new_session_token();
+            let mut player =
+                PlayerState::new(player_number, profile.clone(), token.clone(), server.now());
+            player.recv_window.check(packet.seq_num);
+            player.keeps_record = keeps_record(&requested_name, &profile);
+            // Returning players pick up where they left off last time
+            let record = if player.keeps_record {
+                load_record(&state, &profile)
+            } else {
+                None
+            };
+            match record {
+                Some(record) => {
+                    debug!(position = ?record.position, "restored saved player");
+                    if on_board(state.board_size, &record.position) {
+                        player.position = record.position;
+                    }
+                    player.stats = record.stats;
+                }
+                None => player.stats.first_seen = storage::unix_now(),
+            }
+            player.stats.sessions += 1;
+            let position = player.position.clone();
+            state.players.insert(client_addr_str.clone(), player);
+            transport.pin(&client_addr_str);
+            let mut snapshot = state.snapshot();
+            snapshot.session = Some(Session {
+                token,
+                player: client_addr_str.clone(),
+                resumed: false,
+            });
+            let reply = state.packet_to(
+                &client_addr_str,
+                MessageType::ConnectionInit,
+                snapshot.serialize(),
+            );
+            transport.queue_packet(reply, &client_addr_str);
+
+            // Notify all players about the new player
+            let join = Bytes::from(
+                PlayerJoin {
+                    player: client_addr_str.clone(),
+                    profile: profile.clone(),
+                    previous: None,
+                }
+                .serialize(),
+            );
+            let update = Bytes::from(
+                PlayerUpdate {
+                    player: client_addr_str.clone(),
+                    position: position.clone(),
+                }
+                .serialize(),
+            );
+            for addr in state.players.keys() {
+                if addr != &client_addr_str {
+                    let packet = state.packet_to(addr, MessageType::PlayerJoin, join.clone());
+                    transport.queue_packet(packet, addr);
+                    // Everyone starts out at the origin unless told otherwise
+                    if position != Position::new(0, 0, 0) {
+                        let packet =
+                            state.packet_to(addr, MessageType::PositionUpdate, update.clone());
+                        transport.queue_packet(packet, addr);
+                    }
+                }
+            }
+
+            // Send welcome message
+            let welcome = format!("Welcome to the server, {}!", profile.name);
+            send_chat(transport, &state, &client_addr_str, &welcome).await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Hands a session back to a reconnecting client, keeping its position. A
+// client that comes back from a new address is moved there. Either way,
+// everyone else sees the player reconnect rather than leave and join again.
+// Whoever else held the new address is removed, and true returned so their
+// record gets written once the state lock is released.
+async fn resume_session(
+    server: &Server,
+    state: &mut ServerState,
+    old_addr: &str,
+    new_addr: &str,
+    seq_num: u32,
+) -> io::Result<bool> {
+    let transport = &server.transport;
+    let displaced = old_addr != new_addr && state.players.contains_key(new_addr);
+    if displaced {
+        info!(peer = %new_addr, "session resumed over another player");
+        remove_player(transport, state, new_addr).await?;
+    }
+    let mut player = state.players.remove(old_addr).unwrap();
+    info!(session = player.player_number, from = %old_addr, "session resumed");
+    // The client starts its receive window over when it reconnects, and
+    // we do the same
+    player.recv_window = ReceiveWindow::new();
+    player.recv_window.check(seq_num);
+    player.last_heartbeat = server.now();
+    let was_linkdead = player.linkdead_since.take().is_some();
+    let profile = player.profile.clone();
+    let token = player.session_token.clone();
+    state.players.insert(new_addr.to_string(), player);
+
+    if old_addr != new_addr {
+        transport.unpin(old_addr);
+        transport.pin(new_addr);
+        let join = Bytes::from(
+            PlayerJoin {
+                player: new_addr.to_string(),
+                profile: profile.clone(),
+                previous: Some(old_addr.to_string()),
+            }
+            .serialize(),
+        );
+        for addr in state.players.keys().filter(|&addr| addr != new_addr) {
+            let packet = state.packet_to(addr, MessageType::PlayerJoin, join.clone());
+            transport.queue_packet(packet, addr);
+        }
+    }
+    if was_linkdead || old_addr != new_addr {
+        announce(
+            transport,
+            state,
+            new_addr,
+            &format!("{} reconnected", profile.name),
+        )
+        .await?;
+    }
+
+    let mut snapshot = state.snapshot();
+    snapshot.session = Some(Session {
+        token,
+        player: new_addr.to_string(),
+        resumed: true,
+    });
+    let reply = state.packet_to(new_addr, MessageType::ConnectionInit, snapshot.serialize());
+    transport.queue_packet(reply, new_addr);
+    let welcome = format!("Welcome back, {}!", profile.name);
+    send_chat(transport, state, new_addr, &welcome).await?;
+    Ok(displaced)
+}
+
+// Keeps a timed-out player's session for them to reconnect to, and lets
+// everyone else know.
+async fn mark_linkdead(
+    transport: &Transport,
+    state: &mut ServerState,
+    addr: &str,
+    now: Instant,
+) -> io::Result<()> {
+    let Some(player) = state.players.get_mut(addr) else {
+        return Ok(());
+    };
+    info!(session = player.player_number, peer = %addr, "player linkdead");
+    player.linkdead_since = Some(now);
+    let text = format!("{} lost connection", player.profile.name);
+    announce(transport, state, addr, &text).await
+}
+
+// Tells everyone but `about` something that happened to them.
+async fn announce(
+    transport: &Transport,
+    state: &ServerState,
+    about: &str,
+    text: &str,
+) -> io::Result<()> {
+    for addr in state.players.keys().filter(|&addr| addr != about) {
+        send_chat(transport, state, addr, text).await?;
+    }
+    Ok(())
+}
+
+async fn send_chat(
+    transport: &Transport,
+    state: &ServerState,
+    addr: &str,
+    text: &str,
+) -> io::Result<()> {
+    let chat = Chat {
+        text: text.to_string(),
+    };
+    let packet = state.packet_to(
+        addr,
+        MessageType::ChatMessage,
+        serde_json::to_vec(&chat).unwrap(),
+    );
+    transport.queue_packet(packet, addr);
+    Ok(())
+}
+
+// Whether a position is somewhere players may stand.
+fn on_board(board_size: (u32, u32), position: &Position) -> bool {
+    let (width, height) = (board_size.0 as i32, board_size.1 as i32);
+    position.x >= -width / 2
+        && position.x < width / 2
+        && position.y.saturating_sub(2) >= -height / 2
+        && position.y < height / 2
+}
+
+// Whether a player who asked for `requested` (already sanitized) and was
+// given `profile` gets a record kept for them.
+fn keeps_record(requested: &str, profile: &PlayerProfile) -> bool {
+    !requested.is_empty() && profile.name == requested
+}
+
+// The saved record for a player about to join, if the server keeps records.
+fn load_record(state: &ServerState, profile: &PlayerProfile) -> Option<PlayerRecord> {
+    let storage = state.storage.as_ref()?;
+    storage
+        .load(&storage::identity(profile))
+        .unwrap_or_else(|e| {
+            error!(error = %e, "failed to load player record");
+            None
+        })
+}
+
+// Hands these players' records to storage, if the server keeps records.
+// Nothing reaches the disk until the next persist.
+fn save_players<'a>(
+    state: &ServerState,
+    addrs: impl IntoIterator<Item = &'a str>,
+) -> io::Result<()> {
+    let Some(storage) = &state.storage else {
+        return Ok(());
+    };
+    let now = storage::unix_now();
+    let records = addrs
+        .into_iter()
+        .filter_map(|addr| state.players.get(addr))
+        .filter(|player| player.keeps_record)
+        .map(|player| {
+            let mut stats = player.stats.clone();
+            stats.last_seen = now;
+            let record = PlayerRecord {
+                profile: player.profile.clone(),
+                position: player.position.clone(),
+                stats,
+            };
+            (storage::identity(&player.profile), record)
+        })
+        .collect();
+    storage.save(records)
+}
+
+// Drops a player and tells everyone else they left.
+async fn remove_player(
+    transport: &Transport,
+    state: &mut ServerState,
+    addr: &str,
+) -> io::Result<()> {
+    if let Err(e) = save_players(state, [addr]) {
+        error!(peer = %addr, error = %e, "failed to save player record");
+    }
+    let Some(player) = state.players.remove(addr) else {
+        return Ok(());
+    };
+    info!(session = player.player_number, peer = %addr, "player removed");
+    transport.unpin(addr);
+    let left = Bytes::copy_from_slice(addr.as_bytes());
+    for other in state.players.keys() {
+        let packet = state.packet_to(other, MessageType::PlayerLeft, left.clone());
+        transport.queue_packet(packet, other);
+    }
+    Ok(())
+}
+
+async fn send_rejection(
+    transport: &Transport,
+    state: &ServerState,
+    addr: &str,
+    reason: &str,
+) -> io::Result<()> {
+    let payload = serde_json::to_vec(&ConnectionRejected {
+        reason: reason.to_string(),
+    })
+    .unwrap();
+    let packet = state.packet_to(addr, MessageType::ConnectionRejected, payload);
+    transport.send_packet(&packet, addr).await?;
+    Ok(())
+}
+
+// Disconnects every player the access list no longer lets in.
+async fn enforce_access(
+    transport: &Transport,
+    state: &mut ServerState,
+    access: &AccessFile,
+) -> io::Result<()> {
+    let rejected: Vec<(String, String)> = state
+        .players
+        .iter()
+        .filter_map(|(addr, player)| {
+            let ip = addr.parse::<SocketAddr>().ok()?.ip();
+            let reason = access.list.check(ip, &player.profile.name).err()?;
+            Some((addr.clone(), reason))
+        })
+        .collect();
+    for (addr, reason) in rejected {
+        send_rejection(transport, state, &addr, &reason).await?;
+        remove_player(transport, state, &addr).await?;
+    }
+    Ok(())
+}
+
+// Saves the access list after an admin edit and applies it to connected players.
+async fn update_access(
+    transport: &Transport,
+    state: &mut ServerState,
+    access: &mut AccessFile,
+) -> io::Result<()> {
+    if let Err(e) = access.save() {
+        error!(path = %access.path.display(), error = %e, "failed to save access list");
+    }
+    enforce_access(transport, state, access).await
+}
+
+async fn handle_chat_command(
+    transport: &Transport,
+    state: &mut ServerState,
+    access: &AccessFile,
+    sender: &str,
+    command: ChatCommand,
+) -> io::Result<()> {
+    let Some(sender_name) = state.players.get(sender).map(|p| p.profile.name.clone()) else {
+        return Ok(());
+    };
+    match command {
+        ChatCommand::Who => {
+            let mut names: Vec<&str> = state
+                .players
+                .values()
+                .map(|p| p.profile.name.as_str())
+                .collect();
+            names.sort_unstable();
+            let text = format!("Online ({}): {}", names.len(), names.join(", "));
+            send_chat(transport, state, sender, &text).await?;
+        }
+        ChatCommand::Msg { to, text } => match state.find_player(&to) {
+            Some(target) => {
+                let text = format!("[{} -> {}] {}", sender_name, to, text);
+                send_chat(transport, state, &target, &text).await?;
+                if target != sender {
+                    send_chat(transport, state, sender, &text).await?;
+                }
+            }
+            None => send_chat(transport, state, sender, &format!("No player named {}", to)).await?,
+        },
+        ChatCommand::Nick(name) => {
+            let requested_name = sanitize_name(&name).unwrap_or_default();
+            if let Ok(addr) = sender.parse::<SocketAddr>() {
+                if let Err(reason) = access.list.check(addr.ip(), &requested_name) {
+                    send_chat(transport, state, sender, &reason).await?;
+                    return Ok(());
+                }
+            }
+            // Take the player out while resolving so their own name isn't a clash
+            let mut player = state.players.remove(sender).unwrap();
+            let requested = PlayerProfile::new(&name, player.profile.color);
+            player.profile = state.resolve_profile(Some(requested), player.player_number);
+            player.keeps_record = keeps_record(&requested_name, &player.profile);
+            let profile = player.profile.clone();
+            state.players.insert(sender.to_string(), player);
+
+            let join = Bytes::from(
+                PlayerJoin {
+                    player: sender.to_string(),
+                    profile: profile.clone(),
+                    previous: None,
+                }
+                .serialize(),
+            );
+            let text = format!("{} is now known as {}", sender_name, profile.name);
+            for addr in state.players.keys() {
+                let packet = state.packet_to(addr, MessageType::PlayerJoin, join.clone());
+                transport.queue_packet(packet, addr);
+                send_chat(transport, state, addr, &text).await?;
+            }
+        }
+        ChatCommand::Ping => send_chat(transport, state, sender, "pong").await?,
+    }
+    Ok(())
+}
+
+async fn handle_admin_command(
+    transport: &Transport,
+    state: &mut ServerState,
+    access: &mut AccessFile,
+    command: AdminCommand,
+) -> io::Result<String> {
+    let reply = match command {
+        AdminCommand::Kick { name, reason } => match state.find_player(&name) {
+            Some(addr) => {
+                let text = if reason.is_empty() {
+                    "You were kicked from the server".to_string()
+                } else {
+                    format!("You were kicked from the server: {}", reason)
+                };
+                send_chat(transport, state, &addr, &text).await?;
+                remove_player(transport, state, &addr).await?;
+                info!(%name, peer = %addr, %reason, "kicked player");
+                format!("Kicked {}", name)
+            }
+            None => format!("No player named {}", name),
+        },
+        AdminCommand::Ban(entry) => {
+            if access.list.banned.add(entry.clone()) {
+                update_access(transport, state, access).await?;
+                info!(%entry, "added ban");
+                format!("Banned {}", entry)
+            } else {
+                format!("{} is already banned", entry)
+            }
+        }
+        AdminCommand::Unban(entry) => {
+            if access.list.banned.remove(&entry) {
+                update_access(transport, state, access).await?;
+                info!(%entry, "removed ban");
+                format!("Unbanned {}", entry)
+            } else {
+                format!("{} is not banned", entry)
+            }
+        }
+        AdminCommand::Allow(entry) => {
+            if access.list.allowlist.add(entry.clone()) {
+                update_access(transport, state, access).await?;
+                format!("Allowlisted {}", entry)
+            } else {
+                format!("{} is already allowlisted", entry)
+            }
+        }
+        AdminCommand::Disallow(entry) => {
+            if access.list.allowlist.remove(&entry) {
+                update_access(transport, state, access).await?;
+                format!("Removed {} from the allowlist", entry)
+            } else {
+                format!("{} is not allowlisted", entry)
+            }
+        }
+        AdminCommand::SetAllowlist(enabled) => {
+            access.list.allowlist_enabled = enabled;
+            update_access(transport, state, access).await?;
+            format!("Allowlist {}", if enabled { "enabled" } else { "disabled" })
+        }
+        AdminCommand::ShowAccess => {
+            let list = &access.list;
+            format!(
+                "Banned IPs: {:?}\nBanned names: {:?}\nAllowlist ({}): {:?} {:?}",
+                list.banned.ips,
+                list.banned.names,
+                if list.allowlist_enabled { "on" } else { "off" },
+                list.allowlist.ips,
+                list.allowlist.names
+            )
+        }
+        AdminCommand::Broadcast(text) => {
+            let text = format!("[server] {}", text);
+            for addr in state.players.keys() {
+                send_chat(transport, state, addr, &text).await?;
+            }
+            format!("Sent to {} player(s)", state.players.len())
+        }
+        AdminCommand::List => {
+            let mut reply = format!("{} player(s) online", state.players.len());
+            for (addr, player) in &state.players {
+                let pos = &player.position;
+                let link = match player.linkdead_since {
+                    Some(_) => "linkdead".to_string(),
+                    None => player.quality.stats().summary(),
+                };
+                let _ = write!(
+                    reply,
+                    "\n  {} ({}) at {},{}  {}",
+                    player.profile.name, addr, pos.x, pos.y, link
+                );
+            }
+            reply
+        }
+        AdminCommand::SetTickRate(hz) => {
+            state.tick_interval = Duration::from_secs_f64(1.0 / hz);
+            format!("Tick interval set to {:?}", state.tick_interval)
+        }
+        // Server::admin stops the server itself, there's nothing to do here
+        AdminCommand::Shutdown { .. } => String::new(),
+    };
+    Ok(reply)
+}
@@ -0,0 +1,267 @@
+// Deterministic runs of the whole server, for tests.
+//
+// A Simulation runs an in-memory server on a manual clock. Virtual clients
+// send datagrams to it through a seeded NetworkConditioner. Each step moves
+// the clock forward one flush interval, delivers whatever has arrived by
+// then, runs the server's timers and flushes. Nothing depends on real time,
+// task scheduling or sockets, so the same seed and the same inputs always
+// produce the same packets.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::time::Instant as TokioInstant;
+
+use crate::{
+    access::AccessFile,
+    clock::Clock,
+    conditioner::{Direction, LinkConditions, NetworkConditioner},
+    fragment::{self, Reassembler},
+    metrics::Metrics,
+    quality::{ConnectionQuality, Heartbeat},
+    sequence::SequenceCounter,
+    server::{Config, Server},
+    GamePacket, MessageType, PlayerProfile,
+};
+
+pub type ClientId = usize;
+
+const SERVER_ADDR: ([u8; 4], u16) = ([10, 0, 0, 1], 4000);
+
+// One virtual player.
+#[derive(Debug)]
+pub struct SimClient {
+    pub addr: SocketAddr,
+    // Silent clients stop answering heartbeats, as if they'd crashed
+    pub silent: bool,
+    // Everything the server sent, with the simulated time it arrived
+    pub received: Vec<(Duration, GamePacket)>,
+    seq: SequenceCounter,
+    quality: ConnectionQuality,
+    reassembler: Reassembler<()>,
+}
+
+impl SimClient {
+    // Packets of one type received so far.
+    pub fn received_of(&self, msg_type: MessageType) -> impl Iterator<Item = &GamePacket> {
+        self.received
+            .iter()
+            .map(|(_, packet)| packet)
+            .filter(move |packet| packet.msg_type == msg_type)
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    at: Instant,
+    // Breaks ties between datagrams due at the same moment
+    order: u64,
+    direction: Direction,
+    client: ClientId,
+    datagram: Bytes,
+}
+
+pub struct Simulation {
+    server: Server,
+    clock: Clock,
+    started: Instant,
+    step: Duration,
+    conditioner: NetworkConditioner,
+    clients: Vec<SimClient>,
+    by_addr: HashMap<SocketAddr, ClientId>,
+    reassembler: Reassembler<SocketAddr>,
+    in_flight: Vec<InFlight>,
+    next_order: u64,
+    next_tick: Instant,
+    next_heartbeat: Instant,
+}
+
+impl Simulation {
+    // A server with `config` on a perfect network. The seed drives the
+    // network conditions, and session tokens unless `config` seeds them.
+    // The server gets a manual clock of its own unless `config` already has
+    // one, which lets a capture share it.
+    pub fn new(mut config: Config, seed: u64) -> Self {
+        if let Clock::Runtime = config.clock {
+            config.clock = Clock::manual();
+        }
+        let clock = config.clock.clone();
+        config.render = false;
+        config.session_seed.get_or_insert(seed);
+        let step = config.flush_interval;
+        let server = Server::in_memory(
+            SERVER_ADDR.into(),
+            AccessFile::in_memory(),
+            Arc::new(Metrics::new()),
+            config,
+        );
+        let started = clock.now();
+        Simulation {
+            server,
+            clock,
+            started,
+            step,
+            conditioner: NetworkConditioner::symmetric(LinkConditions::default(), seed),
+            clients: Vec::new(),
+            by_addr: HashMap::new(),
+            reassembler: Reassembler::new(),
+            in_flight: Vec::new(),
+            next_order: 0,
+            next_tick: started,
+            next_heartbeat: started,
+        }
+    }
+
+    pub fn set_conditions(&mut self, direction: Direction, conditions: LinkConditions) {
+        self.conditioner.set_conditions(direction, conditions);
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    // Simulated time since the start.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now() - self.started
+    }
+
+    pub fn add_client(&mut self) -> ClientId {
+        let id = self.clients.len();
+        let addr = SocketAddr::from(([10, 1, (id >> 8) as u8, id as u8], 5000));
+        self.clients.push(SimClient {
+            addr,
+            silent: false,
+            received: Vec::new(),
+            seq: SequenceCounter::default(),
+            quality: ConnectionQuality::new(self.clock.now()),
+            reassembler: Reassembler::new(),
+        });
+        self.by_addr.insert(addr, id);
+        id
+    }
+
+    pub fn client(&self, id: ClientId) -> &SimClient {
+        &self.clients[id]
+    }
+
+    pub fn client_mut(&mut self, id: ClientId) -> &mut SimClient {
+        &mut self.clients[id]
+    }
+
+    pub fn connect(&mut self, client: ClientId, profile: &PlayerProfile) {
+        self.send(client, MessageType::ConnectionInit, profile.serialize());
+    }
+
+    // Sends a packet from a client now. It reaches the server on the step
+    // its delivery time falls in, if the network doesn't lose it.
+    pub fn send(&mut self, client: ClientId, msg_type: MessageType, payload: impl Into<Bytes>) {
+        let packet = GamePacket::new(msg_type, self.clients[client].seq.next(), payload);
+        let datagrams = fragment::split(&packet, self.server.config().max_datagram)
+            .expect("packet too large to send");
+        for datagram in datagrams {
+            self.transmit(Direction::Upstream, client, datagram);
+        }
+    }
+
+    // Advances the clock one step and runs everything due by then.
+    pub async fn step(&mut self) -> io::Result<()> {
+        self.clock.advance(self.step);
+        let now = self.clock.now();
+        self.deliver(now).await?;
+
+        if now >= self.next_tick {
+            self.server.tick().await;
+            self.next_tick += self.server.state().lock().await.tick_interval;
+        }
+        if now >= self.next_heartbeat {
+            self.server.send_heartbeats().await;
+            self.next_heartbeat += self.server.config().heartbeat_interval;
+        }
+        self.server.flush().await?;
+
+        let mut sent = self.server.take_sent();
+        // The server's maps don't iterate in a fixed order, so sort by
+        // destination to make the conditioner's draws line up run to run.
+        // Each client's own packets stay in the order they were sent.
+        sent.sort_by_key(|(addr, _)| *addr);
+        for (addr, datagram) in sent {
+            if let Some(&client) = self.by_addr.get(&addr) {
+                self.transmit(Direction::Downstream, client, datagram);
+            }
+        }
+        self.deliver(now).await
+    }
+
+    pub async fn run_for(&mut self, duration: Duration) -> io::Result<()> {
+        let until = self.clock.now() + duration;
+        while self.clock.now() < until {
+            self.step().await?;
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, direction: Direction, client: ClientId, datagram: Bytes) {
+        let now = TokioInstant::from_std(self.clock.now());
+        for at in self.conditioner.schedule(direction, now) {
+            self.in_flight.push(InFlight {
+                at: at.into_std(),
+                order: self.next_order,
+                direction,
+                client,
+                datagram: datagram.clone(),
+            });
+            self.next_order += 1;
+        }
+    }
+
+    // Hands over every datagram that has arrived by `now`, oldest first.
+    async fn deliver(&mut self, now: Instant) -> io::Result<()> {
+        let (mut due, pending): (Vec<_>, Vec<_>) =
+            self.in_flight.drain(..).partition(|f| f.at <= now);
+        self.in_flight = pending;
+        due.sort_by_key(|f| (f.at, f.order));
+        for flight in due {
+            match flight.direction {
+                Direction::Upstream => {
+                    let from = self.clients[flight.client].addr;
+                    self.server
+                        .dispatch(&mut self.reassembler, flight.datagram, from)
+                        .await?;
+                }
+                Direction::Downstream => self.receive(flight.client, flight.datagram, flight.at),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, id: ClientId, datagram: Bytes, at: Instant) {
+        let elapsed = at.saturating_duration_since(self.started);
+        let mut replies = Vec::new();
+        let client = &mut self.clients[id];
+        for packet in GamePacket::messages(datagram).flatten() {
+            let packet = match packet.msg_type {
+                MessageType::Fragment => match client.reassembler.insert((), &packet, at) {
+                    Ok(Some(packet)) => packet,
+                    _ => continue,
+                },
+                _ => packet,
+            };
+            if packet.msg_type == MessageType::Heartbeat && !client.silent {
+                if let Some(heartbeat) = Heartbeat::deserialize(&packet.payload) {
+                    client.quality.on_heartbeat(&heartbeat, at);
+                    replies.push(client.quality.heartbeat(at).serialize());
+                }
+            }
+            client.received.push((elapsed, packet));
+        }
+        for reply in replies {
+            self.send(id, MessageType::Heartbeat, reply);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// The server's UDP sockets. MeteredSocket wraps a tokio socket so every
+// datagram in or out is counted in Metrics, and takes care of fragmenting
+// and batching outgoing packets.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex as SyncMutex},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tracing::warn;
+
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use std::os::fd::AsRawFd;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use tokio::io::Interest;
+
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use crate::mmsg;
+use crate::{
+    batch::Outbox,
+    fragment::{self, DEFAULT_MAX_DATAGRAM},
+    metrics::Metrics,
+    GamePacket,
+};
+
+// A UDP socket that counts every datagram going through it. Packets sent
+// with send_packet are fragmented if they don't fit in one datagram; packets
+// queued with queue_packet are batched together and sent on the next flush.
+#[derive(Debug)]
+pub struct MeteredSocket {
+    endpoint: Endpoint,
+    metrics: Arc<Metrics>,
+    max_datagram: usize,
+    outbox: SyncMutex<Outbox>,
+}
+
+#[derive(Debug)]
+enum Endpoint {
+    Udp(UdpSocket),
+    // Keeps what's sent instead of sending it, for simulations. Nothing is
+    // ever received; the simulation hands datagrams to the server directly.
+    Memory {
+        addr: SocketAddr,
+        sent: SyncMutex<Vec<(SocketAddr, Bytes)>>,
+    },
+}
+
+impl MeteredSocket {
+    pub fn new(socket: UdpSocket, metrics: Arc<Metrics>) -> Self {
+        Self::with_endpoint(Endpoint::Udp(socket), metrics)
+    }
+
+    // A socket that only pretends to be bound to `addr`. Whatever it sends
+    // is kept for take_sent.
+    pub fn in_memory(addr: SocketAddr, metrics: Arc<Metrics>) -> Self {
+        let sent = SyncMutex::new(Vec::new());
+        Self::with_endpoint(Endpoint::Memory { addr, sent }, metrics)
+    }
+
+    fn with_endpoint(endpoint: Endpoint, metrics: Arc<Metrics>) -> Self {
+        MeteredSocket {
+            endpoint,
+            metrics,
+            max_datagram: DEFAULT_MAX_DATAGRAM,
+            outbox: SyncMutex::new(Outbox::new(DEFAULT_MAX_DATAGRAM)),
+        }
+    }
+
+    pub fn with_max_datagram(mut self, max_datagram: usize) -> Self {
+        self.max_datagram = max_datagram;
+        self.outbox = SyncMutex::new(Outbox::new(max_datagram));
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.endpoint {
+            Endpoint::Udp(socket) => socket.local_addr(),
+            Endpoint::Memory { addr, .. } => Ok(*addr),
+        }
+    }
+
+    // Everything an in-memory socket has sent since the last call, in order.
+    // Always empty for a real socket.
+    pub fn take_sent(&self) -> Vec<(SocketAddr, Bytes)> {
+        match &self.endpoint {
+            Endpoint::Udp(_) => Vec::new(),
+            Endpoint::Memory { sent, .. } => std::mem::take(&mut *sent.lock().unwrap()),
+        }
+    }
+
+    fn udp(&self) -> io::Result<&UdpSocket> {
+        match &self.endpoint {
+            Endpoint::Udp(socket) => Ok(socket),
+            Endpoint::Memory { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "in-memory sockets don't receive",
+            )),
+        }
+    }
+
+    pub async fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], target: A) -> io::Result<usize> {
+        let sent = match &self.endpoint {
+            Endpoint::Udp(socket) => socket.send_to(buf, target).await?,
+            Endpoint::Memory { sent, .. } => {
+                let target = lookup_host(target).await?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no address to send to")
+                })?;
+                sent.lock()
+                    .unwrap()
+                    .push((target, Bytes::copy_from_slice(buf)));
+                buf.len()
+            }
+        };
+        self.metrics.record_datagram_sent(buf);
+        Ok(sent)
+    }
+
+    pub async fn send_packet<A: ToSocketAddrs + Copy>(
+        &self,
+        packet: &GamePacket,
+        target: A,
+    ) -> io::Result<()> {
+        let datagrams = fragment::split(packet, self.max_datagram)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.metrics.record_packet_sent(packet.msg_type);
+        for datagram in datagrams {
+            self.send_to(&datagram, target).await?;
+        }
+        Ok(())
+    }
+
+    pub fn queue_packet(&self, packet: GamePacket, target: &str) {
+        self.metrics.record_packet_sent(packet.msg_type);
+        self.outbox.lock().unwrap().push(target, packet);
+    }
+
+    // Sends everything queued since the last flush. A datagram that can't
+    // be sent is logged and skipped, so one unreachable peer doesn't cost
+    // everyone else theirs; the error returned only counts them.
+    pub async fn flush(&self) -> io::Result<()> {
+        let queued = self.outbox.lock().unwrap().drain();
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        if let Endpoint::Udp(socket) = &self.endpoint {
+            return self.flush_mmsg(socket, queued).await;
+        }
+        let mut failed = 0;
+        let mut total = 0;
+        for (target, datagrams) in queued {
+            for datagram in datagrams {
+                total += 1;
+                if let Err(e) = self.send_to(&datagram, target.as_str()).await {
+                    warn!(peer = %target, error = %e, "failed to send datagram");
+                    failed += 1;
+                }
+            }
+        }
+        flush_result(failed, total)
+    }
+
+    // Sends the drained outbox many datagrams per system call.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    async fn flush_mmsg(
+        &self,
+        socket: &UdpSocket,
+        queued: Vec<(String, Vec<Bytes>)>,
+    ) -> io::Result<()> {
+        let mut datagrams = Vec::new();
+        for (target, batch) in queued {
+            let Ok(addr) = target.parse::<SocketAddr>() else {
+                warn!(%target, "not sending to unparsable address");
+                continue;
+            };
+            datagrams.extend(batch.into_iter().map(|datagram| (addr, datagram)));
+        }
+        let fd = socket.as_raw_fd();
+        let mut sent = 0;
+        let mut failed = 0;
+        while sent < datagrams.len() {
+            let pending = &datagrams[sent..];
+            match socket
+                .async_io(Interest::WRITABLE, || mmsg::send(fd, pending))
+                .await
+            {
+                Ok(n) => {
+                    for (_, datagram) in &pending[..n] {
+                        self.metrics.record_datagram_sent(datagram);
+                    }
+                    sent += n;
+                }
+                // The error is about the first datagram in the call; skip it
+                // and carry on with the rest
+                Err(e) => {
+                    warn!(peer = %pending[0].0, error = %e, "failed to send datagram");
+                    failed += 1;
+                    sent += 1;
+                }
+            }
+        }
+        flush_result(failed, datagrams.len())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, addr) = self.udp()?.recv_from(buf).await?;
+        self.metrics.record_datagram_received(&buf[..len]);
+        Ok((len, addr))
+    }
+
+    // Receives one datagram into `pool` and hands it out without copying.
+    // The pool's allocation is reused as soon as the previous datagram and
+    // every packet decoded from it have been dropped.
+    pub async fn recv_bytes(&self, pool: &mut BytesMut) -> io::Result<(Bytes, SocketAddr)> {
+        pool.clear();
+        pool.reserve(fragment::RECV_BUFFER_SIZE);
+        let (_, addr) = self.udp()?.recv_buf_from(pool).await?;
+        self.metrics.record_datagram_received(pool);
+        Ok((pool.split().freeze(), addr))
+    }
+
+    // Receives whatever datagrams are waiting, at least one.
+    #[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+    pub async fn recv_batch(&self, pool: &mut RecvPool) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        Ok(vec![self.recv_bytes(&mut pool.buf).await?])
+    }
+
+    // Receives whatever datagrams are waiting, at least one, many per system
+    // call. Each is copied out of the scratch space into the pool, so only
+    // the bytes actually received are copied.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    pub async fn recv_batch(&self, pool: &mut RecvPool) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        let socket = self.udp()?;
+        let fd = socket.as_raw_fd();
+        let RecvPool {
+            buf,
+            scratch,
+            received,
+        } = pool;
+        received.clear();
+        socket
+            .async_io(Interest::READABLE, || {
+                mmsg::recv(fd, scratch, fragment::RECV_BUFFER_SIZE, received)
+            })
+            .await?;
+        buf.clear();
+        buf.reserve(received.iter().map(|(len, _)| len).sum());
+        let datagrams = received
+            .iter()
+            .zip(scratch.chunks_exact(fragment::RECV_BUFFER_SIZE))
+            .map(|(&(len, addr), slot)| {
+                self.metrics.record_datagram_received(&slot[..len]);
+                buf.extend_from_slice(&slot[..len]);
+                (buf.split().freeze(), addr)
+            })
+            .collect();
+        Ok(datagrams)
+    }
+}
+
+// Buffers MeteredSocket::recv_batch receives into, reused between calls.
+#[derive(Debug)]
+pub struct RecvPool {
+    buf: BytesMut,
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    scratch: Vec<u8>,
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl Default for RecvPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecvPool {
+    pub fn new() -> Self {
+        RecvPool {
+            buf: BytesMut::with_capacity(fragment::RECV_BUFFER_SIZE),
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            scratch: vec![0; mmsg::BATCH * fragment::RECV_BUFFER_SIZE],
+            #[cfg(all(target_os = "linux", feature = "mmsg"))]
+            received: Vec::with_capacity(mmsg::BATCH),
+        }
+    }
+}
+
+// What a flush that carried on past `failed` errors reports.
+fn flush_result(failed: usize, total: usize) -> io::Result<()> {
+    if failed == 0 {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "{} of {} datagrams could not be sent",
+        failed, total
+    )))
+}
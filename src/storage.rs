@@ -0,0 +1,159 @@
+// Player records that outlive the server: each player's profile, where they
+// were last and a few counters, so coming back after a restart puts them
+// where they left off.
+//
+// Players have no accounts, so a player's identity is their name, compared
+// without ASCII case the way the server tells names apart. The server saves
+// a player's record when they leave, every autosave interval for everyone
+// still on, and when it shuts down.
+//
+// GAME_UDP_STORAGE names a JSON file to keep the records in. Without it
+// nothing is kept. Anything else that can load and save records can stand
+// in for the file by implementing Storage.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{PlayerProfile, Position};
+
+pub const STORAGE_VAR: &str = "GAME_UDP_STORAGE";
+
+// Counted over every session a player has had.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub sessions: u32,
+    pub moves: u64,
+    pub chat_messages: u64,
+    // Seconds since the Unix epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerRecord {
+    pub profile: PlayerProfile,
+    pub position: Position,
+    #[serde(default)]
+    pub stats: PlayerStats,
+}
+
+// The key a player's record is stored under. Folds case exactly as
+// eq_ignore_ascii_case does, so two players the server lets on at once
+// never share a record.
+pub fn identity(profile: &PlayerProfile) -> String {
+    profile.name.to_ascii_lowercase()
+}
+
+// Seconds since the Unix epoch, for the stats.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub trait Storage: fmt::Debug + Send + Sync {
+    fn load(&self, identity: &str) -> io::Result<Option<PlayerRecord>>;
+
+    // Adds or replaces these records, keyed by identity. The server calls
+    // this with its state locked, so it mustn't wait on anything slow.
+    fn save(&self, records: Vec<(String, PlayerRecord)>) -> io::Result<()>;
+
+    // Makes everything saved so far last past a restart. The server calls
+    // this from a blocking thread with nothing locked.
+    fn persist(&self) -> io::Result<()>;
+}
+
+// Every record in one JSON file, read when it's opened and written whole
+// when persisted. Fine for the few hundred players a server like this sees.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    records: Mutex<BTreeMap<String, PlayerRecord>>,
+    // Set by save, cleared once the records are written out
+    dirty: AtomicBool,
+    // Held for a whole write, so two writes can't land out of order
+    writing: Mutex<()>,
+}
+
+impl FileStorage {
+    // Loads the file, starting with no records if it doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<FileStorage> {
+        let path = path.into();
+        let records = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(FileStorage {
+            path,
+            records: Mutex::new(records),
+            dirty: AtomicBool::new(false),
+            writing: Mutex::new(()),
+        })
+    }
+
+    // Records kept only in memory, for simulations. Persisting writes
+    // nothing.
+    pub fn in_memory() -> FileStorage {
+        FileStorage {
+            path: PathBuf::new(),
+            records: Mutex::new(BTreeMap::new()),
+            dirty: AtomicBool::new(false),
+            writing: Mutex::new(()),
+        }
+    }
+
+    // The file named by GAME_UDP_STORAGE, if it's set.
+    pub fn from_env() -> io::Result<Option<Arc<dyn Storage>>> {
+        match std::env::var(STORAGE_VAR) {
+            Ok(path) => Ok(Some(Arc::new(FileStorage::open(path)?))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self, identity: &str) -> io::Result<Option<PlayerRecord>> {
+        Ok(self.records.lock().unwrap().get(identity).cloned())
+    }
+
+    fn save(&self, records: Vec<(String, PlayerRecord)>) -> io::Result<()> {
+        self.records.lock().unwrap().extend(records);
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        if self.path.as_os_str().is_empty() || !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        // Serialize with the records locked but write without, so saves
+        // carry on meanwhile
+        let data = serde_json::to_vec_pretty(&*self.records.lock().unwrap()).unwrap();
+        // Write next to the file and move it into place, so a crash halfway
+        // through leaves the last complete save
+        let partial = self.path.with_extension("partial");
+        let written = fs::write(&partial, data).and_then(|()| fs::rename(&partial, &self.path));
+        if written.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        written
+    }
+}
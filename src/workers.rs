@@ -0,0 +1,190 @@
+// Running the server on several sockets bound to the same port.
+//
+// With SO_REUSEPORT the kernel spreads incoming datagrams across the sockets
+// by hashing the sender's address, so a client keeps landing on the same
+// socket. One worker runs per socket. A session is pinned to the worker that
+// received its ConnectionInit and everything queued for that client goes out
+// through that worker's socket; packets queued anywhere else are handed over
+// on the owning worker's channel.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::warn;
+
+use crate::{
+    capture::{Direction, Recorder},
+    socket::MeteredSocket,
+    GamePacket,
+};
+
+pub const WORKERS_VAR: &str = "GAME_UDP_WORKERS";
+
+// A packet another worker queued for one of this worker's sessions.
+pub type Handoff = (String, GamePacket);
+
+// Reads the number of workers from GAME_UDP_WORKERS, defaulting to one.
+pub fn workers_from_env() -> usize {
+    std::env::var(WORKERS_VAR)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(1)
+}
+
+// Binds `count` sockets to `addr`. More than one needs SO_REUSEPORT, which is
+// only available on Unix.
+pub async fn bind(addr: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    if count <= 1 {
+        return Ok(vec![UdpSocket::bind(addr).await?]);
+    }
+    (0..count).map(|_| bind_reuseport(addr)).collect()
+}
+
+#[cfg(unix)]
+fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuseport(_addr: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "multiple workers need SO_REUSEPORT, which this platform lacks",
+    ))
+}
+
+#[derive(Debug)]
+struct Shared {
+    sockets: Vec<Arc<MeteredSocket>>,
+    handoff: Vec<mpsc::UnboundedSender<Handoff>>,
+    // Which worker owns each session, by client address
+    sessions: RwLock<HashMap<String, usize>>,
+    capture: Option<Arc<Recorder>>,
+}
+
+// Sends packets through whichever worker owns the session. Each worker holds
+// a copy that knows which worker it is; tasks that aren't workers hold one
+// that doesn't.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    local: Option<usize>,
+    shared: Arc<Shared>,
+}
+
+impl Transport {
+    // Returns the transport along with each worker's handoff receiver. Every
+    // packet sent is recorded to `capture`, if there is one.
+    pub fn new(
+        sockets: Vec<Arc<MeteredSocket>>,
+        capture: Option<Arc<Recorder>>,
+    ) -> (Self, Vec<mpsc::UnboundedReceiver<Handoff>>) {
+        let (handoff, receivers) = sockets.iter().map(|_| mpsc::unbounded_channel()).unzip();
+        let transport = Transport {
+            local: None,
+            shared: Arc::new(Shared {
+                sockets,
+                handoff,
+                sessions: RwLock::new(HashMap::new()),
+                capture,
+            }),
+        };
+        (transport, receivers)
+    }
+
+    pub fn for_worker(&self, worker: usize) -> Self {
+        Transport {
+            local: Some(worker),
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.shared.sockets.len()
+    }
+
+    pub fn socket(&self, worker: usize) -> &Arc<MeteredSocket> {
+        &self.shared.sockets[worker]
+    }
+
+    // Pins a session to this worker.
+    pub fn pin(&self, addr: &str) {
+        let worker = self.local.unwrap_or(0);
+        self.shared
+            .sessions
+            .write()
+            .unwrap()
+            .insert(addr.to_string(), worker);
+    }
+
+    pub fn unpin(&self, addr: &str) {
+        self.shared.sessions.write().unwrap().remove(addr);
+    }
+
+    // The worker that owns `addr`, or this one if nobody does yet.
+    fn owner(&self, addr: &str) -> usize {
+        let pinned = self.shared.sessions.read().unwrap().get(addr).copied();
+        pinned.or(self.local).unwrap_or(0)
+    }
+
+    // Queues a packet for the next flush of the owning worker's socket.
+    pub fn queue_packet(&self, packet: GamePacket, addr: &str) {
+        self.record(&packet, addr);
+        let owner = self.owner(addr);
+        if Some(owner) == self.local {
+            self.shared.sockets[owner].queue_packet(packet, addr);
+        } else if self.shared.handoff[owner]
+            .send((addr.to_string(), packet))
+            .is_err()
+        {
+            warn!(worker = owner, peer = %addr, "worker gone, dropping packet");
+        }
+    }
+
+    // Sends a packet right away from the owning worker's socket.
+    pub async fn send_packet(&self, packet: &GamePacket, addr: &str) -> io::Result<()> {
+        self.record(packet, addr);
+        self.shared.sockets[self.owner(addr)]
+            .send_packet(packet, addr)
+            .await
+    }
+
+    // Flushes every socket, and the capture, even if some fail. Returns the
+    // first failure.
+    pub async fn flush(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for socket in &self.shared.sockets {
+            if let Err(e) = socket.flush().await {
+                result = result.and(Err(e));
+            }
+        }
+        if let Some(capture) = &self.shared.capture {
+            capture.flush().await;
+        }
+        result
+    }
+
+    // Records a packet received from `addr`, if capturing.
+    pub fn record_inbound(&self, packet: &GamePacket, addr: &str) {
+        if let Some(capture) = &self.shared.capture {
+            capture.record(Direction::Inbound, addr, packet);
+        }
+    }
+
+    fn record(&self, packet: &GamePacket, addr: &str) {
+        if let Some(capture) = &self.shared.capture {
+            capture.record(Direction::Outbound, addr, packet);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Address ranges and the ban and allow checks built on them.
+
+use std::net::IpAddr;
+
+use game_udp::access::{AccessEntry, AccessList, IpRange};
+
+fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+}
+
+fn range(text: &str) -> IpRange {
+    text.parse().unwrap()
+}
+
+#[test]
+fn prefixes_cover_the_right_addresses() {
+    let block = range("10.1.0.0/16");
+    assert!(block.contains(ip("10.1.0.0")));
+    assert!(block.contains(ip("10.1.255.255")));
+    assert!(!block.contains(ip("10.2.0.0")));
+    assert!(!block.contains(ip("10.0.255.255")));
+
+    // Host bits in the network are ignored
+    assert!(range("192.0.2.77/24").contains(ip("192.0.2.1")));
+
+    // A /0 is everything of its family, and nothing of the other
+    assert!(range("0.0.0.0/0").contains(ip("255.255.255.255")));
+    assert!(range("0.0.0.0/0").contains(ip("0.0.0.0")));
+    assert!(!range("0.0.0.0/0").contains(ip("2001:db8::1")));
+    assert!(range("::/0").contains(ip("2001:db8::1")));
+
+    // A /32 or a bare address is one host
+    for single in [range("203.0.113.9/32"), range("203.0.113.9")] {
+        assert!(single.contains(ip("203.0.113.9")));
+        assert!(!single.contains(ip("203.0.113.8")));
+        assert!(!single.contains(ip("203.0.113.10")));
+    }
+
+    let v6 = range("2001:db8:abcd::/48");
+    assert!(v6.contains(ip("2001:db8:abcd:ffff::1")));
+    assert!(!v6.contains(ip("2001:db8:abce::1")));
+    assert!(range("2001:db8::1/128").contains(ip("2001:db8::1")));
+    assert!(!range("2001:db8::1/128").contains(ip("2001:db8::2")));
+}
+
+#[test]
+fn mapped_addresses_match_as_ipv4() {
+    // The same client, as seen on an IPv4 socket and a dual-stack one
+    let mapped = ip("::ffff:198.51.100.20");
+    assert!(range("198.51.100.0/24").contains(mapped));
+    assert!(range("198.51.100.20").contains(mapped));
+    assert!(!range("198.51.101.0/24").contains(mapped));
+
+    // A range written in mapped form covers plain IPv4 clients too
+    let mapped_range = range("::ffff:198.51.100.0/120");
+    assert!(mapped_range.contains(ip("198.51.100.20")));
+    assert!(mapped_range.contains(mapped));
+    assert!(!mapped_range.contains(ip("198.51.101.20")));
+}
+
+#[test]
+fn bad_ranges_are_rejected() {
+    for bad in [
+        "10.0.0.0/33",
+        "2001:db8::/129",
+        "10.0.0.0/",
+        "10.0.0.0/-1",
+        "10.0.0/8",
+        "bob",
+    ] {
+        assert!(bad.parse::<IpRange>().is_err(), "accepted {}", bad);
+    }
+    // Anything that isn't a range is taken for a name
+    assert_eq!(
+        AccessEntry::parse("bob"),
+        AccessEntry::Name("bob".to_string())
+    );
+    assert_eq!(
+        AccessEntry::parse(" 10.0.0.0 / 8 "),
+        AccessEntry::Ip(range("10.0.0.0/8"))
+    );
+}
+
+#[test]
+fn ranges_round_trip_through_the_file() {
+    for text in ["10.0.0.0/8", "203.0.113.9", "2001:db8::/32", "::1"] {
+        assert_eq!(range(text).to_string(), text);
+    }
+    assert_eq!(range("203.0.113.9/32").to_string(), "203.0.113.9");
+}
+
+#[test]
+fn bans_and_the_allowlist_are_checked() {
+    let mut list = AccessList::default();
+    list.banned.add(AccessEntry::parse("10.0.0.0/8"));
+    list.banned.add(AccessEntry::parse("Mallory"));
+    assert!(list.check(ip("10.9.8.7"), "alice").is_err());
+    assert!(list.check(ip("::ffff:10.9.8.7"), "alice").is_err());
+    assert!(list.check(ip("192.0.2.1"), "mallory").is_err());
+    assert!(list.check(ip("192.0.2.1"), "alice").is_ok());
+
+    list.allowlist_enabled = true;
+    list.allowlist.add(AccessEntry::parse("192.0.2.0/24"));
+    list.allowlist.add(AccessEntry::parse("bob"));
+    assert!(list.check(ip("192.0.2.1"), "alice").is_ok());
+    assert!(list.check(ip("198.51.100.1"), "BOB").is_ok());
+    assert!(list.check(ip("198.51.100.1"), "alice").is_err());
+}
@@ -0,0 +1,117 @@
+// Recording a simulated session and reading it back.
+
+use std::{collections::HashMap, fs, sync::Arc, time::Duration};
+
+use game_udp::{
+    capture::{Direction, Reader, Recorder, Role},
+    clock::Clock,
+    server::Config,
+    sim::Simulation,
+    GamePacket, MessageType, MoveInput, PlayerColor, PlayerProfile, Position,
+};
+
+const BOARD_SIZE: (u32, u32) = (40, 20);
+
+#[tokio::test]
+async fn captures_every_packet_in_and_out() {
+    let path = std::env::temp_dir().join(format!("game_udp-test-{}.cap", std::process::id()));
+    let clock = Clock::manual();
+    let mut config = Config::new(BOARD_SIZE);
+    config.clock = clock.clone();
+    config.capture = Some(Arc::new(
+        Recorder::create(&path, Role::Server, clock).unwrap(),
+    ));
+
+    let mut sim = Simulation::new(config, 3);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Blue));
+    sim.step().await.unwrap();
+    for input_seq in 1..=5 {
+        let input = MoveInput {
+            input_seq,
+            position: Position::new(input_seq as i32, 0, 0),
+        };
+        sim.send(alice, MessageType::PositionUpdate, input.serialize());
+        sim.step().await.unwrap();
+    }
+    sim.run_for(Duration::from_secs(10)).await.unwrap();
+    sim.server().flush().await.unwrap();
+
+    let reader = Reader::open(&path).unwrap();
+    assert_eq!(reader.role(), Role::Server);
+    let records: Vec<_> = reader.map(Result::unwrap).collect();
+    fs::remove_file(&path).unwrap();
+
+    assert!(records.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    assert!(records.last().unwrap().at <= sim.elapsed());
+
+    // On a perfect network each client got exactly what was recorded as
+    // sent to it. Heartbeats skip the queue, so only the sets match up.
+    let mut outbound: HashMap<String, Vec<_>> = HashMap::new();
+    for record in records
+        .iter()
+        .filter(|r| r.direction == Direction::Outbound)
+    {
+        outbound
+            .entry(record.addr.clone())
+            .or_default()
+            .push(record.packet.clone());
+    }
+    for client in [alice, bob] {
+        let client = sim.client(client);
+        let mut received: Vec<_> = client.received.iter().map(|(_, p)| p.clone()).collect();
+        received.sort_by_key(|p| p.seq_num);
+        let mut sent = outbound.remove(&client.addr.to_string()).unwrap();
+        sent.sort_by_key(|p| p.seq_num);
+        assert_eq!(sent, received);
+    }
+
+    let moves = records.iter().filter(|r| {
+        r.direction == Direction::Inbound
+            && r.addr == sim.client(alice).addr.to_string()
+            && r.packet.msg_type == MessageType::PositionUpdate
+    });
+    assert_eq!(moves.count(), 5);
+    let heartbeats = records.iter().filter(|r| {
+        r.direction == Direction::Inbound && r.packet.msg_type == MessageType::Heartbeat
+    });
+    assert!(heartbeats.count() >= 4);
+}
+
+#[test]
+fn dropping_the_recorder_writes_everything() {
+    let path = std::env::temp_dir().join(format!("game_udp-test-{}-drop.cap", std::process::id()));
+    let recorder = Recorder::create(&path, Role::Client, Clock::manual()).unwrap();
+    for seq in 0..100 {
+        let packet = GamePacket::new(MessageType::Heartbeat, seq, Vec::new());
+        recorder.record(Direction::Outbound, "127.0.0.1:4000", &packet);
+    }
+    drop(recorder);
+
+    let records: Vec<_> = Reader::open(&path).unwrap().map(Result::unwrap).collect();
+    fs::remove_file(&path).unwrap();
+    let seqs: Vec<_> = records.iter().map(|r| r.packet.seq_num).collect();
+    assert_eq!(seqs, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn other_files_are_rejected() {
+    let err = Reader::new(&b"GUDPCAX\x01\x00"[..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let err = Reader::new(&b"GUDPCAP\x09\x00"[..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn truncated_records_are_errors() {
+    // A peer record, then an inbound record cut off partway through its packet
+    let mut bytes = b"GUDPCAP\x01\x00".to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0, 3]);
+    bytes.extend_from_slice(b"a:1");
+    bytes.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 40]);
+    bytes.extend_from_slice(&[3, 1, 0, 0]);
+    let mut reader = Reader::new(&bytes[..]).unwrap();
+    assert!(reader.next().unwrap().is_err());
+}
@@ -0,0 +1,342 @@
+// Property tests for the packet codec and payload decoders. Each property runs
+// against CASES inputs from a seeded RNG, so a failure names the seed and
+// case that reproduce it.
+
+use std::{collections::BTreeMap, time::Instant};
+
+use bytes::Bytes;
+use game_udp::{
+    batch,
+    fragment::{self, Reassembler},
+    quality::{ConnectionStats, Heartbeat, HeartbeatEcho},
+    Chat, ConnectionRejected, ConnectionRequest, GamePacket, MessageType, MoveAck, MoveInput,
+    PlayerColor, PlayerJoin, PlayerProfile, PlayerStateSend, PlayerUpdate, Position,
+    ServerStateSend, Session,
+};
+use rand::{
+    rngs::StdRng,
+    seq::{IndexedRandom, SliceRandom},
+    Rng, SeedableRng,
+};
+
+const SEED: u64 = 0x6761_6d65;
+const CASES: usize = 512;
+
+const COLORS: [PlayerColor; 7] = [
+    PlayerColor::Red,
+    PlayerColor::Green,
+    PlayerColor::Yellow,
+    PlayerColor::Blue,
+    PlayerColor::Magenta,
+    PlayerColor::Cyan,
+    PlayerColor::White,
+];
+
+// Runs `property` once per case with its own RNG.
+fn check(name: &str, mut property: impl FnMut(&mut StdRng)) {
+    for case in 0..CASES {
+        let seed = SEED.wrapping_add(case as u64);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| property(&mut rng)));
+        if let Err(panic) = result {
+            eprintln!("{} failed on case {} (seed {})", name, case, seed);
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+fn bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+    let len = rng.random_range(0..=max_len);
+    (0..len).map(|_| rng.random()).collect()
+}
+
+// Mostly ASCII with some arbitrary Unicode, including control characters.
+fn string(rng: &mut StdRng, max_len: usize) -> String {
+    let len = rng.random_range(0..=max_len);
+    (0..len)
+        .map(|_| {
+            if rng.random_bool(0.8) {
+                rng.random_range(' '..='~')
+            } else {
+                rng.random()
+            }
+        })
+        .collect()
+}
+
+fn position(rng: &mut StdRng) -> Position {
+    Position::new(rng.random(), rng.random(), rng.random())
+}
+
+fn profile(rng: &mut StdRng) -> PlayerProfile {
+    PlayerProfile::new(&string(rng, 40), *COLORS.choose(rng).unwrap())
+}
+
+fn packet(rng: &mut StdRng, max_payload: usize) -> GamePacket {
+    let msg_type = *MessageType::ALL.choose(rng).unwrap();
+    let mut packet = GamePacket::new(msg_type, rng.random(), bytes(rng, max_payload));
+    packet.version = rng.random();
+    packet
+}
+
+fn heartbeat(rng: &mut StdRng) -> Heartbeat {
+    Heartbeat {
+        id: rng.random(),
+        sent_at_us: rng.random(),
+        echo: rng.random_bool(0.5).then(|| HeartbeatEcho {
+            id: rng.random(),
+            sent_at_us: rng.random(),
+            held_us: rng.random(),
+        }),
+        stats: rng.random_bool(0.5).then(|| ConnectionStats {
+            rtt_ms: rng
+                .random_bool(0.5)
+                .then(|| rng.random_range(0.0..10_000.0)),
+            jitter_ms: rng.random_range(0.0..1_000.0),
+            loss_percent: rng.random_range(0.0..=100.0),
+        }),
+    }
+}
+
+#[test]
+fn message_type_bytes_round_trip() {
+    for msg_type in MessageType::ALL {
+        assert_eq!(MessageType::from_byte(msg_type as u8), Some(msg_type));
+    }
+    for b in 0..=u8::MAX {
+        if let Some(msg_type) = MessageType::from_byte(b) {
+            assert_eq!(msg_type as u8, b);
+            assert!(MessageType::ALL.contains(&msg_type));
+        }
+    }
+}
+
+#[test]
+fn packets_round_trip() {
+    check("packets_round_trip", |rng| {
+        let packet = packet(rng, 2048);
+        let data = packet.serialize();
+        assert_eq!(data.len(), packet.encoded_len());
+        assert_eq!(GamePacket::decode(data.clone()).as_ref(), Some(&packet));
+        assert_eq!(GamePacket::deserialize(&data), Some(packet));
+    });
+}
+
+#[test]
+fn decoding_is_lossless() {
+    check("decoding_is_lossless", |rng| {
+        let data = bytes(rng, 64);
+        match GamePacket::deserialize(&data) {
+            Some(packet) => assert_eq!(&packet.serialize()[..], &data[..]),
+            None => assert!(
+                data.len() < GamePacket::HEADER_LEN || MessageType::from_byte(data[0]).is_none()
+            ),
+        }
+    });
+}
+
+#[test]
+fn payloads_round_trip() {
+    check("payloads_round_trip", |rng| {
+        let pos = position(rng);
+        assert_eq!(Position::deserialize(&pos.serialize()), Some(pos));
+
+        let input = MoveInput {
+            input_seq: rng.random(),
+            position: position(rng),
+        };
+        assert_eq!(MoveInput::deserialize(&input.serialize()), Some(input));
+
+        let ack = MoveAck {
+            input_seq: rng.random(),
+            position: position(rng),
+        };
+        assert_eq!(MoveAck::deserialize(&ack.serialize()), Some(ack));
+
+        let update = PlayerUpdate {
+            player: string(rng, 24),
+            position: position(rng),
+        };
+        assert_eq!(PlayerUpdate::deserialize(&update.serialize()), Some(update));
+
+        let profile = profile(rng);
+        assert_eq!(
+            PlayerProfile::deserialize(&profile.serialize()),
+            Some(profile.clone())
+        );
+
+        let request = ConnectionRequest {
+            profile: profile.clone(),
+            session: rng.random_bool(0.5).then(|| string(rng, 16)),
+        };
+        assert_eq!(
+            ConnectionRequest::deserialize(&request.serialize()),
+            Some(request)
+        );
+
+        let join = PlayerJoin {
+            player: string(rng, 24),
+            profile,
+            previous: rng.random_bool(0.5).then(|| string(rng, 24)),
+        };
+        assert_eq!(PlayerJoin::deserialize(&join.serialize()), Some(join));
+
+        let heartbeat = heartbeat(rng);
+        assert_eq!(
+            Heartbeat::deserialize(&heartbeat.serialize()),
+            Some(heartbeat)
+        );
+
+        let chat = Chat {
+            text: string(rng, 200),
+        };
+        let data = serde_json::to_vec(&chat).unwrap();
+        assert_eq!(serde_json::from_slice::<Chat>(&data).unwrap(), chat);
+
+        let rejected = ConnectionRejected {
+            reason: string(rng, 80),
+        };
+        let data = serde_json::to_vec(&rejected).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<ConnectionRejected>(&data).unwrap(),
+            rejected
+        );
+
+        let players: BTreeMap<String, PlayerStateSend> = (0..rng.random_range(0..8))
+            .map(|_| {
+                let player = PlayerStateSend {
+                    position: position(rng),
+                    profile: self::profile(rng),
+                };
+                (string(rng, 24), player)
+            })
+            .collect();
+        let snapshot = ServerStateSend {
+            players,
+            board_size: (rng.random(), rng.random()),
+            session: rng.random_bool(0.5).then(|| Session {
+                token: string(rng, 16),
+                player: string(rng, 24),
+                resumed: rng.random(),
+            }),
+        };
+        let data = serde_json::to_vec(&snapshot).unwrap();
+        assert_eq!(ServerStateSend::deserialize(&data).unwrap(), snapshot);
+    });
+}
+
+#[test]
+fn fragments_reassemble_in_any_order() {
+    check("fragments_reassemble_in_any_order", |rng| {
+        let max_datagram = rng.random_range(64..=1500);
+        // Stay within what the receiver accepts
+        let max_payload = 60 * (max_datagram - 16);
+        let mut packet = packet(rng, max_payload);
+        packet.msg_type = MessageType::ChatMessage;
+
+        let mut datagrams = fragment::split(&packet, max_datagram).unwrap();
+        assert!(datagrams.iter().all(|d| d.len() <= max_datagram));
+        datagrams.shuffle(rng);
+        // A repeated fragment changes nothing
+        if let Some(repeat) = datagrams.first().cloned() {
+            if datagrams.len() > 1 {
+                datagrams.insert(1, repeat);
+            }
+        }
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let mut complete = Vec::new();
+        for datagram in datagrams {
+            let received = GamePacket::decode(datagram).unwrap();
+            if received.msg_type == MessageType::Fragment {
+                if let Some(packet) = reassembler.insert((), &received, now).unwrap() {
+                    complete.push(packet);
+                }
+            } else {
+                complete.push(received);
+            }
+        }
+        assert_eq!(complete, vec![packet]);
+        assert_eq!(reassembler.pending_bytes(), 0);
+    });
+}
+
+#[test]
+fn batches_keep_every_packet_in_order() {
+    check("batches_keep_every_packet_in_order", |rng| {
+        let max_datagram = rng.random_range(64..=1500);
+        let packets: Vec<GamePacket> = (0..rng.random_range(0..40))
+            .map(|i| {
+                // Mostly small packets, with the odd one that needs fragmenting
+                let max_payload = if rng.random_bool(0.1) {
+                    4 * max_datagram
+                } else {
+                    max_datagram / 4
+                };
+                let msg_type = *MessageType::ALL[..8].choose(rng).unwrap();
+                GamePacket::new(msg_type, i, bytes(rng, max_payload))
+            })
+            .collect();
+
+        let datagrams = batch::pack(packets.clone(), max_datagram);
+        assert!(datagrams.iter().all(|d| d.len() <= max_datagram));
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let mut received = Vec::new();
+        for datagram in datagrams {
+            for message in GamePacket::messages(datagram) {
+                let packet = message.expect("packed datagram failed to decode");
+                if packet.msg_type == MessageType::Fragment {
+                    if let Some(packet) = reassembler.insert((), &packet, now).unwrap() {
+                        received.push(packet);
+                    }
+                } else {
+                    received.push(packet);
+                }
+            }
+        }
+        assert_eq!(received, packets);
+    });
+}
+
+// Whatever arrives, decoding either succeeds or reports failure.
+#[test]
+fn arbitrary_bytes_never_panic() {
+    check("arbitrary_bytes_never_panic", |rng| {
+        // Random bytes rarely get past the header, so also try corrupted
+        // copies of real datagrams
+        let data = if rng.random_bool(0.5) {
+            let packets = (0..rng.random_range(1..6))
+                .map(|_| packet(rng, 300))
+                .collect();
+            let mut data = batch::pack(packets, 1200)[0].to_vec();
+            for _ in 0..rng.random_range(1..4) {
+                let i = rng.random_range(0..data.len());
+                data[i] = rng.random();
+            }
+            data.truncate(rng.random_range(0..=data.len()));
+            data
+        } else {
+            bytes(rng, 512)
+        };
+
+        let mut reassembler = Reassembler::new();
+        for packet in GamePacket::messages(Bytes::from(data.clone())).flatten() {
+            if packet.msg_type == MessageType::Fragment {
+                let _ = reassembler.insert((), &packet, Instant::now());
+            }
+        }
+        let _ = Position::deserialize(&data);
+        let _ = MoveInput::deserialize(&data);
+        let _ = MoveAck::deserialize(&data);
+        let _ = PlayerUpdate::deserialize(&data);
+        let _ = PlayerProfile::deserialize(&data);
+        let _ = PlayerJoin::deserialize(&data);
+        let _ = Heartbeat::deserialize(&data);
+        let _ = ServerStateSend::deserialize(&data);
+        let _ = serde_json::from_slice::<Chat>(&data);
+        let _ = serde_json::from_slice::<ConnectionRejected>(&data);
+    });
+}
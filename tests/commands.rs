@@ -0,0 +1,240 @@
+// Parsing of chat slash commands and admin console commands, and what the
+// server does with them.
+
+use std::time::Duration;
+
+use game_udp::{
+    access::AccessEntry,
+    commands::{AdminCommand, ChatCommand},
+    server::Config,
+    sim::{ClientId, Simulation},
+    Chat, MessageType, PlayerColor, PlayerProfile,
+};
+
+const BOARD_SIZE: (u32, u32) = (40, 20);
+
+fn chat(text: &str) -> Option<Result<ChatCommand, String>> {
+    ChatCommand::parse(text)
+}
+
+#[test]
+fn chat_commands_parse() {
+    assert_eq!(chat("hello"), None);
+    assert_eq!(chat("  /who "), Some(Ok(ChatCommand::Who)));
+    assert_eq!(chat("/PING"), Some(Ok(ChatCommand::Ping)));
+    assert_eq!(
+        chat("/nick  Big Al "),
+        Some(Ok(ChatCommand::Nick("Big Al".to_string())))
+    );
+    assert_eq!(
+        chat("/msg bob see you  there"),
+        Some(Ok(ChatCommand::Msg {
+            to: "bob".to_string(),
+            text: "see you  there".to_string(),
+        }))
+    );
+}
+
+#[test]
+fn bad_chat_commands_get_usage() {
+    for (text, usage) in [
+        ("/nick", "Usage: /nick <name>"),
+        ("/msg", "Usage: /msg <name> <text>"),
+        ("/msg bob", "Usage: /msg <name> <text>"),
+        ("/dance", "Unknown command /dance"),
+        ("/", "Unknown command /"),
+    ] {
+        match chat(text) {
+            Some(Err(reply)) => assert!(reply.starts_with(usage), "{}: {}", text, reply),
+            other => panic!("{} parsed as {:?}", text, other),
+        }
+    }
+}
+
+#[test]
+fn admin_commands_parse() {
+    let parse = |line: &str| AdminCommand::parse(line).unwrap();
+    assert_eq!(
+        parse("kick mallory"),
+        AdminCommand::Kick {
+            name: "mallory".to_string(),
+            reason: String::new(),
+        }
+    );
+    assert_eq!(
+        parse("KICK mallory spamming chat"),
+        AdminCommand::Kick {
+            name: "mallory".to_string(),
+            reason: "spamming chat".to_string(),
+        }
+    );
+    assert_eq!(
+        parse("ban mallory"),
+        AdminCommand::Ban(AccessEntry::Name("mallory".to_string()))
+    );
+    assert!(matches!(
+        parse("ban 10.0.0.0/8"),
+        AdminCommand::Ban(AccessEntry::Ip(_))
+    ));
+    assert!(matches!(
+        parse("unban 10.0.0.1"),
+        AdminCommand::Unban(AccessEntry::Ip(_))
+    ));
+    assert_eq!(
+        parse("allow alice"),
+        AdminCommand::Allow(AccessEntry::Name("alice".to_string()))
+    );
+    assert_eq!(
+        parse("disallow alice"),
+        AdminCommand::Disallow(AccessEntry::Name("alice".to_string()))
+    );
+    assert_eq!(parse("allowlist on"), AdminCommand::SetAllowlist(true));
+    assert_eq!(parse("allowlist off"), AdminCommand::SetAllowlist(false));
+    assert_eq!(parse("bans"), AdminCommand::ShowAccess);
+    assert_eq!(
+        parse("broadcast back in five"),
+        AdminCommand::Broadcast("back in five".to_string())
+    );
+    assert_eq!(parse("list"), AdminCommand::List);
+    assert_eq!(
+        parse("shutdown"),
+        AdminCommand::Shutdown { restart_in: None }
+    );
+    assert_eq!(
+        parse("shutdown 30"),
+        AdminCommand::Shutdown {
+            restart_in: Some(Duration::from_secs(30)),
+        }
+    );
+}
+
+#[test]
+fn bad_admin_commands_get_usage() {
+    for (line, usage) in [
+        ("kick", "Usage: kick <name> [reason]"),
+        ("ban", "Usage: ban <name|ip|cidr>"),
+        ("unban", "Usage: unban <name|ip|cidr>"),
+        ("allow", "Usage: allow <name|ip|cidr>"),
+        ("disallow", "Usage: disallow <name|ip|cidr>"),
+        ("allowlist", "Usage: allowlist on|off"),
+        ("allowlist maybe", "Usage: allowlist on|off"),
+        ("broadcast", "Usage: broadcast <text>"),
+        ("shutdown soon", "Usage: shutdown [seconds until restart]"),
+        ("set", "Usage: set tickrate <hz>"),
+        ("set volume 11", "Usage: set tickrate <hz>"),
+        ("reboot", "Unknown command 'reboot'"),
+    ] {
+        match AdminCommand::parse(line) {
+            Err(reply) => assert!(reply.starts_with(usage), "{}: {}", line, reply),
+            Ok(command) => panic!("{} parsed as {:?}", line, command),
+        }
+    }
+}
+
+#[test]
+fn tick_rates_are_kept_in_range() {
+    assert_eq!(
+        AdminCommand::parse("set tickrate 20"),
+        Ok(AdminCommand::SetTickRate(20.0))
+    );
+    for bounds in ["0.01", "1000"] {
+        assert!(AdminCommand::parse(&format!("set tickrate {}", bounds)).is_ok());
+    }
+    // Any of these would panic turning the rate into a tick interval, or
+    // leave the server doing nothing but tick
+    for hz in ["0", "-5", "1e-20", "0.001", "1001", "inf", "NaN", "fast"] {
+        assert!(
+            AdminCommand::parse(&format!("set tickrate {}", hz)).is_err(),
+            "accepted {}",
+            hz
+        );
+    }
+}
+
+// Connects players with these names, one client each.
+async fn players(names: &[&str]) -> (Simulation, Vec<ClientId>) {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), 1);
+    let clients: Vec<_> = names.iter().map(|_| sim.add_client()).collect();
+    for (&client, name) in clients.iter().zip(names) {
+        sim.connect(client, &PlayerProfile::new(name, PlayerColor::Red));
+    }
+    sim.step().await.unwrap();
+    (sim, clients)
+}
+
+async fn say(sim: &mut Simulation, client: ClientId, text: &str) {
+    let chat = Chat {
+        text: text.to_string(),
+    };
+    sim.send(
+        client,
+        MessageType::ChatMessage,
+        serde_json::to_vec(&chat).unwrap(),
+    );
+    sim.step().await.unwrap();
+}
+
+fn chats(sim: &Simulation, client: ClientId) -> Vec<String> {
+    sim.client(client)
+        .received_of(MessageType::ChatMessage)
+        .map(|packet| {
+            serde_json::from_slice::<Chat>(&packet.payload)
+                .unwrap()
+                .text
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn who_lists_everyone_online() {
+    let (mut sim, clients) = players(&["carol", "alice", "bob"]).await;
+    say(&mut sim, clients[0], "/who").await;
+    assert_eq!(
+        chats(&sim, clients[0]).last().unwrap(),
+        "Online (3): alice, bob, carol"
+    );
+}
+
+#[tokio::test]
+async fn messages_to_unknown_names_go_nowhere() {
+    let (mut sim, clients) = players(&["alice", "bob"]).await;
+    let before = chats(&sim, clients[1]).len();
+    say(&mut sim, clients[0], "/msg zed are you there").await;
+    assert_eq!(
+        chats(&sim, clients[0]).last().unwrap(),
+        "No player named zed"
+    );
+    assert_eq!(chats(&sim, clients[1]).len(), before);
+}
+
+#[tokio::test]
+async fn taken_nicknames_get_a_number() {
+    let (mut sim, clients) = players(&["alice", "bob"]).await;
+    say(&mut sim, clients[0], "/nick BOB").await;
+    for &client in &clients {
+        assert_eq!(
+            chats(&sim, client).last().unwrap(),
+            "alice is now known as BOB2"
+        );
+    }
+    let state = sim.server().state().lock().await;
+    let addr = sim.client(clients[0]).addr.to_string();
+    assert_eq!(state.players[&addr].profile.name, "BOB2");
+}
+
+#[tokio::test]
+async fn admin_commands_reply_with_text() {
+    let (sim, _) = players(&["alice", "bob"]).await;
+    let admin = |line: &str| sim.server().admin(AdminCommand::parse(line).unwrap());
+    assert!(admin("list")
+        .await
+        .unwrap()
+        .starts_with("2 player(s) online"));
+    assert_eq!(admin("kick zed").await.unwrap(), "No player named zed");
+    assert_eq!(admin("kick bob").await.unwrap(), "Kicked bob");
+    assert_eq!(admin("ban mallory").await.unwrap(), "Banned mallory");
+    assert_eq!(
+        admin("ban mallory").await.unwrap(),
+        "mallory is already banned"
+    );
+}
@@ -0,0 +1,25 @@
+// The generated Wireshark dissector against the codec it describes.
+
+use game_udp::{dissector, MessageType};
+
+const CHECKED_IN: &str = include_str!("../wireshark/game_udp.lua");
+
+#[test]
+fn checked_in_dissector_is_current() {
+    assert!(
+        CHECKED_IN == dissector::lua(),
+        "wireshark/game_udp.lua is out of date, regenerate it with\n\n    \
+         cargo run --bin game_udp-inspect -- --dissector wireshark/game_udp.lua\n"
+    );
+}
+
+#[test]
+fn every_message_type_is_described() {
+    let lua = dissector::lua();
+    for msg_type in MessageType::ALL {
+        let entry = format!("[0x{:02x}] = \"{:?}\"", msg_type as u8, msg_type);
+        assert!(lua.contains(&entry), "missing {}", entry);
+    }
+    assert!(lua.contains("[0x07] = \"text\""));
+    assert!(lua.contains("[0x0a] = \"batch\""));
+}
@@ -0,0 +1,131 @@
+// Reassembly limits: what incomplete packets may cost, and for how long.
+
+use std::time::Instant;
+
+use bytes::{BufMut, BytesMut};
+use game_udp::{
+    fragment::{self, FragmentError, Reassembler, MAX_FRAGMENTS},
+    GamePacket, MessageType,
+};
+
+// Fragment `index` of `count` of packet `seq`, carrying `chunk`.
+fn fragment(seq: u32, index: u16, count: u16, chunk: &[u8]) -> GamePacket {
+    let mut payload = BytesMut::new();
+    payload.put_u16(index);
+    payload.put_u16(count);
+    payload.put_slice(chunk);
+    GamePacket::new(MessageType::Fragment, seq, payload.freeze())
+}
+
+// A real packet, split into fragments for `max_datagram`.
+fn split(seq: u32, len: usize, max_datagram: usize) -> (GamePacket, Vec<GamePacket>) {
+    let packet = GamePacket::new(MessageType::ChatMessage, seq, vec![b'x'; len]);
+    let fragments = fragment::split(&packet, max_datagram)
+        .unwrap()
+        .into_iter()
+        .map(|datagram| GamePacket::decode(datagram).unwrap())
+        .collect();
+    (packet, fragments)
+}
+
+#[test]
+fn packets_too_big_to_reassemble_are_not_split() {
+    let chunk = 100 - GamePacket::HEADER_LEN - fragment::FRAGMENT_HEADER_LEN;
+    let fits = MAX_FRAGMENTS as usize * chunk - GamePacket::HEADER_LEN;
+    let (_, fragments) = split(1, fits, 100);
+    assert_eq!(fragments.len(), MAX_FRAGMENTS as usize);
+
+    let packet = GamePacket::new(MessageType::ChatMessage, 1, vec![b'x'; fits + 1]);
+    assert_eq!(
+        fragment::split(&packet, 100),
+        Err(FragmentError::TooManyFragments(MAX_FRAGMENTS as usize + 1))
+    );
+}
+
+#[test]
+fn empty_slots_count_against_the_budget() {
+    let mut reassembler = Reassembler::new();
+    let now = Instant::now();
+    // One byte of data, but room kept for 64 chunks
+    reassembler
+        .insert("mallory", &fragment(1, 0, MAX_FRAGMENTS, b"x"), now)
+        .unwrap();
+    assert!(reassembler.pending_bytes() > 64 * 8);
+
+    // Flooding with new sequence numbers stays within the peer's limits
+    for seq in 2..10_000 {
+        reassembler
+            .insert("mallory", &fragment(seq, 0, MAX_FRAGMENTS, b"x"), now)
+            .unwrap();
+    }
+    assert!(reassembler.pending_packets() <= reassembler.max_partials_per_peer);
+    assert!(reassembler.pending_bytes() <= reassembler.max_peer_bytes);
+}
+
+#[test]
+fn one_peer_cannot_push_out_another() {
+    let mut reassembler = Reassembler::new();
+    let now = Instant::now();
+    let (packet, fragments) = split(7, 4000, 1200);
+    let (last, first) = fragments.split_last().unwrap();
+    for fragment in first {
+        assert_eq!(reassembler.insert("alice", fragment, now), Ok(None));
+    }
+
+    // Large fragments from many sequence numbers, well over every limit
+    let chunk = vec![0u8; 60_000];
+    for seq in 0..200 {
+        let _ = reassembler.insert("mallory", &fragment(seq, 0, 2, &chunk), now);
+        assert!(reassembler.pending_bytes() <= reassembler.max_pending_bytes);
+    }
+    assert_eq!(reassembler.insert("alice", last, now), Ok(Some(packet)));
+}
+
+#[test]
+fn the_total_budget_is_shared_fairly() {
+    let mut reassembler = Reassembler::new();
+    reassembler.max_pending_bytes = 200_000;
+    let now = Instant::now();
+    let chunk = vec![0u8; 50_000];
+    // Each peer stays under its own limit, but together they fill the budget
+    for (seq, peer) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+        let _ = reassembler.insert(peer, &fragment(seq as u32, 0, 2, &chunk), now);
+        assert!(reassembler.pending_bytes() <= reassembler.max_pending_bytes);
+    }
+    // The last to arrive is the one turned away
+    assert_eq!(reassembler.pending_packets(), 3);
+    for (seq, peer) in ["a", "b", "c"].into_iter().enumerate() {
+        let done = reassembler.insert(peer, &fragment(seq as u32, 1, 2, b"x"), now);
+        assert_eq!(done, Err(FragmentError::InvalidPacket));
+    }
+    assert_eq!(reassembler.pending_bytes(), 0);
+}
+
+#[test]
+fn incomplete_packets_time_out() {
+    let mut reassembler = Reassembler::new();
+    let start = Instant::now();
+    let timeout = reassembler.timeout;
+    let (packet, fragments) = split(3, 3000, 1200);
+    reassembler.insert("alice", &fragments[0], start).unwrap();
+    reassembler.insert("bob", &fragments[0], start).unwrap();
+
+    // Fragments arriving after the timeout start the packet over
+    let late = start + timeout;
+    for fragment in &fragments[1..] {
+        assert_eq!(reassembler.insert("alice", fragment, late), Ok(None));
+    }
+    assert_eq!(
+        reassembler.insert("alice", &fragments[0], late),
+        Ok(Some(packet))
+    );
+
+    // Everything else is swept up on a later insert
+    reassembler
+        .insert("carol", &fragments[0], late + timeout)
+        .unwrap();
+    assert_eq!(reassembler.pending_packets(), 1);
+    reassembler.expire(late + timeout * 2);
+    assert_eq!(reassembler.pending_packets(), 0);
+    assert_eq!(reassembler.pending_bytes(), 0);
+}
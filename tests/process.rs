@@ -0,0 +1,78 @@
+// The server binary as an operator runs it: started as a process and stopped
+// from the admin console or with a signal. Both use the server's fixed port,
+// so they run one after the other in a single test.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Starts the server in its own directory, with stdin left open the way a
+// terminal leaves it, and waits until it's listening.
+fn start(dir: &Path) -> Child {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_game_udp"))
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let log = dir.join("server.log");
+    while !fs::read_to_string(&log).is_ok_and(|log| log.contains("server listening")) {
+        assert!(started.elapsed() < TIMEOUT, "server never started");
+        thread::sleep(Duration::from_millis(20));
+    }
+    child
+}
+
+// Waits for the server to exit on its own, killing it if it doesn't.
+fn exits(child: &mut Child) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < TIMEOUT {
+        if child.try_wait().unwrap().is_some() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    false
+}
+
+fn scratch(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("game_udp-test-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn the_server_exits_when_stopped() {
+    let dir = scratch("process-console");
+    let mut child = start(&dir);
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"shutdown\n").unwrap();
+    assert!(exits(&mut child), "server kept running after shutdown");
+    drop(stdin);
+    fs::remove_dir_all(&dir).unwrap();
+
+    #[cfg(unix)]
+    {
+        let dir = scratch("process-signal");
+        let mut child = start(&dir);
+        let killed = Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+        assert!(exits(&mut child), "server kept running after SIGTERM");
+        drop(child.stdin.take());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+// Display names: cleaning up what clients ask for, keeping names unique, and
+// shortening them to initials.
+
+use std::time::Instant;
+
+use game_udp::{sanitize_name, PlayerColor, PlayerProfile, PlayerState, ServerState, MAX_NAME_LEN};
+
+// A server with these players already connected.
+fn server_with(names: &[&str]) -> ServerState {
+    let mut state = ServerState::new((40, 20));
+    for (number, name) in names.iter().enumerate() {
+        let profile = PlayerProfile::new(name, PlayerColor::Red);
+        let player = PlayerState::new(number as u32, profile, String::new(), Instant::now());
+        state
+            .players
+            .insert(format!("10.0.0.{}:4000", number), player);
+    }
+    state
+}
+
+fn name_given(state: &ServerState, requested: &str) -> String {
+    let profile = PlayerProfile::new(requested, PlayerColor::Blue);
+    state.resolve_profile(Some(profile), 7).name
+}
+
+#[test]
+fn names_are_cleaned_up() {
+    assert_eq!(sanitize_name("  alice "), Some("alice".to_string()));
+    assert_eq!(sanitize_name("Big Al_2-x"), Some("Big Al_2-x".to_string()));
+    assert_eq!(sanitize_name("al<ice>!"), Some("alice".to_string()));
+    assert_eq!(sanitize_name("Zoë"), Some("Zoë".to_string()));
+    assert_eq!(sanitize_name("名前"), Some("名前".to_string()));
+    assert_eq!(sanitize_name("al🙂ice"), Some("alice".to_string()));
+}
+
+#[test]
+fn empty_names_are_refused() {
+    for name in ["", "   ", "\t\n", "!!!", "🙂"] {
+        assert_eq!(sanitize_name(name), None, "{:?}", name);
+    }
+}
+
+#[test]
+fn long_names_are_cut_short() {
+    let long = "abcdefghijklmnopqrstuvwxyz";
+    assert_eq!(sanitize_name(long).unwrap(), &long[..MAX_NAME_LEN]);
+    // Counted in characters, not bytes
+    let accents = "é".repeat(MAX_NAME_LEN + 4);
+    assert_eq!(
+        sanitize_name(&accents).unwrap().chars().count(),
+        MAX_NAME_LEN
+    );
+    // Cutting can leave a trailing space, which goes too
+    assert_eq!(
+        sanitize_name("abcdefghijklmno pqr").unwrap(),
+        "abcdefghijklmno"
+    );
+}
+
+#[test]
+fn missing_names_get_a_numbered_default() {
+    let state = server_with(&[]);
+    let profile = state.resolve_profile(None, 7);
+    assert_eq!(
+        profile,
+        PlayerProfile::new("Player7", PlayerColor::default())
+    );
+    // A profile with an unusable name keeps its color
+    for name in ["", "   ", "???"] {
+        let requested = PlayerProfile::new(name, PlayerColor::Blue);
+        let profile = state.resolve_profile(Some(requested), 7);
+        assert_eq!(profile, PlayerProfile::new("Player7", PlayerColor::Blue));
+    }
+}
+
+#[test]
+fn taken_names_get_a_number() {
+    let state = server_with(&["alice", "Bob", "bob2", "Zoë"]);
+    assert_eq!(name_given(&state, "carol"), "carol");
+    assert_eq!(name_given(&state, " alice "), "alice2");
+    // Case doesn't make a name different
+    assert_eq!(name_given(&state, "ALICE"), "ALICE2");
+    assert_eq!(name_given(&state, "bob"), "bob3");
+    assert_eq!(name_given(&state, "Zoë"), "Zoë2");
+}
+
+#[test]
+fn numbered_names_stay_within_the_limit() {
+    let full = "abcdefghijklmnop";
+    assert_eq!(full.len(), MAX_NAME_LEN);
+    let state = server_with(&[full]);
+    assert_eq!(name_given(&state, full), "abcdefghijklmno2");
+    let state = server_with(&[full, "abcdefghijklmno2"]);
+    assert_eq!(
+        name_given(&state, "abcdefghijklmnopqrs"),
+        "abcdefghijklmno3"
+    );
+}
+
+#[test]
+fn initials_come_from_the_first_two_words() {
+    let initials = |name: &str| PlayerProfile::new(name, PlayerColor::Red).initials();
+    assert_eq!(initials("alice"), "A");
+    assert_eq!(initials("big al"), "BA");
+    assert_eq!(initials("mary-jane watson"), "MJ");
+    assert_eq!(initials("the_real_deal"), "TR");
+    assert_eq!(initials("zoë ärt"), "ZÄ");
+    assert_eq!(initials(""), "");
+}
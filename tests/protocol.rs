@@ -0,0 +1,119 @@
+// The protocol specification against the codec and the checked-in vectors.
+
+use bytes::Bytes;
+use game_udp::{
+    protocol::{self, Sender, HEADER},
+    GamePacket, MessageType, MoveInput, Position,
+};
+
+const CHECKED_IN: &str = include_str!("../protocol/vectors.json");
+
+#[test]
+fn checked_in_vectors_are_current() {
+    assert!(
+        CHECKED_IN == protocol::vectors_json(),
+        "protocol/vectors.json no longer matches what the codec encodes. If the \
+         change was deliberate, regenerate it with\n\n    \
+         cargo run --bin game_udp-inspect -- --vectors protocol/vectors.json\n"
+    );
+}
+
+#[test]
+fn every_vector_matches_its_schema() {
+    for vector in protocol::vectors() {
+        assert!(!vector.packets().is_empty(), "{} is empty", vector.name);
+        for packet in vector.packets() {
+            if let Err(e) = protocol::validate(&packet, vector.sender) {
+                panic!("{}: {}", vector.name, e);
+            }
+        }
+        // Batches and fragments are checked as they go on the wire too
+        for datagram in &vector.datagrams {
+            let packet = GamePacket::decode(datagram.clone()).unwrap();
+            if let Err(e) = protocol::validate(&packet, vector.sender) {
+                panic!("{} datagram: {}", vector.name, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn every_message_has_a_vector() {
+    let vectors = protocol::vectors();
+    for message in protocol::MESSAGES {
+        let covered = vectors.iter().any(|vector| {
+            let outer = vector
+                .datagrams
+                .iter()
+                .filter_map(|d| GamePacket::decode(d.clone()));
+            vector.sender == message.sender
+                && vector
+                    .packets()
+                    .into_iter()
+                    .chain(outer)
+                    .any(|p| p.msg_type == message.msg_type)
+        });
+        assert!(
+            covered,
+            "no vector for {:?} from the {:?}",
+            message.msg_type, message.sender
+        );
+    }
+}
+
+#[test]
+fn every_message_type_has_a_schema() {
+    for msg_type in MessageType::ALL {
+        assert!(
+            protocol::MESSAGES.iter().any(|m| m.msg_type == msg_type),
+            "{:?} isn't described",
+            msg_type
+        );
+    }
+}
+
+#[test]
+fn header_layout_matches_the_codec() {
+    let packet = GamePacket::new(
+        MessageType::ChatMessage,
+        0x0102_0304,
+        Bytes::from_static(b"{}"),
+    );
+    let bytes = packet.serialize();
+    let field = |name| HEADER.iter().find(|f| f.name == name).unwrap();
+    let read = |name| {
+        let f = field(name);
+        bytes[f.offset..f.offset + f.len]
+            .iter()
+            .fold(0u64, |n, &b| n << 8 | b as u64)
+    };
+    assert_eq!(read("type"), MessageType::ChatMessage as u64);
+    assert_eq!(read("version"), protocol::VERSION as u64);
+    assert_eq!(read("seq"), 0x0102_0304);
+    let end = HEADER.iter().map(|f| f.offset + f.len).max().unwrap();
+    assert_eq!(end, GamePacket::HEADER_LEN);
+    assert_eq!(&bytes[end..], b"{}");
+}
+
+#[test]
+fn schema_mismatches_are_rejected() {
+    let input = MoveInput {
+        input_seq: 7,
+        position: Position::new(1, 2, 0),
+    };
+    let good = GamePacket::new(MessageType::PositionUpdate, 1, input.serialize());
+    assert_eq!(protocol::validate(&good, Sender::Client), Ok(()));
+    // The client's move input isn't what the server sends back
+    assert!(protocol::validate(&good, Sender::Server).is_err());
+
+    let text = String::from_utf8(input.serialize().to_vec()).unwrap();
+    for bad in [
+        text.replace("input_seq", "inputSeq"),
+        text.replace("\"input_seq\":7,", ""),
+        text.replace("\"input_seq\":7", "\"input_seq\":\"7\""),
+    ] {
+        assert_ne!(bad, text);
+        let packet = GamePacket::new(MessageType::PositionUpdate, 1, Bytes::from(bad));
+        assert!(protocol::validate(&packet, Sender::Client).is_err());
+    }
+}
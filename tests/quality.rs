@@ -0,0 +1,122 @@
+// RTT, jitter and loss measured from heartbeats.
+
+use std::time::{Duration, Instant};
+
+use game_udp::quality::{ConnectionQuality, Heartbeat, HeartbeatEcho};
+
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+// A heartbeat from the peer with no echo, for counting loss.
+fn heartbeat(id: u32) -> Heartbeat {
+    Heartbeat {
+        id,
+        sent_at_us: 0,
+        echo: None,
+        stats: None,
+    }
+}
+
+#[test]
+fn rtt_leaves_out_the_time_the_peer_held_on() {
+    let start = Instant::now();
+    let mut ours = ConnectionQuality::new(start);
+    let mut theirs = ConnectionQuality::new(start + ms(5000));
+
+    // Their clock started at a different time, which doesn't matter
+    let sent = ours.heartbeat(start);
+    theirs.on_heartbeat(&sent, start + ms(20));
+    let reply = theirs.heartbeat(start + ms(70));
+    assert_eq!(reply.echo.unwrap().held_us, 50_000);
+    let sample = ours.on_heartbeat(&reply, start + ms(110));
+    assert_eq!(sample, Some(ms(60)));
+    assert_eq!(ours.rtt(), Some(ms(60)));
+    let stats = ours.stats();
+    assert_eq!(stats.rtt_ms, Some(60.0));
+    assert_eq!(stats.jitter_ms, 30.0);
+}
+
+#[test]
+fn samples_are_smoothed() {
+    let start = Instant::now();
+    let mut quality = ConnectionQuality::new(start);
+    let mut now = start;
+    for (id, rtt) in [100, 100, 180, 100].into_iter().enumerate() {
+        let sent_at = now;
+        now += ms(rtt);
+        let echoed = Heartbeat {
+            echo: Some(HeartbeatEcho {
+                id: id as u32,
+                sent_at_us: sent_at.duration_since(start).as_micros() as u64,
+                held_us: 0,
+            }),
+            ..heartbeat(id as u32)
+        };
+        assert_eq!(quality.on_heartbeat(&echoed, now), Some(ms(rtt)));
+    }
+    // srtt: 100, 100, 110, 108.75; rttvar: 50, 37.5, 48.125, 38.59375
+    assert_eq!(quality.rtt(), Some(Duration::from_micros(108_750)));
+    let stats = quality.stats();
+    assert!(
+        (stats.jitter_ms - 38.59375).abs() < 1e-6,
+        "{}",
+        stats.jitter_ms
+    );
+}
+
+#[test]
+fn echoes_from_the_future_are_ignored() {
+    let start = Instant::now();
+    let mut quality = ConnectionQuality::new(start);
+    let bogus = Heartbeat {
+        echo: Some(HeartbeatEcho {
+            id: 0,
+            sent_at_us: 10_000_000,
+            held_us: 0,
+        }),
+        ..heartbeat(0)
+    };
+    assert_eq!(quality.on_heartbeat(&bogus, start + ms(10)), None);
+    assert_eq!(quality.rtt(), None);
+}
+
+#[test]
+fn gaps_in_the_ids_count_as_loss() {
+    let now = Instant::now();
+    let mut quality = ConnectionQuality::new(now);
+    assert_eq!(quality.stats().loss_percent, 0.0);
+    for id in [0, 1, 3, 4, 7] {
+        quality.on_heartbeat(&heartbeat(id), now);
+    }
+    // 5 of 0..=7 arrived
+    assert_eq!(quality.stats().loss_percent, 37.5);
+    // Latecomers fill the gaps back in
+    quality.on_heartbeat(&heartbeat(2), now);
+    quality.on_heartbeat(&heartbeat(6), now);
+    assert_eq!(quality.stats().loss_percent, 12.5);
+}
+
+#[test]
+fn loss_is_counted_across_wraparound() {
+    let now = Instant::now();
+    let mut quality = ConnectionQuality::new(now);
+    let first = u32::MAX - 4;
+    // Ten ids from u32::MAX - 4 round to 4, every other one lost
+    for step in (0..10).step_by(2) {
+        quality.on_heartbeat(&heartbeat(first.wrapping_add(step)), now);
+    }
+    quality.on_heartbeat(&heartbeat(first.wrapping_add(9)), now);
+    assert_eq!(quality.stats().loss_percent, 40.0);
+}
+
+#[test]
+fn heartbeats_overtaken_by_the_first_are_not_loss() {
+    let now = Instant::now();
+    let mut quality = ConnectionQuality::new(now);
+    quality.on_heartbeat(&heartbeat(0), now);
+    // Sent before 0, across the wrap, and arrived after it
+    quality.on_heartbeat(&heartbeat(u32::MAX), now);
+    quality.on_heartbeat(&heartbeat(1), now);
+    assert_eq!(quality.stats().loss_percent, 0.0);
+}
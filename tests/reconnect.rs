@@ -0,0 +1,87 @@
+// When the client gives up on the server and tries to get back in.
+
+use std::time::{Duration, Instant};
+
+use game_udp::reconnect::{retry_delay, Link, LinkState};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn connects_straight_away_and_retries_until_answered() {
+    let start = Instant::now();
+    let mut link = Link::new(TIMEOUT, start);
+    assert_eq!(link.state(), LinkState::Connecting);
+    assert!(link.poll(start));
+    assert!(!link.poll(start));
+    assert_eq!(link.deadline(), start + retry_delay(0));
+    assert!(link.poll(link.deadline()));
+    assert_eq!(link.attempts(), 2);
+
+    let answered = start + Duration::from_millis(1500);
+    link.heard(answered);
+    assert_eq!(link.state(), LinkState::Connected);
+    assert_eq!(link.attempts(), 0);
+    assert_eq!(link.deadline(), answered + TIMEOUT);
+}
+
+#[test]
+fn silence_starts_reconnecting() {
+    let start = Instant::now();
+    let mut link = Link::new(TIMEOUT, start);
+    link.poll(start);
+    link.heard(start);
+    // Hearing from the server keeps pushing the timeout back
+    for second in 1..=20 {
+        let now = start + Duration::from_secs(second);
+        assert!(!link.poll(now));
+        link.heard(now);
+    }
+    let last_heard = start + Duration::from_secs(20);
+    assert!(!link.poll(last_heard + TIMEOUT - Duration::from_millis(1)));
+    assert_eq!(link.state(), LinkState::Connected);
+
+    let gone = last_heard + TIMEOUT;
+    assert!(link.poll(gone));
+    assert_eq!(link.state(), LinkState::Reconnecting);
+    assert_eq!(link.attempts(), 1);
+
+    // Attempts back off, up to a limit
+    let mut now = gone;
+    let mut gaps = Vec::new();
+    for _ in 0..8 {
+        let next = link.deadline();
+        assert!(!link.poll(next - Duration::from_millis(1)));
+        assert!(link.poll(next));
+        gaps.push((next - now).as_secs());
+        now = next;
+    }
+    assert_eq!(gaps, [1, 2, 4, 8, 16, 30, 30, 30]);
+}
+
+#[test]
+fn shutdown_notices_skip_the_timeout() {
+    let start = Instant::now();
+    let mut link = Link::new(TIMEOUT, start);
+    link.poll(start);
+    link.heard(start);
+
+    // Told when the server will be back, wait that long before trying
+    let restart_in = Duration::from_secs(45);
+    link.server_stopped(start, Some(restart_in));
+    assert_eq!(link.state(), LinkState::Reconnecting);
+    assert!(!link.poll(start + restart_in - Duration::from_millis(1)));
+    assert!(link.poll(start + restart_in));
+    assert_eq!(link.attempts(), 1);
+
+    // Otherwise try again shortly, well before the timeout would have
+    link.heard(start);
+    link.server_stopped(start, None);
+    assert_eq!(link.deadline(), start + retry_delay(0));
+    assert!(link.deadline() < start + TIMEOUT);
+}
+
+#[test]
+fn retry_delay_saturates() {
+    assert_eq!(retry_delay(0), Duration::from_secs(1));
+    assert_eq!(retry_delay(u32::MAX), retry_delay(5));
+}
@@ -0,0 +1,89 @@
+// Serial number comparison and the duplicate-detecting receive window.
+
+use game_udp::sequence::{seq_greater, ReceiveWindow, SeqCheck, SequenceCounter};
+
+const NEW: SeqCheck = SeqCheck::New { newest: true };
+const LATE: SeqCheck = SeqCheck::New { newest: false };
+
+#[test]
+fn comparisons_allow_for_wraparound() {
+    assert!(seq_greater(2, 1));
+    assert!(!seq_greater(1, 2));
+    assert!(!seq_greater(7, 7));
+    assert!(seq_greater(0, u32::MAX));
+    assert!(seq_greater(5, u32::MAX - 5));
+    assert!(!seq_greater(u32::MAX, 0));
+    // Half the space apart, neither is newer
+    assert!(!seq_greater(1 << 31, 0));
+    assert!(!seq_greater(0, 1 << 31));
+    assert!(seq_greater((1 << 31) - 1, 0));
+}
+
+#[test]
+fn counters_wrap() {
+    let counter = SequenceCounter::new(u32::MAX);
+    assert_eq!(counter.next(), u32::MAX);
+    assert_eq!(counter.next(), 0);
+    assert_eq!(counter.next(), 1);
+}
+
+#[test]
+fn duplicates_and_late_packets_are_told_apart() {
+    let mut window = ReceiveWindow::new();
+    assert_eq!(window.check(10), NEW);
+    assert_eq!(window.check(10), SeqCheck::Duplicate);
+    assert_eq!(window.check(12), NEW);
+    assert_eq!(window.check(11), LATE);
+    assert_eq!(window.check(11), SeqCheck::Duplicate);
+    assert_eq!(window.check(9), LATE);
+    assert_eq!(window.highest(), Some(12));
+}
+
+#[test]
+fn the_window_slides_across_wraparound() {
+    let mut window = ReceiveWindow::new();
+    let start = u32::MAX - 2;
+    assert_eq!(window.check(start), NEW);
+    assert_eq!(window.check(start.wrapping_add(5)), NEW);
+    assert_eq!(window.highest(), Some(2));
+    // Numbers from before the wrap are behind, not ahead
+    assert_eq!(window.check(u32::MAX), LATE);
+    assert_eq!(window.check(start), SeqCheck::Duplicate);
+    assert_eq!(window.check(u32::MAX), SeqCheck::Duplicate);
+    assert_eq!(window.check(0), LATE);
+    assert_eq!(window.check(1), LATE);
+    assert_eq!(window.check(u32::MAX - 1), LATE);
+}
+
+#[test]
+fn big_jumps_forget_what_came_before() {
+    let mut window = ReceiveWindow::new();
+    window.check(100);
+    window.check(99);
+    // Exactly the window size: the old bits shift out
+    assert_eq!(window.check(100 + ReceiveWindow::SIZE), NEW);
+    assert_eq!(window.check(100), SeqCheck::TooOld);
+    assert_eq!(window.check(101), LATE);
+    assert_eq!(window.check(101), SeqCheck::Duplicate);
+
+    // Further than the window, across the wrap
+    let mut window = ReceiveWindow::new();
+    window.check(u32::MAX - 10);
+    assert_eq!(window.check(1000), NEW);
+    assert_eq!(window.check(u32::MAX - 10), SeqCheck::TooOld);
+    assert_eq!(window.check(1000 - ReceiveWindow::SIZE), SeqCheck::TooOld);
+    assert_eq!(window.check(1000 - ReceiveWindow::SIZE + 1), LATE);
+}
+
+#[test]
+fn the_oldest_slot_is_still_tracked() {
+    let mut window = ReceiveWindow::new();
+    window.check(500);
+    let oldest = 500 - (ReceiveWindow::SIZE - 1);
+    assert_eq!(window.check(oldest), LATE);
+    assert_eq!(window.check(oldest), SeqCheck::Duplicate);
+    assert_eq!(window.check(oldest - 1), SeqCheck::TooOld);
+    // One step forward pushes it out
+    window.check(501);
+    assert_eq!(window.check(oldest), SeqCheck::TooOld);
+}
@@ -0,0 +1,438 @@
+// End-to-end protocol tests: a real server on an ephemeral localhost port,
+// driven by scripted clients.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use game_udp::{
+    access::AccessFile,
+    commands::AdminCommand,
+    fragment::{Reassembler, RECV_BUFFER_SIZE},
+    metrics::Metrics,
+    sequence::SequenceCounter,
+    server::{Config, Server},
+    storage::{PlayerRecord, Storage},
+    Chat, GamePacket, MessageType, MoveAck, MoveInput, PlayerColor, PlayerJoin, PlayerProfile,
+    PlayerUpdate, Position, ServerShutdown, ServerStateSend,
+};
+use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
+use tokio::{
+    net::UdpSocket,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+const BOARD_SIZE: (u32, u32) = (40, 20);
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestServer {
+    server: Server,
+    addr: SocketAddr,
+    task: JoinHandle<std::io::Result<()>>,
+}
+
+impl TestServer {
+    async fn start(name: &str) -> TestServer {
+        TestServer::start_with(name, Config::new(BOARD_SIZE)).await
+    }
+
+    async fn start_with(name: &str, config: Config) -> TestServer {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        // A file that doesn't exist yet gives empty access lists
+        let access_path = std::env::temp_dir().join(format!(
+            "game_udp-test-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        let access = AccessFile::open(access_path).unwrap();
+        let (server, handoffs) =
+            Server::new(vec![socket], access, Arc::new(Metrics::new()), config);
+        let task = tokio::spawn(server.clone().run(handoffs));
+        TestServer { server, addr, task }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct TestClient {
+    socket: UdpSocket,
+    seq: SequenceCounter,
+    reassembler: Reassembler<()>,
+    received: VecDeque<GamePacket>,
+}
+
+impl TestClient {
+    async fn new(server: SocketAddr) -> TestClient {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        TestClient {
+            socket,
+            seq: SequenceCounter::default(),
+            reassembler: Reassembler::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    // Joins as `name` and returns the snapshot the server answers with.
+    async fn connect(server: SocketAddr, name: &str) -> (TestClient, ServerStateSend) {
+        let mut client = TestClient::new(server).await;
+        let profile = PlayerProfile::new(name, PlayerColor::Green);
+        client
+            .send(MessageType::ConnectionInit, profile.serialize())
+            .await;
+        let reply = client.expect(MessageType::ConnectionInit).await;
+        let snapshot = ServerStateSend::deserialize(&reply.payload).unwrap();
+        (client, snapshot)
+    }
+
+    // The address the server knows this client by.
+    fn addr(&self) -> String {
+        self.socket.local_addr().unwrap().to_string()
+    }
+
+    async fn send(&self, msg_type: MessageType, payload: Vec<u8>) {
+        let packet = GamePacket::new(msg_type, self.seq.next(), payload);
+        self.socket.send(&packet.serialize()).await.unwrap();
+    }
+
+    async fn send_move(&self, input_seq: u32, position: Position) {
+        let input = MoveInput {
+            input_seq,
+            position,
+        };
+        self.send(MessageType::PositionUpdate, input.serialize())
+            .await;
+    }
+
+    async fn send_chat(&self, text: &str) {
+        let chat = Chat {
+            text: text.to_string(),
+        };
+        self.send(MessageType::ChatMessage, serde_json::to_vec(&chat).unwrap())
+            .await;
+    }
+
+    async fn recv(&mut self) -> GamePacket {
+        loop {
+            if let Some(packet) = self.received.pop_front() {
+                return packet;
+            }
+            let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+            let len = time::timeout(RECV_TIMEOUT, self.socket.recv(&mut buf))
+                .await
+                .expect("timed out waiting for the server")
+                .unwrap();
+            buf.truncate(len);
+            for message in GamePacket::messages(Bytes::from(buf)) {
+                let packet = message.expect("server sent an undecodable message");
+                let packet = match packet.msg_type {
+                    MessageType::Fragment => {
+                        match self
+                            .reassembler
+                            .insert((), &packet, Instant::now().into_std())
+                            .unwrap()
+                        {
+                            Some(packet) => packet,
+                            None => continue,
+                        }
+                    }
+                    _ => packet,
+                };
+                self.received.push_back(packet);
+            }
+        }
+    }
+
+    // Waits for a packet of the given type, skipping everything else.
+    async fn expect(&mut self, msg_type: MessageType) -> GamePacket {
+        loop {
+            let packet = self.recv().await;
+            if packet.msg_type as u8 == msg_type as u8 {
+                return packet;
+            }
+        }
+    }
+
+    // Waits for a chat message with the given text.
+    async fn expect_chat(&mut self, text: &str) {
+        loop {
+            let packet = self.expect(MessageType::ChatMessage).await;
+            let chat: Chat = serde_json::from_slice(&packet.payload).unwrap();
+            if chat.text == text {
+                return;
+            }
+        }
+    }
+
+    async fn expect_ack(&mut self) -> MoveAck {
+        let packet = self.expect(MessageType::ConfirmPlayerMovement).await;
+        MoveAck::deserialize(&packet.payload).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn connection_init_returns_snapshot() {
+    let server = TestServer::start("snapshot").await;
+    let (alice, snapshot) = TestClient::connect(server.addr, "alice").await;
+    assert_eq!(snapshot.board_size, BOARD_SIZE);
+    assert_eq!(snapshot.players.len(), 1);
+    assert_eq!(snapshot.players[&alice.addr()].profile.name, "alice");
+
+    let (bob, snapshot) = TestClient::connect(server.addr, "bob").await;
+    assert_eq!(snapshot.players.len(), 2);
+    assert_eq!(snapshot.players[&alice.addr()].profile.name, "alice");
+    assert_eq!(snapshot.players[&bob.addr()].profile.name, "bob");
+}
+
+#[tokio::test]
+async fn joins_and_leaves_are_broadcast() {
+    let server = TestServer::start("join-leave").await;
+    let (mut alice, _) = TestClient::connect(server.addr, "alice").await;
+    let (bob, _) = TestClient::connect(server.addr, "bob").await;
+
+    let join = alice.expect(MessageType::PlayerJoin).await;
+    let join = PlayerJoin::deserialize(&join.payload).unwrap();
+    assert_eq!(join.player, bob.addr());
+    assert_eq!(join.profile.name, "bob");
+
+    server
+        .server
+        .admin(AdminCommand::Kick {
+            name: "bob".to_string(),
+            reason: String::new(),
+        })
+        .await
+        .unwrap();
+    let left = alice.expect(MessageType::PlayerLeft).await;
+    assert_eq!(&left.payload[..], bob.addr().as_bytes());
+}
+
+#[tokio::test]
+async fn moves_off_the_board_are_rejected() {
+    let server = TestServer::start("board-edges").await;
+    let (mut alice, _) = TestClient::connect(server.addr, "alice").await;
+    let (mut bob, _) = TestClient::connect(server.addr, "bob").await;
+    let (width, height) = (BOARD_SIZE.0 as i32, BOARD_SIZE.1 as i32);
+
+    // The far corner of the board is still on it
+    let corner = Position::new(-width / 2, height / 2 - 1, 0);
+    alice.send_move(1, corner.clone()).await;
+    let ack = alice.expect_ack().await;
+    assert_eq!(ack.input_seq, 1);
+    assert_eq!((ack.position.x, ack.position.y), (corner.x, corner.y));
+    let update = bob.expect(MessageType::PositionUpdate).await;
+    let update = PlayerUpdate::deserialize(&update.payload).unwrap();
+    assert_eq!(update.player, alice.addr());
+
+    let outside = [
+        Position::new(width / 2, 0, 0),
+        Position::new(-width / 2 - 1, 0, 0),
+        Position::new(0, height / 2, 0),
+        Position::new(0, -height / 2 + 1, 0),
+    ];
+    for (i, position) in outside.into_iter().enumerate() {
+        let input_seq = i as u32 + 2;
+        alice.send_move(input_seq, position).await;
+        // Rejected moves are answered with the position the server kept
+        let ack = alice.expect_ack().await;
+        assert_eq!(ack.input_seq, input_seq);
+        assert_eq!((ack.position.x, ack.position.y), (corner.x, corner.y));
+    }
+}
+
+#[tokio::test]
+async fn chat_reaches_every_player() {
+    let server = TestServer::start("chat").await;
+    let (mut alice, _) = TestClient::connect(server.addr, "alice").await;
+    let (mut bob, _) = TestClient::connect(server.addr, "bob").await;
+    let (mut carol, _) = TestClient::connect(server.addr, "carol").await;
+
+    alice.send_chat("hello everyone").await;
+    for client in [&mut alice, &mut bob, &mut carol] {
+        client.expect_chat("hello everyone").await;
+    }
+}
+
+#[tokio::test]
+async fn shutting_down_tells_every_player() {
+    let mut server = TestServer::start("shutdown").await;
+    let (mut alice, _) = TestClient::connect(server.addr, "alice").await;
+    let (mut bob, _) = TestClient::connect(server.addr, "bob").await;
+
+    server
+        .server
+        .admin(AdminCommand::Shutdown {
+            restart_in: Some(Duration::from_secs(30)),
+        })
+        .await
+        .unwrap();
+    for client in [&mut alice, &mut bob] {
+        let notice = client.expect(MessageType::ServerShutdown).await;
+        let notice = ServerShutdown::deserialize(&notice.payload).unwrap();
+        assert_eq!(notice.restart_in(), Some(Duration::from_secs(30)));
+    }
+    // Run returns once its tasks have stopped
+    let finished = time::timeout(RECV_TIMEOUT, &mut server.task)
+        .await
+        .expect("server kept running after shutdown");
+    finished.unwrap().unwrap();
+}
+
+// Storage whose first save blows up, taking the task that made it down.
+#[derive(Debug, Default)]
+struct FailingStorage {
+    failed: AtomicBool,
+}
+
+impl Storage for FailingStorage {
+    fn load(&self, _identity: &str) -> io::Result<Option<PlayerRecord>> {
+        Ok(None)
+    }
+
+    fn save(&self, _records: Vec<(String, PlayerRecord)>) -> io::Result<()> {
+        if !self.failed.swap(true, Ordering::Relaxed) {
+            panic!("storage went away");
+        }
+        Ok(())
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn failing_tasks_still_shut_down() {
+    let mut config = Config::new(BOARD_SIZE);
+    config.storage = Some(Arc::new(FailingStorage::default()));
+    config.autosave_interval = Duration::from_millis(200);
+    let mut server = TestServer::start_with("task-failure", config).await;
+    let (mut alice, _) = TestClient::connect(server.addr, "alice").await;
+
+    let notice = alice.expect(MessageType::ServerShutdown).await;
+    let notice = ServerShutdown::deserialize(&notice.payload).unwrap();
+    assert_eq!(notice.reason, "Server error");
+    let finished = time::timeout(RECV_TIMEOUT, &mut server.task)
+        .await
+        .expect("server kept running after a task failed");
+    assert!(finished.unwrap().is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn silent_players_time_out() {
+    let server = TestServer::start("heartbeat-timeout").await;
+    let (alice, _) = TestClient::connect(server.addr, "alice").await;
+    let (mut bob, _) = TestClient::connect(server.addr, "bob").await;
+    let started = Instant::now();
+
+    // Bob keeps answering while alice goes quiet
+    let left = loop {
+        bob.send(MessageType::Heartbeat, Vec::new()).await;
+        let next_heartbeat = time::sleep(Duration::from_secs(1));
+        tokio::pin!(next_heartbeat);
+        let left = tokio::select! {
+            packet = bob.expect(MessageType::PlayerLeft) => Some(packet),
+            _ = &mut next_heartbeat => None,
+        };
+        if let Some(left) = left {
+            break left;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(90),
+            "alice was never dropped"
+        );
+    };
+    assert_eq!(&left.payload[..], alice.addr().as_bytes());
+    // After the grace period for reconnecting runs out
+    let config = Config::new(BOARD_SIZE);
+    assert!(started.elapsed() >= config.heartbeat_timeout + config.linkdead_grace);
+
+    let state = server.server.state().lock().await;
+    assert!(!state.players.contains_key(&alice.addr()));
+    assert!(state.players.contains_key(&bob.addr()));
+}
+
+// Mostly on or near the board, sometimes at the limits of the type.
+fn coordinate(rng: &mut StdRng) -> i32 {
+    match rng.random_range(0..4) {
+        0 => *[i32::MIN, i32::MIN + 1, i32::MAX].choose(rng).unwrap(),
+        1 => rng.random(),
+        _ => rng.random_range(-30..30),
+    }
+}
+
+// Garbage, corrupted packets and out-of-range values from a connected peer
+// are dropped without taking the server down.
+#[tokio::test]
+async fn hostile_datagrams_are_survived() {
+    let server = TestServer::start("hostile").await;
+    let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let mut reassembler = Reassembler::new();
+    let profile = PlayerProfile::new("mallory", PlayerColor::Red);
+    let init = GamePacket::new(MessageType::ConnectionInit, 1, profile.serialize());
+    server
+        .server
+        .dispatch(&mut reassembler, init.serialize(), peer)
+        .await
+        .unwrap();
+
+    let types = [
+        MessageType::PositionUpdate,
+        MessageType::ChatMessage,
+        MessageType::Heartbeat,
+        MessageType::ConnectionInit,
+        MessageType::Fragment,
+        MessageType::Batch,
+    ];
+    let mut rng = StdRng::seed_from_u64(0x686f_7374);
+    for seq in 2..2000 {
+        let msg_type = *types.choose(&mut rng).unwrap();
+        let payload = match rng.random_range(0..3) {
+            0 => (0..rng.random_range(0..64)).map(|_| rng.random()).collect(),
+            1 => MoveInput {
+                input_seq: rng.random(),
+                position: Position::new(coordinate(&mut rng), coordinate(&mut rng), 0),
+            }
+            .serialize(),
+            _ => {
+                let text = if rng.random_bool(0.5) {
+                    "/nick"
+                } else {
+                    "/msg"
+                };
+                let chat = Chat {
+                    text: format!("{} {}", text, rng.random::<u32>()),
+                };
+                serde_json::to_vec(&chat).unwrap()
+            }
+        };
+        let mut data = GamePacket::new(msg_type, seq, payload).serialize().to_vec();
+        if rng.random_bool(0.3) {
+            let i = rng.random_range(0..data.len());
+            data[i] = rng.random();
+        }
+        server
+            .server
+            .dispatch(&mut reassembler, Bytes::from(data), peer)
+            .await
+            .unwrap();
+    }
+
+    // Still serving everyone else
+    let (alice, snapshot) = TestClient::connect(server.addr, "alice").await;
+    assert!(snapshot.players.contains_key(&alice.addr()));
+}
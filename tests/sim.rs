@@ -0,0 +1,541 @@
+// Whole-server runs in the deterministic simulator.
+
+use std::{fs, sync::Arc, time::Duration};
+
+use game_udp::{
+    commands::AdminCommand,
+    conditioner::{Direction, LinkConditions},
+    server::Config,
+    sim::{ClientId, Simulation},
+    storage::{FileStorage, Storage},
+    Chat, ConnectionRequest, GamePacket, MessageType, MoveInput, PlayerColor, PlayerJoin,
+    PlayerProfile, Position, ServerStateSend, Session,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const BOARD_SIZE: (u32, u32) = (40, 20);
+
+type Trace = (Vec<Vec<(Duration, GamePacket)>>, Vec<u8>);
+
+// Many players wandering about and chatting over a bad network, some of
+// them dropping out along the way. Returns what every client received and
+// the server's final state.
+async fn crowd(seed: u64) -> Trace {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), seed);
+    let bad = LinkConditions {
+        loss: 0.05,
+        latency: Duration::from_millis(30),
+        jitter: Duration::from_millis(20),
+        duplicate: 0.02,
+        reorder: 0.05,
+        reorder_delay: Duration::from_millis(40),
+    };
+    sim.set_conditions(Direction::Upstream, bad);
+    sim.set_conditions(Direction::Downstream, bad);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let clients: Vec<_> = (0..40).map(|_| sim.add_client()).collect();
+    let mut positions = vec![Position::new(0, 0, 0); clients.len()];
+    let mut input_seq = 0;
+    for (i, &client) in clients.iter().enumerate() {
+        let profile = PlayerProfile::new(&format!("player{}", i), PlayerColor::Blue);
+        sim.connect(client, &profile);
+        sim.step().await.unwrap();
+    }
+
+    while sim.elapsed() < Duration::from_secs(20) {
+        for (i, &client) in clients.iter().enumerate() {
+            if sim.client(client).silent {
+                continue;
+            }
+            if rng.random_bool(0.3) {
+                let position = &mut positions[i];
+                position.x = (position.x + rng.random_range(-1..=1)).clamp(-20, 19);
+                position.y = (position.y + rng.random_range(-1..=1)).clamp(-7, 9);
+                input_seq += 1;
+                let input = MoveInput {
+                    input_seq,
+                    position: position.clone(),
+                };
+                sim.send(client, MessageType::PositionUpdate, input.serialize());
+            }
+            if rng.random_bool(0.005) {
+                let chat = Chat {
+                    text: format!("hello from {}", i),
+                };
+                sim.send(
+                    client,
+                    MessageType::ChatMessage,
+                    serde_json::to_vec(&chat).unwrap(),
+                );
+            }
+            if rng.random_bool(0.0005) {
+                sim.client_mut(client).silent = true;
+            }
+        }
+        sim.step().await.unwrap();
+    }
+
+    let received = clients
+        .iter()
+        .map(|&client| sim.client(client).received.clone())
+        .collect();
+    let state = sim.server().state().lock().await.serialize();
+    (received, state)
+}
+
+#[tokio::test]
+async fn same_seed_same_run() {
+    let first = crowd(7).await;
+    let second = crowd(7).await;
+    // Some joins are lost on the bad network, but most get through
+    let joined = first.0.iter().filter(|received| !received.is_empty());
+    assert!(joined.count() > 30);
+    assert!(first == second, "two runs with the same seed diverged");
+
+    let other = crowd(8).await;
+    assert!(first != other, "a different seed changed nothing");
+}
+
+// When each chat a client received arrived, and what it said.
+fn chats(sim: &Simulation, client: ClientId) -> Vec<(Duration, String)> {
+    sim.client(client)
+        .received
+        .iter()
+        .filter(|(_, packet)| packet.msg_type == MessageType::ChatMessage)
+        .map(|(at, packet)| {
+            let chat: Chat = serde_json::from_slice(&packet.payload).unwrap();
+            (*at, chat.text)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn silent_players_are_dropped_on_schedule() {
+    let config = Config::new(BOARD_SIZE);
+    let timeout = config.heartbeat_timeout;
+    let grace = config.linkdead_grace;
+    let mut sim = Simulation::new(config, 1);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Green));
+    sim.step().await.unwrap();
+    assert_eq!(sim.server().state().lock().await.players.len(), 2);
+
+    sim.client_mut(alice).silent = true;
+    let went_silent = sim.elapsed();
+    while sim.client(bob).received_of(MessageType::PlayerLeft).count() == 0 {
+        sim.step().await.unwrap();
+        assert!(
+            sim.elapsed() < Duration::from_secs(120),
+            "alice was never dropped"
+        );
+    }
+    let tick_interval = sim.server().state().lock().await.tick_interval;
+
+    // First alice is linkdead, kept for a while in case they come back
+    let (lost_at, _) = chats(&sim, bob)
+        .into_iter()
+        .find(|(_, text)| text == "alice lost connection")
+        .unwrap();
+    let lost_after = lost_at - went_silent;
+    assert!(lost_after >= timeout);
+    assert!(lost_after <= timeout + tick_interval);
+
+    let dropped_after = sim.elapsed() - went_silent;
+    assert!(dropped_after >= timeout + grace);
+    assert!(dropped_after <= timeout + grace + 2 * tick_interval);
+
+    let left = sim
+        .client(bob)
+        .received_of(MessageType::PlayerLeft)
+        .next()
+        .unwrap();
+    assert_eq!(
+        &left.payload[..],
+        sim.client(alice).addr.to_string().as_bytes()
+    );
+    let state = sim.server().state().lock().await;
+    assert!(!state
+        .players
+        .contains_key(&sim.client(alice).addr.to_string()));
+}
+
+// Five players go quiet at once and one stays to watch them go. Returns the
+// chats and PlayerLeft messages the watcher saw, in order.
+async fn mass_timeout(seed: u64) -> Vec<(Duration, GamePacket)> {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), seed);
+    let clients: Vec<_> = (0..6).map(|_| sim.add_client()).collect();
+    for (i, &client) in clients.iter().enumerate() {
+        let profile = PlayerProfile::new(&format!("player{}", i), PlayerColor::Blue);
+        sim.connect(client, &profile);
+    }
+    sim.step().await.unwrap();
+    let (&watcher, quiet) = clients.split_last().unwrap();
+    for &client in quiet {
+        sim.client_mut(client).silent = true;
+    }
+    while sim
+        .client(watcher)
+        .received_of(MessageType::PlayerLeft)
+        .count()
+        < quiet.len()
+    {
+        sim.step().await.unwrap();
+        assert!(
+            sim.elapsed() < Duration::from_secs(120),
+            "the quiet players were never dropped"
+        );
+    }
+    sim.client(watcher)
+        .received
+        .iter()
+        .filter(|(_, packet)| {
+            matches!(
+                packet.msg_type,
+                MessageType::ChatMessage | MessageType::PlayerLeft
+            )
+        })
+        .cloned()
+        .collect()
+}
+
+#[tokio::test]
+async fn simultaneous_timeouts_are_announced_in_a_fixed_order() {
+    let first = mass_timeout(3).await;
+    let second = mass_timeout(3).await;
+    assert_eq!(
+        first
+            .iter()
+            .filter(|(_, packet)| packet.msg_type == MessageType::PlayerLeft)
+            .count(),
+        5
+    );
+    assert!(first == second, "two runs with the same seed diverged");
+}
+
+#[tokio::test]
+async fn linkdead_players_can_come_back() {
+    let config = Config::new(BOARD_SIZE);
+    let timeout = config.heartbeat_timeout;
+    let mut sim = Simulation::new(config, 5);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Green));
+    sim.step().await.unwrap();
+    let input = MoveInput {
+        input_seq: 1,
+        position: Position::new(-2, 4, 0),
+    };
+    sim.send(alice, MessageType::PositionUpdate, input.serialize());
+    sim.step().await.unwrap();
+    let (_, first) = session(&sim, alice);
+    let old_addr = sim.client(alice).addr.to_string();
+
+    // Alice's connection comes back by itself
+    sim.client_mut(alice).silent = true;
+    sim.run_for(timeout + Duration::from_secs(6)).await.unwrap();
+    let state = sim.server().state().lock().await;
+    assert!(state.players[&old_addr].linkdead_since.is_some());
+    // Still a player, but not counted as an active one
+    let metrics = sim.server().metrics().render(&state.players);
+    assert!(metrics.contains("\ngame_udp_active_players 1\n"));
+    assert!(metrics.contains("\ngame_udp_linkdead_players 1\n"));
+    drop(state);
+    sim.client_mut(alice).silent = false;
+    sim.send(alice, MessageType::Heartbeat, Vec::new());
+    sim.step().await.unwrap();
+    assert!(sim.server().state().lock().await.players[&old_addr]
+        .linkdead_since
+        .is_none());
+    let texts: Vec<_> = chats(&sim, bob).into_iter().map(|(_, text)| text).collect();
+    assert!(texts.ends_with(&["alice lost connection".into(), "alice reconnected".into()]));
+
+    // Then alice is gone long enough that their client reconnects from a new address
+    sim.client_mut(alice).silent = true;
+    sim.run_for(timeout + Duration::from_secs(6)).await.unwrap();
+    let moved = sim.add_client();
+    let request = ConnectionRequest {
+        profile: PlayerProfile::new("alice", PlayerColor::Red),
+        session: Some(first.token),
+    };
+    sim.send(moved, MessageType::ConnectionInit, request.serialize());
+    sim.step().await.unwrap();
+    let (snapshot, resumed) = session(&sim, moved);
+    assert!(resumed.resumed);
+    assert_eq!(snapshot.players.len(), 2);
+    assert_eq!(
+        snapshot.players[&resumed.player].position,
+        Position::new(-2, 4, 0)
+    );
+    let state = sim.server().state().lock().await;
+    assert!(state.players[&resumed.player].linkdead_since.is_none());
+    drop(state);
+
+    // Bob saw alice reconnect, and never leave
+    assert_eq!(
+        sim.client(bob).received_of(MessageType::PlayerLeft).count(),
+        0
+    );
+    let texts: Vec<_> = chats(&sim, bob).into_iter().map(|(_, text)| text).collect();
+    assert_eq!(texts.last().unwrap(), "alice reconnected");
+
+    // Nothing is dropped later on either
+    sim.run_for(Duration::from_secs(60)).await.unwrap();
+    assert_eq!(sim.server().state().lock().await.players.len(), 2);
+}
+
+// The session in the newest ConnectionInit reply a client got.
+fn session(sim: &Simulation, client: ClientId) -> (ServerStateSend, Session) {
+    let reply = sim
+        .client(client)
+        .received_of(MessageType::ConnectionInit)
+        .last()
+        .unwrap();
+    let mut snapshot = ServerStateSend::deserialize(&reply.payload).unwrap();
+    let session = snapshot.session.take().unwrap();
+    (snapshot, session)
+}
+
+#[tokio::test]
+async fn reconnecting_clients_get_their_session_back() {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), 3);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Green));
+    sim.step().await.unwrap();
+    let input = MoveInput {
+        input_seq: 1,
+        position: Position::new(3, 1, 0),
+    };
+    sim.send(alice, MessageType::PositionUpdate, input.serialize());
+    sim.step().await.unwrap();
+    let (_, first) = session(&sim, alice);
+    assert!(!first.resumed);
+    assert_eq!(first.player, sim.client(alice).addr.to_string());
+
+    // From the same address, nobody else notices
+    let joins = sim.client(bob).received_of(MessageType::PlayerJoin).count();
+    let request = ConnectionRequest {
+        profile: PlayerProfile::new("alice", PlayerColor::Red),
+        session: Some(first.token.clone()),
+    };
+    sim.send(alice, MessageType::ConnectionInit, request.serialize());
+    sim.step().await.unwrap();
+    let (snapshot, resumed) = session(&sim, alice);
+    assert!(resumed.resumed);
+    assert_eq!(resumed.token, first.token);
+    assert_eq!(snapshot.players.len(), 2);
+    assert_eq!(
+        snapshot.players[&resumed.player].position,
+        Position::new(3, 1, 0)
+    );
+    assert_eq!(
+        sim.client(bob).received_of(MessageType::PlayerJoin).count(),
+        joins
+    );
+
+    // From a new address, the player moves there with everything intact
+    let moved = sim.add_client();
+    sim.send(moved, MessageType::ConnectionInit, request.serialize());
+    sim.step().await.unwrap();
+    let new_addr = sim.client(moved).addr.to_string();
+    let (snapshot, resumed) = session(&sim, moved);
+    assert!(resumed.resumed);
+    assert_eq!(resumed.player, new_addr);
+    assert_eq!(snapshot.players.len(), 2);
+    let player = &snapshot.players[&new_addr];
+    assert_eq!(player.profile.name, "alice");
+    assert_eq!(player.position, Position::new(3, 1, 0));
+
+    // Bob sees the same player reconnect rather than leave and join
+    let bob = sim.client(bob);
+    assert_eq!(bob.received_of(MessageType::PlayerLeft).count(), 0);
+    let join = bob.received_of(MessageType::PlayerJoin).last().unwrap();
+    assert_eq!(
+        PlayerJoin::deserialize(&join.payload).unwrap(),
+        PlayerJoin {
+            player: new_addr,
+            profile: PlayerProfile::new("alice", PlayerColor::Red),
+            previous: Some(first.player),
+        }
+    );
+}
+
+#[tokio::test]
+async fn resuming_onto_a_taken_address_removes_its_player() {
+    let path = std::env::temp_dir().join(format!(
+        "game_udp-test-{}-displaced.json",
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    let storage = Arc::new(FileStorage::open(&path).unwrap());
+    let mut config = Config::new(BOARD_SIZE);
+    config.storage = Some(Arc::clone(&storage) as Arc<dyn Storage>);
+    let mut sim = Simulation::new(config, 6);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    let carol = sim.add_client();
+    for (client, name) in [(alice, "alice"), (bob, "bob"), (carol, "carol")] {
+        sim.connect(client, &PlayerProfile::new(name, PlayerColor::Red));
+    }
+    sim.step().await.unwrap();
+    let input = MoveInput {
+        input_seq: 1,
+        position: Position::new(5, 2, 0),
+    };
+    sim.send(bob, MessageType::PositionUpdate, input.serialize());
+    sim.step().await.unwrap();
+    let (_, first) = session(&sim, alice);
+
+    // Alice's session turns up from bob's address
+    let request = ConnectionRequest {
+        profile: PlayerProfile::new("alice", PlayerColor::Red),
+        session: Some(first.token),
+    };
+    sim.send(bob, MessageType::ConnectionInit, request.serialize());
+    sim.step().await.unwrap();
+    let bob_addr = sim.client(bob).addr.to_string();
+    let (snapshot, resumed) = session(&sim, bob);
+    assert!(resumed.resumed);
+    assert_eq!(snapshot.players.len(), 2);
+    assert_eq!(snapshot.players[&bob_addr].profile.name, "alice");
+
+    // Carol sees bob leave, and bob's record is written out
+    let left: Vec<_> = sim
+        .client(carol)
+        .received_of(MessageType::PlayerLeft)
+        .map(|packet| packet.payload.clone())
+        .collect();
+    assert_eq!(left, [bob_addr.as_bytes()]);
+    let record = FileStorage::open(&path).unwrap().load("bob").unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(record.unwrap().position, Position::new(5, 2, 0));
+}
+
+#[tokio::test]
+async fn unknown_sessions_start_over() {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), 4);
+    let alice = sim.add_client();
+    let request = ConnectionRequest {
+        profile: PlayerProfile::new("alice", PlayerColor::Red),
+        session: Some("0123456789abcdef".to_string()),
+    };
+    sim.send(alice, MessageType::ConnectionInit, request.serialize());
+    sim.step().await.unwrap();
+    let (snapshot, session) = session(&sim, alice);
+    assert!(!session.resumed);
+    assert_ne!(session.token, "0123456789abcdef");
+    assert_eq!(snapshot.players.len(), 1);
+    assert_eq!(snapshot.players[&session.player].profile.name, "alice");
+}
+
+#[tokio::test]
+async fn repeated_connects_without_a_token_resume() {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), 5);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Green));
+    sim.step().await.unwrap();
+    let (_, first) = session(&sim, alice);
+    let joins = sim.client(bob).received_of(MessageType::PlayerJoin).count();
+
+    // As if the first reply was lost and the client asked again
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.step().await.unwrap();
+    let (snapshot, again) = session(&sim, alice);
+    assert!(again.resumed);
+    assert_eq!(again.token, first.token);
+    assert_eq!(snapshot.players.len(), 2);
+    assert_eq!(snapshot.players[&again.player].profile.name, "alice");
+    assert_eq!(
+        sim.client(bob).received_of(MessageType::PlayerJoin).count(),
+        joins
+    );
+}
+
+#[tokio::test]
+async fn shut_out_peers_cannot_chat() {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), 6);
+    let alice = sim.add_client();
+    let bob = sim.add_client();
+    let mallory = sim.add_client();
+    sim.server()
+        .admin(AdminCommand::parse("ban mallory").unwrap())
+        .await
+        .unwrap();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Green));
+    sim.connect(mallory, &PlayerProfile::new("mallory", PlayerColor::Blue));
+    sim.step().await.unwrap();
+    assert_eq!(
+        sim.client(mallory)
+            .received_of(MessageType::ConnectionRejected)
+            .count(),
+        1
+    );
+    sim.server()
+        .admin(AdminCommand::parse("kick bob").unwrap())
+        .await
+        .unwrap();
+
+    for (client, text) in [(mallory, "from the banned"), (bob, "from the kicked")] {
+        let chat = Chat {
+            text: text.to_string(),
+        };
+        sim.send(
+            client,
+            MessageType::ChatMessage,
+            serde_json::to_vec(&chat).unwrap(),
+        );
+        // Commands go nowhere either
+        let who = Chat {
+            text: "/who".to_string(),
+        };
+        sim.send(
+            client,
+            MessageType::ChatMessage,
+            serde_json::to_vec(&who).unwrap(),
+        );
+    }
+    sim.step().await.unwrap();
+    let heard: Vec<_> = chats(&sim, alice)
+        .into_iter()
+        .map(|(_, text)| text)
+        .collect();
+    assert!(!heard.iter().any(|text| text.starts_with("from the")));
+    for client in [mallory, bob] {
+        assert!(!chats(&sim, client)
+            .iter()
+            .any(|(_, text)| text.contains("alice")));
+    }
+}
+
+#[tokio::test]
+async fn names_are_checked_as_assigned() {
+    let mut sim = Simulation::new(Config::new(BOARD_SIZE), 7);
+    let alice = sim.add_client();
+    let second = sim.add_client();
+    sim.server()
+        .admin(AdminCommand::parse("ban alice2").unwrap())
+        .await
+        .unwrap();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.step().await.unwrap();
+
+    // Asking for a taken name would have made them alice2
+    sim.connect(second, &PlayerProfile::new("alice", PlayerColor::Blue));
+    sim.step().await.unwrap();
+    assert_eq!(
+        sim.client(second)
+            .received_of(MessageType::ConnectionRejected)
+            .count(),
+        1
+    );
+    let state = sim.server().state().lock().await;
+    assert_eq!(state.players.len(), 1);
+}
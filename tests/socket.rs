@@ -0,0 +1,109 @@
+// Sending through a metered socket: what a flush does when some peers can't
+// be reached, and what gets counted.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use game_udp::{
+    batch, fragment::Reassembler, metrics::Metrics, server::Config, sim::Simulation,
+    socket::MeteredSocket, GamePacket, MessageType,
+};
+use tokio::{net::UdpSocket, time};
+
+#[tokio::test]
+async fn bad_peers_do_not_stop_a_flush() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = MeteredSocket::new(socket, Arc::new(Metrics::new()));
+    let mut peers = Vec::new();
+    for _ in 0..5 {
+        peers.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    }
+
+    // An IPv4 socket can't send to an IPv6 address at all. The outbox is
+    // flushed in no particular order, so the bad peers are mixed in.
+    for seq in 1..=3 {
+        let packet = GamePacket::new(MessageType::ChatMessage, seq, b"{}".to_vec());
+        for (port, peer) in (1..).zip(&peers) {
+            socket.queue_packet(packet.clone(), &format!("[::1]:{}", port));
+            socket.queue_packet(packet.clone(), &peer.local_addr().unwrap().to_string());
+        }
+    }
+    assert!(socket.flush().await.is_err());
+
+    for peer in &peers {
+        let mut buf = [0u8; 2048];
+        let len = time::timeout(Duration::from_secs(5), peer.recv(&mut buf))
+            .await
+            .expect("a good peer got nothing")
+            .unwrap();
+        let received: Vec<_> = GamePacket::messages(buf[..len].to_vec().into())
+            .map(|packet| packet.unwrap().seq_num)
+            .collect();
+        assert_eq!(received, [1, 2, 3]);
+    }
+}
+
+// The value of one line of the rendered metrics.
+fn metric(rendered: &str, series: &str) -> f64 {
+    rendered
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in the metrics", series))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn batched_packets_are_counted_by_type() {
+    let metrics = Arc::new(Metrics::new());
+    let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let socket = MeteredSocket::in_memory(addr, Arc::clone(&metrics));
+    for seq in 1..=3 {
+        let packet = GamePacket::new(MessageType::ChatMessage, seq, b"{}".to_vec());
+        socket.queue_packet(packet, "127.0.0.1:5000");
+    }
+    let heartbeat = GamePacket::new(MessageType::Heartbeat, 4, b"{}".to_vec());
+    socket.queue_packet(heartbeat, "127.0.0.1:5000");
+    socket.flush().await.unwrap();
+    assert_eq!(socket.take_sent().len(), 1);
+
+    let rendered = metrics.render(&Default::default());
+    let sent = "game_udp_packets_sent_total";
+    assert_eq!(
+        metric(&rendered, &format!("{}{{type=\"ChatMessage\"}}", sent)),
+        3.0
+    );
+    assert_eq!(
+        metric(&rendered, &format!("{}{{type=\"Heartbeat\"}}", sent)),
+        1.0
+    );
+    assert_eq!(
+        metric(&rendered, &format!("{}{{type=\"Batch\"}}", sent)),
+        0.0
+    );
+    assert_eq!(metric(&rendered, "game_udp_datagrams_sent_total"), 1.0);
+}
+
+#[tokio::test]
+async fn packets_unpacked_from_a_batch_are_counted_by_type() {
+    let sim = Simulation::new(Config::new((40, 20)), 1);
+    let packets = (1..=3)
+        .map(|seq| GamePacket::new(MessageType::Heartbeat, seq, b"{}".to_vec()))
+        .collect();
+    let datagram = batch::pack(packets, 1200).remove(0);
+    let from = "127.0.0.1:5000".parse().unwrap();
+    sim.server()
+        .dispatch(&mut Reassembler::new(), datagram, from)
+        .await
+        .unwrap();
+
+    let rendered = sim.server().metrics().render(&Default::default());
+    let received = "game_udp_packets_received_total";
+    assert_eq!(
+        metric(&rendered, &format!("{}{{type=\"Heartbeat\"}}", received)),
+        3.0
+    );
+    assert_eq!(
+        metric(&rendered, &format!("{}{{type=\"Batch\"}}", received)),
+        0.0
+    );
+}
@@ -0,0 +1,241 @@
+// Player records kept between server runs.
+
+use std::{fs, sync::Arc, time::Duration};
+
+use game_udp::{
+    server::Config,
+    sim::{ClientId, Simulation},
+    storage::{self, FileStorage, PlayerRecord, PlayerStats, Storage},
+    Chat, MessageType, MoveInput, PlayerColor, PlayerProfile, PlayerUpdate, Position,
+    ServerStateSend,
+};
+
+const BOARD_SIZE: (u32, u32) = (40, 20);
+
+fn config(storage: &Arc<FileStorage>) -> Config {
+    let mut config = Config::new(BOARD_SIZE);
+    config.storage = Some(Arc::clone(storage) as Arc<dyn Storage>);
+    config
+}
+
+// Where the newest ConnectionInit reply put a client.
+fn own_position(sim: &Simulation, client: ClientId) -> Position {
+    let reply = sim
+        .client(client)
+        .received_of(MessageType::ConnectionInit)
+        .last()
+        .unwrap();
+    let snapshot = ServerStateSend::deserialize(&reply.payload).unwrap();
+    let session = snapshot.session.unwrap();
+    snapshot.players[&session.player].position.clone()
+}
+
+#[test]
+fn records_survive_reopening_the_file() {
+    let path =
+        std::env::temp_dir().join(format!("game_udp-test-{}-players.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let storage = FileStorage::open(&path).unwrap();
+    assert_eq!(storage.load("alice").unwrap(), None);
+
+    let record = PlayerRecord {
+        profile: PlayerProfile::new("Alice", PlayerColor::Green),
+        position: Position::new(3, -4, 0),
+        stats: PlayerStats {
+            sessions: 2,
+            moves: 40,
+            chat_messages: 3,
+            first_seen: 1_700_000_000,
+            last_seen: 1_700_003_600,
+        },
+    };
+    storage
+        .save(vec![("alice".to_string(), record.clone())])
+        .unwrap();
+    // Saved records only reach the file when persisted
+    assert_eq!(
+        FileStorage::open(&path).unwrap().load("alice").unwrap(),
+        None
+    );
+    storage.persist().unwrap();
+    let reopened = FileStorage::open(&path).unwrap();
+    assert_eq!(reopened.load("alice").unwrap(), Some(record));
+    assert_eq!(reopened.load("bob").unwrap(), None);
+
+    fs::write(&path, b"not json").unwrap();
+    let err = FileStorage::open(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn players_pick_up_where_they_left_off() {
+    let storage = Arc::new(FileStorage::in_memory());
+
+    let mut sim = Simulation::new(config(&storage), 1);
+    let alice = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.step().await.unwrap();
+    for input_seq in 1..=3 {
+        let input = MoveInput {
+            input_seq,
+            position: Position::new(input_seq as i32, 2, 0),
+        };
+        sim.send(alice, MessageType::PositionUpdate, input.serialize());
+        sim.step().await.unwrap();
+    }
+    let chat = Chat {
+        text: "brb".to_string(),
+    };
+    sim.send(
+        alice,
+        MessageType::ChatMessage,
+        serde_json::to_vec(&chat).unwrap(),
+    );
+    sim.step().await.unwrap();
+    sim.server().save().await.unwrap();
+
+    let record = storage.load("alice").unwrap().unwrap();
+    assert_eq!(record.position, Position::new(3, 2, 0));
+    assert_eq!(record.stats.sessions, 1);
+    assert_eq!(record.stats.moves, 3);
+    assert_eq!(record.stats.chat_messages, 1);
+    assert!(record.stats.first_seen > 0);
+    assert!(record.stats.last_seen >= record.stats.first_seen);
+
+    // A new server with the same records, and alice on a new address
+    let mut sim = Simulation::new(config(&storage), 2);
+    let bob = sim.add_client();
+    sim.connect(bob, &PlayerProfile::new("bob", PlayerColor::Blue));
+    sim.step().await.unwrap();
+    assert_eq!(own_position(&sim, bob), Position::new(0, 0, 0));
+    let alice = sim.add_client();
+    // Names match without case
+    sim.connect(alice, &PlayerProfile::new("ALICE", PlayerColor::Cyan));
+    sim.step().await.unwrap();
+    assert_eq!(own_position(&sim, alice), Position::new(3, 2, 0));
+    let update = sim
+        .client(bob)
+        .received_of(MessageType::PositionUpdate)
+        .last()
+        .unwrap();
+    assert_eq!(
+        PlayerUpdate::deserialize(&update.payload).unwrap(),
+        PlayerUpdate {
+            player: sim.client(alice).addr.to_string(),
+            position: Position::new(3, 2, 0),
+        }
+    );
+
+    // Leaving saves the record without waiting for the next save
+    let mut config = config(&storage);
+    config.linkdead_grace = Duration::ZERO;
+    let mut sim = Simulation::new(config, 3);
+    let carol = sim.add_client();
+    sim.connect(carol, &PlayerProfile::new("carol", PlayerColor::Red));
+    sim.step().await.unwrap();
+    let input = MoveInput {
+        input_seq: 1,
+        position: Position::new(-5, 1, 0),
+    };
+    sim.send(carol, MessageType::PositionUpdate, input.serialize());
+    sim.step().await.unwrap();
+    sim.client_mut(carol).silent = true;
+    sim.run_for(Duration::from_secs(20)).await.unwrap();
+    assert!(sim.server().state().lock().await.players.is_empty());
+    let record = storage.load("carol").unwrap().unwrap();
+    assert_eq!(record.position, Position::new(-5, 1, 0));
+}
+
+#[tokio::test]
+async fn saved_positions_off_the_board_are_dropped() {
+    let storage = Arc::new(FileStorage::in_memory());
+    let record = PlayerRecord {
+        profile: PlayerProfile::new("alice", PlayerColor::Red),
+        position: Position::new(100, 100, 0),
+        stats: PlayerStats::default(),
+    };
+    storage.save(vec![("alice".to_string(), record)]).unwrap();
+    let mut sim = Simulation::new(config(&storage), 1);
+    let alice = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.step().await.unwrap();
+    assert_eq!(own_position(&sim, alice), Position::new(0, 0, 0));
+}
+
+#[tokio::test]
+async fn only_requested_names_get_records() {
+    let storage = Arc::new(FileStorage::in_memory());
+    let mut sim = Simulation::new(config(&storage), 1);
+    let alice = sim.add_client();
+    let impostor = sim.add_client();
+    let nameless = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.step().await.unwrap();
+    // Gets "alice2", and nobody should inherit that later
+    sim.connect(impostor, &PlayerProfile::new("Alice", PlayerColor::Green));
+    // Gets a fallback name
+    sim.send(nameless, MessageType::ConnectionInit, Vec::new());
+    sim.step().await.unwrap();
+    let names: Vec<_> = sim
+        .server()
+        .state()
+        .lock()
+        .await
+        .players
+        .values()
+        .map(|player| player.profile.name.clone())
+        .collect();
+    assert_eq!(names.len(), 3);
+    sim.server().save().await.unwrap();
+
+    assert!(storage.load("alice").unwrap().is_some());
+    for name in names.iter().filter(|&name| name != "alice") {
+        let profile = PlayerProfile::new(name, PlayerColor::Red);
+        assert_eq!(storage.load(&storage::identity(&profile)).unwrap(), None);
+    }
+}
+
+#[tokio::test]
+async fn names_told_apart_keep_apart_records() {
+    let storage = Arc::new(FileStorage::in_memory());
+    let mut sim = Simulation::new(config(&storage), 1);
+    // Only ASCII letters match without case, so both get the name they asked for
+    let upper = sim.add_client();
+    let lower = sim.add_client();
+    sim.connect(upper, &PlayerProfile::new("Émile", PlayerColor::Red));
+    sim.connect(lower, &PlayerProfile::new("émile", PlayerColor::Blue));
+    sim.step().await.unwrap();
+    for (input_seq, client, x) in [(1, upper, 4), (1, lower, -4)] {
+        let input = MoveInput {
+            input_seq,
+            position: Position::new(x, 0, 0),
+        };
+        sim.send(client, MessageType::PositionUpdate, input.serialize());
+    }
+    sim.step().await.unwrap();
+    sim.server().save().await.unwrap();
+
+    let mut sim = Simulation::new(config(&storage), 2);
+    let lower = sim.add_client();
+    sim.connect(lower, &PlayerProfile::new("émile", PlayerColor::Blue));
+    sim.step().await.unwrap();
+    assert_eq!(own_position(&sim, lower), Position::new(-4, 0, 0));
+}
+
+#[tokio::test]
+async fn server_saves_reach_the_file() {
+    let path =
+        std::env::temp_dir().join(format!("game_udp-test-{}-server.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let storage = Arc::new(FileStorage::open(&path).unwrap());
+    let mut sim = Simulation::new(config(&storage), 1);
+    let alice = sim.add_client();
+    sim.connect(alice, &PlayerProfile::new("alice", PlayerColor::Red));
+    sim.step().await.unwrap();
+    sim.server().save().await.unwrap();
+
+    let reopened = FileStorage::open(&path).unwrap();
+    assert_eq!(reopened.load("alice").unwrap().unwrap().stats.sessions, 1);
+    fs::remove_file(&path).unwrap();
+}
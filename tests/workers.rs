@@ -0,0 +1,78 @@
+// Session affinity across workers: packets for a session go out through the
+// worker it's pinned to, wherever they were queued.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use game_udp::{
+    metrics::Metrics,
+    socket::MeteredSocket,
+    workers::{Handoff, Transport},
+    GamePacket, MessageType,
+};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+const PEER: &str = "127.0.0.1:5000";
+
+fn transport(workers: u16) -> (Transport, Vec<UnboundedReceiver<Handoff>>) {
+    let metrics = Arc::new(Metrics::new());
+    let sockets = (0..workers)
+        .map(|worker| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 4000 + worker));
+            Arc::new(MeteredSocket::in_memory(addr, Arc::clone(&metrics)))
+        })
+        .collect();
+    Transport::new(sockets, None)
+}
+
+fn chat(seq_num: u32) -> GamePacket {
+    GamePacket::new(MessageType::ChatMessage, seq_num, b"{}".to_vec())
+}
+
+// How many datagrams each worker's socket put on the wire.
+async fn sent(transport: &Transport) -> Vec<usize> {
+    transport.flush().await.unwrap();
+    (0..transport.worker_count())
+        .map(|worker| transport.socket(worker).take_sent().len())
+        .collect()
+}
+
+#[tokio::test]
+async fn pinned_peers_are_handed_to_their_worker() {
+    let (transport, mut handoffs) = transport(2);
+    let (first, second) = (transport.for_worker(0), transport.for_worker(1));
+    second.pin(PEER);
+
+    first.queue_packet(chat(1), PEER);
+    let (addr, packet) = handoffs[1].try_recv().unwrap();
+    assert_eq!((addr.as_str(), packet), (PEER, chat(1)));
+    assert!(handoffs[0].try_recv().is_err());
+    assert_eq!(sent(&transport).await, [0, 0]);
+
+    // The owner sends its own packets, and anyone can send right away
+    // through the owner's socket
+    second.queue_packet(chat(2), PEER);
+    assert_eq!(sent(&transport).await, [0, 1]);
+    first.send_packet(&chat(3), PEER).await.unwrap();
+    assert_eq!(sent(&transport).await, [0, 1]);
+    assert!(handoffs[1].try_recv().is_err());
+}
+
+#[tokio::test]
+async fn unpinned_peers_are_sent_locally() {
+    let (transport, mut handoffs) = transport(2);
+    let (first, second) = (transport.for_worker(0), transport.for_worker(1));
+    second.pin(PEER);
+    second.unpin(PEER);
+
+    first.queue_packet(chat(1), PEER);
+    second.queue_packet(chat(2), PEER);
+    assert_eq!(sent(&transport).await, [1, 1]);
+    for handoff in &mut handoffs {
+        assert!(handoff.try_recv().is_err());
+    }
+
+    // Tasks that aren't workers hand unpinned peers to the first worker
+    transport.queue_packet(chat(3), PEER);
+    let (addr, packet) = handoffs[0].try_recv().unwrap();
+    assert_eq!((addr.as_str(), packet), (PEER, chat(3)));
+}
@@ -0,0 +1,141 @@
+-- Wireshark dissector for game_udp.
+--
+-- Generated by game_udp::dissector from the protocol definitions; don't edit
+-- it by hand. Regenerate it with
+--
+--     cargo run --bin game_udp-inspect -- --dissector wireshark/game_udp.lua
+--
+-- To install, copy this file into Wireshark's Lua plugin directory.
+
+local game_udp = Proto("game_udp", "game_udp Protocol")
+
+local HEADER_LEN = 6
+local FRAGMENT_HEADER_LEN = 4
+local FRAME_HEADER_LEN = 2
+
+local message_types = {
+    [0x01] = "PositionUpdate",
+    [0x02] = "ChatMessage",
+    [0x03] = "Heartbeat",
+    [0x04] = "ConnectionInit",
+    [0x05] = "PlayerJoin",
+    [0x06] = "ConfirmPlayerMovement",
+    [0x07] = "PlayerLeft",
+    [0x08] = "ConnectionRejected",
+    [0x09] = "Fragment",
+    [0x0a] = "Batch",
+    [0x0b] = "ServerShutdown",
+}
+
+local payload_formats = {
+    [0x01] = "json",
+    [0x02] = "json",
+    [0x03] = "json",
+    [0x04] = "json",
+    [0x05] = "json",
+    [0x06] = "json",
+    [0x07] = "text",
+    [0x08] = "json",
+    [0x09] = "fragment",
+    [0x0a] = "batch",
+    [0x0b] = "json",
+}
+
+local f_type = ProtoField.uint8("game_udp.type", "Message type", base.HEX, message_types)
+local f_version = ProtoField.uint8("game_udp.version", "Version", base.DEC)
+local f_seq = ProtoField.uint32("game_udp.seq", "Sequence number", base.DEC)
+local f_payload = ProtoField.bytes("game_udp.payload", "Payload")
+local f_text = ProtoField.string("game_udp.text", "Text")
+local f_fragment_index = ProtoField.uint16("game_udp.fragment.index", "Fragment index", base.DEC)
+local f_fragment_count = ProtoField.uint16("game_udp.fragment.count", "Fragment count", base.DEC)
+local f_frame_len = ProtoField.uint16("game_udp.batch.length", "Frame length", base.DEC)
+
+game_udp.fields = {
+    f_type,
+    f_version,
+    f_seq,
+    f_payload,
+    f_text,
+    f_fragment_index,
+    f_fragment_count,
+    f_frame_len,
+}
+
+game_udp.prefs.port = Pref.uint("UDP port", 4000, "Port the game server listens on")
+
+local json = Dissector.get("json")
+
+-- Adds one packet in `tvb` to `tree`. Returns a short summary for the info
+-- column.
+local function dissect_packet(tvb, pinfo, tree)
+    if tvb:len() < HEADER_LEN then
+        local item = tree:add(game_udp, tvb(), "game_udp, truncated")
+        item:add_expert_info(PI_MALFORMED, PI_ERROR, "Shorter than a packet header")
+        return "Malformed"
+    end
+    local msg_type = tvb(0, 1):uint()
+    local seq = tvb(2, 4):uint()
+    local name = message_types[msg_type] or string.format("Unknown (0x%02x)", msg_type)
+    local subtree = tree:add(game_udp, tvb(), "game_udp " .. name .. ", seq " .. seq)
+    subtree:add(f_type, tvb(0, 1))
+    subtree:add(f_version, tvb(1, 1))
+    subtree:add(f_seq, tvb(2, 4))
+
+    local payload_len = tvb:len() - HEADER_LEN
+    if payload_len == 0 then
+        return name
+    end
+    local payload = tvb(HEADER_LEN, payload_len)
+    local format = payload_formats[msg_type]
+    if format == "json" then
+        subtree:add(f_payload, payload)
+        if json then
+            json:call(payload:tvb(), pinfo, subtree)
+        end
+    elseif format == "text" then
+        subtree:add(f_text, payload)
+    elseif format == "fragment" and payload_len > FRAGMENT_HEADER_LEN then
+        subtree:add(f_fragment_index, payload(0, 2))
+        subtree:add(f_fragment_count, payload(2, 2))
+        subtree:add(f_payload, payload(FRAGMENT_HEADER_LEN))
+        name = string.format("%s %d/%d", name, payload(0, 2):uint() + 1, payload(2, 2):uint())
+    elseif format == "batch" then
+        local names = {}
+        local offset = HEADER_LEN
+        while offset + FRAME_HEADER_LEN <= tvb:len() do
+            local len = tvb(offset, FRAME_HEADER_LEN):uint()
+            subtree:add(f_frame_len, tvb(offset, FRAME_HEADER_LEN))
+            offset = offset + FRAME_HEADER_LEN
+            if len == 0 or offset + len > tvb:len() then
+                subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Truncated batch frame")
+                break
+            end
+            names[#names + 1] = dissect_packet(tvb(offset, len):tvb(), pinfo, subtree)
+            offset = offset + len
+        end
+        name = name .. " [" .. table.concat(names, ", ") .. "]"
+    else
+        subtree:add(f_payload, payload)
+    end
+    return name
+end
+
+function game_udp.dissector(tvb, pinfo, tree)
+    local summary = dissect_packet(tvb, pinfo, tree)
+    -- Nested dissectors like JSON rename the protocol column, so set it last
+    pinfo.cols.protocol = game_udp.name
+    pinfo.cols.info = summary
+    return tvb:len()
+end
+
+local udp_port = DissectorTable.get("udp.port")
+local registered_port = game_udp.prefs.port
+udp_port:add(registered_port, game_udp)
+
+function game_udp.prefs_changed()
+    if registered_port ~= game_udp.prefs.port then
+        udp_port:remove(registered_port, game_udp)
+        registered_port = game_udp.prefs.port
+        udp_port:add(registered_port, game_udp)
+    end
+end